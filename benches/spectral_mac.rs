@@ -0,0 +1,64 @@
+// Spectral multiply-accumulate of the direct sound convolution, per block and ear:
+// `cargo bench --bench spectral_mac`
+//
+// before: the input spectrum is converted with a scalar loop, the product of every segment is
+// accumulated and the sum is copied to interleaved form for the inverse FFT
+// after: SIMD conversion into the history, the last segment is written interleaved directly
+use std::hint::black_box;
+use std::time::Instant;
+
+use num_complex::Complex;
+use RustTest::simd::{complex_mac, complex_mac_interleaved, SplitComplex};
+
+const N_BINS: usize = 513; // blocksize 512
+const N_ITERATIONS: usize = 20000;
+
+fn spectrum(seed: usize) -> Vec<Complex<f32>> {
+    (0..N_BINS).map(|i| Complex::new(((i * 7 + seed) % 13) as f32 / 13.0, ((i * 5 + seed) % 11) as f32 / 11.0)).collect()
+}
+
+// average time per block (ns)
+fn time(mut block: impl FnMut()) -> f64 {
+    for _ in 0..N_ITERATIONS / 10 {
+        block();
+    }
+    let start = Instant::now();
+    for _ in 0..N_ITERATIONS {
+        block();
+    }
+    start.elapsed().as_nanos() as f64 / N_ITERATIONS as f64
+}
+
+fn main() {
+    for n_segments in [1, 4, 16] {
+        let input_spectrum = spectrum(0);
+        let filter: Vec<SplitComplex> = (1..=n_segments).map(|seed| SplitComplex::from_interleaved(&spectrum(seed))).collect();
+        let mut input_f = vec![SplitComplex::zeros(N_BINS); n_segments];
+        let mut acc = SplitComplex::zeros(N_BINS);
+        let mut output = vec![Complex::new(0.0, 0.0); N_BINS];
+
+        let before = time(|| {
+            let history = &mut input_f[0];
+            for (i, c) in black_box(&input_spectrum).iter().enumerate() {
+                history.re[i] = c.re;
+                history.im[i] = c.im;
+            }
+            acc.clear();
+            for (history, segment) in input_f.iter().zip(filter.iter()) {
+                complex_mac(&mut acc, history, segment);
+            }
+            acc.copy_to_interleaved(&mut output);
+            black_box(&output);
+        });
+        let after = time(|| {
+            input_f[0].copy_from_interleaved(black_box(&input_spectrum));
+            acc.clear();
+            for (history, segment) in input_f.iter().zip(filter.iter()).take(n_segments - 1) {
+                complex_mac(&mut acc, history, segment);
+            }
+            complex_mac_interleaved(&mut output, &acc, &input_f[n_segments - 1], &filter[n_segments - 1]);
+            black_box(&output);
+        });
+        println!("{n_segments:>2} segments: before {before:>7.1} ns, after {after:>7.1} ns, speedup {:.2}x", before / after);
+    }
+}
@@ -0,0 +1,165 @@
+use std::f32::consts::PI;
+
+// reference conditions of ISO 9613-1
+const REFERENCE_TEMPERATURE: f32 = 293.15;
+const TRIPLE_POINT_TEMPERATURE: f32 = 273.16;
+const REFERENCE_PRESSURE: f32 = 101.325;
+
+// frequency at which the path filters are fitted to the ISO attenuation (Hz)
+const FIT_FREQUENCY: f32 = 8000.0;
+
+// Atmospheric conditions of the scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Atmosphere {
+    // degree Celsius
+    temperature: f32,
+    // relative humidity in percent
+    relative_humidity: f32,
+    // kPa
+    pressure: f32,
+}
+
+impl Default for Atmosphere {
+    fn default() -> Self {
+        Atmosphere::new(20.0, 50.0, REFERENCE_PRESSURE)
+    }
+}
+
+impl Atmosphere {
+    pub fn new(temperature: f32, relative_humidity: f32, pressure: f32) -> Self {
+        Self {
+            temperature,
+            relative_humidity: relative_humidity.clamp(0.0, 100.0),
+            pressure: pressure.max(f32::EPSILON),
+        }
+    }
+
+    pub fn get_temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn get_relative_humidity(&self) -> f32 {
+        self.relative_humidity
+    }
+
+    pub fn get_pressure(&self) -> f32 {
+        self.pressure
+    }
+
+    // speed of sound in dry air (m/s)
+    pub fn speed_of_sound(&self) -> f32 {
+        331.3 * (1.0 + self.temperature / 273.15).sqrt()
+    }
+
+    // pure-tone attenuation coefficient (dB/m) following ISO 9613-1
+    pub fn attenuation_coefficient(&self, frequency: f32) -> f32 {
+        let t = self.temperature + 273.15;
+        let t_rel = t / REFERENCE_TEMPERATURE;
+        let pa_rel = self.pressure / REFERENCE_PRESSURE;
+
+        // molar concentration of water vapour (%)
+        let c = -6.8346 * (TRIPLE_POINT_TEMPERATURE / t).powf(1.261) + 4.6151;
+        let h = self.relative_humidity * 10f32.powf(c) / pa_rel;
+
+        // relaxation frequencies of oxygen and nitrogen
+        let fr_o = pa_rel * (24.0 + 4.04e4 * h * (0.02 + h) / (0.391 + h));
+        let fr_n = pa_rel
+            * t_rel.powf(-0.5)
+            * (9.0 + 280.0 * h * (-4.170 * (t_rel.powf(-1.0 / 3.0) - 1.0)).exp());
+
+        let f2 = frequency * frequency;
+        8.686
+            * f2
+            * (1.84e-11 / pa_rel * t_rel.sqrt()
+                + t_rel.powf(-2.5)
+                    * (0.01275 * (-2239.1 / t).exp() / (fr_o + f2 / fr_o)
+                        + 0.1068 * (-3352.0 / t).exp() / (fr_n + f2 / fr_n)))
+    }
+
+    // attenuation (dB) of a path of the given length (m)
+    pub fn path_attenuation(&self, frequency: f32, distance: f32) -> f32 {
+        self.attenuation_coefficient(frequency) * distance.max(0.0)
+    }
+}
+
+// One-pole low-pass approximating the air absorption of a single propagation path. The
+// pole is fitted so that the filter matches the ISO attenuation at FIT_FREQUENCY (or at
+// half the Nyquist frequency for low sample rates). Coefficient changes are ramped over
+// one block.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AirAbsorptionFilter {
+    coefficient: f32,
+    target_coefficient: f32,
+    y1: f32,
+}
+
+impl AirAbsorptionFilter {
+    pub fn new(atmosphere: &Atmosphere, distance: f32, sample_rate: f32) -> Self {
+        let coefficient = fit_coefficient(atmosphere, distance, sample_rate);
+        Self {
+            coefficient,
+            target_coefficient: coefficient,
+            y1: 0.0,
+        }
+    }
+
+    pub fn set_distance(&mut self, atmosphere: &Atmosphere, distance: f32, sample_rate: f32) {
+        self.target_coefficient = fit_coefficient(atmosphere, distance, sample_rate);
+    }
+
+    pub fn get_coefficient(&self) -> f32 {
+        self.target_coefficient
+    }
+
+    // magnitude response at the given frequency
+    pub fn gain(&self, frequency: f32, sample_rate: f32) -> f32 {
+        let a = self.target_coefficient;
+        let cos_w = (2.0 * PI * frequency / sample_rate).cos();
+        (1.0 - a) / (1.0 - 2.0 * a * cos_w + a * a).sqrt()
+    }
+
+    pub fn reset(&mut self) {
+        self.y1 = 0.0;
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        let n_points = buffer.len() as f32;
+        let step = (self.target_coefficient - self.coefficient) / n_points;
+        for (i, s) in buffer.iter_mut().enumerate() {
+            let a = self.coefficient + step * (i + 1) as f32;
+            self.y1 = (1.0 - a) * *s + a * self.y1;
+            *s = self.y1;
+        }
+        self.coefficient = self.target_coefficient;
+    }
+}
+
+fn fit_coefficient(atmosphere: &Atmosphere, distance: f32, sample_rate: f32) -> f32 {
+    let frequency = FIT_FREQUENCY.min(sample_rate / 4.0);
+    let g2 = 10f32.powf(-atmosphere.path_attenuation(frequency, distance) / 10.0);
+    if g2 >= 1.0 - 1e-6 {
+        return 0.0;
+    }
+    // solves (1 - a)² = g² (1 - 2 a cos(w) + a²) for the stable root
+    let cos_w = (2.0 * PI * frequency / sample_rate).cos();
+    let b = 1.0 - g2 * cos_w;
+    let c = 1.0 - g2;
+    ((b - (b * b - c * c).max(0.0).sqrt()) / c).clamp(0.0, 0.999)
+}
+
+#[test]
+fn test_iso_9613_attenuation() {
+    // ISO 9613-1 table values at 20 °C, 50 % and 101.325 kPa (dB/km)
+    let atmosphere = Atmosphere::default();
+    for (frequency, expected) in [(1000.0, 4.66), (2000.0, 9.86), (4000.0, 29.7), (8000.0, 105.0)] {
+        let alpha = atmosphere.attenuation_coefficient(frequency) * 1000.0;
+        assert!((alpha - expected).abs() / expected < 0.03, "{frequency}: {alpha}");
+    }
+    assert!((atmosphere.speed_of_sound() - 343.2).abs() < 0.1);
+
+    // the fitted filter matches the attenuation at the fit frequency
+    let filter = AirAbsorptionFilter::new(&atmosphere, 50.0, 48000.0);
+    let expected = -atmosphere.path_attenuation(FIT_FREQUENCY, 50.0);
+    let gain = 20.0 * filter.gain(FIT_FREQUENCY, 48000.0).log10();
+    assert!((gain - expected).abs() < 0.01);
+}
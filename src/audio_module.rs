@@ -1,83 +1,386 @@
 use cpal::{
     self,
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    FrameCount, FromSample, Sample, SizedSample,
+    FromSample, SizedSample,
 };
-use std::sync::mpsc::Receiver;
+use nalgebra::Point3;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::path::Path;
 use std::thread;
 
 use crate::{
-    audioSceneHandlerData::Scene_data,
-    convolver::Spatializer,
-    filter::{BinauralFilter, FFTManager, FilterStorage, FilterTree},
+    brir::{head_yaw_pitch, BrirSet, BrirSets},
+    convolver::{
+        virtual_source_id, ChannelFactory, SpatializerBank, SpatializerChannel, DIFFRACTION_SOURCES, MAX_VIRTUAL_SOURCES,
+        REFLECTION_SOURCES,
+    },
+    filter::{BinauralFilterType, FFTManager, FilterStorage},
     image_source_method::ISMAcousticScene,
+    osc::Source_parameter,
+    scene::{calculate_azimuth_and_elevation_of_direction, get_position, get_quaternion, SceneUpdate},
+    worker_pool::WorkerPool,
 };
 
-pub fn start_audio_thread(rx: Receiver<Scene_data>) {
+// Model: HRTFs + image source model, DataBased: measured BRIRs selected by head orientation
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RenderingMode {
+    #[default]
+    Model,
+    DataBased,
+}
+
+// Reflections of the model based rendering: image sources, or a Scattering Delay Network per
+// source (cheaper for moving sources). Selected at runtime with /room/model, the scene handler
+// builds the networks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RoomModel {
+    #[default]
+    ImageSources,
+    ScatteringDelayNetwork,
+}
+
+impl RoomModel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ism" => Some(RoomModel::ImageSources),
+            "sdn" => Some(RoomModel::ScatteringDelayNetwork),
+            _ => None,
+        }
+    }
+}
+
+// length of the measured BRIRs in samples
+const BRIR_LENGTH: usize = 48000;
+const BRIR_POSITIONS: &str = "./assets/brir_positions.dat";
+
+// samples per block, filters built on other threads have to use the same partitioning
+pub const BUFFER_SIZE: usize = 512;
+
+// objects the audio thread replaced or removed, it sends them back to be dropped on the
+// scene handler side. The audio thread neither allocates nor frees memory.
+pub enum Garbage {
+    Scene(SceneUpdate),
+    // the previous values (e.g. the previous room model) and the unused channels of a parameter
+    Parameter(Source_parameter),
+    Channel(Box<SpatializerChannel>),
+}
+
+// capacity of the garbage channel (sync_channel, allocated once). If the scene handler falls
+// that far behind, the audio thread drops the objects itself.
+pub const GARBAGE_CAPACITY: usize = 4096;
+
+// returns the settings of the channels (with the sample rate of the output device), the scene
+// handler builds the channels of new sources with them
+pub fn start_audio_thread(
+    rx: Receiver<SceneUpdate>,
+    parameter_rx: Receiver<Source_parameter>,
+    garbage_tx: SyncSender<Garbage>,
+) -> ChannelFactory {
+    start_audio_thread_with_mode(rx, parameter_rx, garbage_tx, RenderingMode::default())
+}
+
+pub fn start_audio_thread_with_mode(
+    rx: Receiver<SceneUpdate>,
+    parameter_rx: Receiver<Source_parameter>,
+    garbage_tx: SyncSender<Garbage>,
+    rendering_mode: RenderingMode,
+) -> ChannelFactory {
+    let (factory_tx, factory_rx) = mpsc::channel();
     thread::spawn(move || {
         let host = cpal::default_host();
         let output_device = host.default_output_device().unwrap();
         let output_config = output_device.default_output_config().unwrap();
+        let threads = AudioThreadChannels { rx, parameter_rx, garbage_tx, factory_tx };
 
         let audio_thread_result = match output_config.sample_format() {
-            cpal::SampleFormat::I8 => run::<i8>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::I16 => run::<i16>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::I32 => run::<i32>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::I64 => run::<i64>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::U8 => run::<u8>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::U16 => run::<u16>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::U32 => run::<u32>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::U64 => run::<u64>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::F32 => run::<f32>(&output_device, &output_config.into(), rx),
-            cpal::SampleFormat::F64 => run::<f64>(&output_device, &output_config.into(), rx),
+            cpal::SampleFormat::I8 => run::<i8>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::I16 => run::<i16>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::I32 => run::<i32>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::I64 => run::<i64>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::U8 => run::<u8>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::U16 => run::<u16>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::U32 => run::<u32>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::U64 => run::<u64>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::F32 => run::<f32>(&output_device, &output_config.into(), threads, rendering_mode),
+            cpal::SampleFormat::F64 => run::<f64>(&output_device, &output_config.into(), threads, rendering_mode),
             sample_format => panic!("Unsupported sample format '{sample_format}'"),
         };
 
-        audio_thread_result
+        if let Err(error) = audio_thread_result {
+            eprintln!("Audio thread stopped: {error}");
+        }
     });
+    factory_rx.recv().expect("The audio thread could not start")
+}
+
+// connections of the audio thread to the scene handler
+struct AudioThreadChannels {
+    rx: Receiver<SceneUpdate>,
+    parameter_rx: Receiver<Source_parameter>,
+    garbage_tx: SyncSender<Garbage>,
+    // sent once the bank is set up
+    factory_tx: Sender<ChannelFactory>,
 }
 
 fn run<T>(
     devcice: &cpal::Device,
     config: &cpal::StreamConfig,
-    rx: Receiver<Scene_data>,
+    threads: AudioThreadChannels,
+    rendering_mode: RenderingMode,
 ) -> Result<(), anyhow::Error>
 where
     T: SizedSample + FromSample<f32>,
 {
-    let _sample_rate = config.sample_rate.0 as f32;
+    let sample_rate = config.sample_rate.0 as f32;
     let channels = config.channels as usize;
-    let buffer_size = 512;
+    let buffer_size = BUFFER_SIZE;
     let error_callback = |err| eprintln!("Error occured on stream: {}", err);
 
     let filterpath: &str = "./assets/hrtf_binaray.dat";
     let anglepath: &str = "./assets/angles.dat";
     // initialize Engine here
-    let mut fft_manager = FFTManager::new(512);
+    let mut fft_manager = FFTManager::new(BUFFER_SIZE);
     let (hrtf_storage, hrtf_tree) =
         FilterStorage::new(filterpath, anglepath, &mut fft_manager, buffer_size);
 
-    let mut spatializer = Spatializer::new(buffer_size, fft_manager, &hrtf_storage);
-    let prev_hrtfs: Vec<&BinauralFilter> = Vec::new();
-    let active_hrtfs: Vec<&BinauralFilter> = Vec::new();
-    let mut n_sources = 0;
+    // measured room: BRIRs instead of HRTF + ISM, directivity is part of the measurement.
+    // Without measured positions, the single set is used for all sources.
+    let brir_sets = match rendering_mode {
+        RenderingMode::DataBased if Path::new(BRIR_POSITIONS).exists() => Some(
+            BrirSets::from_files(BRIR_POSITIONS, "./assets/brir", BRIR_LENGTH, &mut fft_manager, buffer_size)?,
+        ),
+        RenderingMode::DataBased => {
+            let mut sets = BrirSets::new();
+            let set = BrirSet::new(
+                "./assets/brir_binary.dat",
+                "./assets/brir_angles.dat",
+                BRIR_LENGTH,
+                &mut fft_manager,
+                buffer_size,
+            )?;
+            sets.add_set(Point3::origin(), set);
+            Some(sets)
+        }
+        RenderingMode::Model => None,
+    };
+
+    let mut spatializer_bank = match brir_sets.as_ref() {
+        Some(brir_sets) => {
+            SpatializerBank::with_n_segments(buffer_size, fft_manager, brir_sets.get_n_segments())
+        }
+        None => {
+            let mut bank = SpatializerBank::new(buffer_size, fft_manager, &hrtf_storage);
+            bank.enable_directivity();
+            bank
+        }
+    };
+
+    spatializer_bank.set_sample_rate(sample_rate);
+
+    // render sources on all but one core (the audio thread renders as well)
+    let n_workers = thread::available_parallelism().map_or(0, |n| n.get().saturating_sub(2));
+    if n_workers > 0 {
+        spatializer_bank.set_worker_pool(WorkerPool::new(n_workers));
+    }
+
+    let AudioThreadChannels { rx, parameter_rx, garbage_tx, factory_tx } = threads;
+    factory_tx.send(spatializer_bank.get_channel_factory())?;
 
     let mut audio_scene = ISMAcousticScene::default();
+    // renders one block of BUFFER_SIZE interleaved stereo frames
+    let mut render_block = move |block: &mut [f32]| {
+        // the scene is taken before the parameters: the Sources parameter of its ids was
+        // sent before it, its channels exist once the parameters are applied
+        let scene_update = rx.try_recv();
+
+        // if the garbage channel is full, the object is dropped here
+        let dispose = |garbage: Garbage| {
+            let _ = garbage_tx.try_send(garbage);
+        };
+
+        // per-source parameters. The replaced values go back with the parameter.
+        while let Ok(parameter) = parameter_rx.try_recv() {
+            let garbage = match parameter {
+                Source_parameter::Sources(ids, mut channels) => {
+                    spatializer_bank.sync_sources(&ids, &mut channels);
+                    Source_parameter::Sources(ids, channels)
+                }
+                Source_parameter::DistanceAttenuation(id, distance_attenuation) => {
+                    Source_parameter::DistanceAttenuation(id, spatializer_bank.set_distance_attenuation(id, distance_attenuation))
+                }
+                Source_parameter::Doppler(id, enabled) => {
+                    spatializer_bank.set_doppler(id, enabled);
+                    continue;
+                }
+                Source_parameter::Signal(id, signal) => Source_parameter::Signal(id, spatializer_bank.set_source_signal(id, signal)),
+                Source_parameter::Atmosphere(atmosphere) => {
+                    spatializer_bank.set_atmosphere(atmosphere);
+                    continue;
+                }
+                // the measured BRIRs contain the propagation in the room and the directivity
+                parameter @ (Source_parameter::Occlusion(..)
+                | Source_parameter::Transmission(..)
+                | Source_parameter::Diffraction(..)
+                | Source_parameter::Reflections(..)
+                | Source_parameter::Directivity(..)
+                | Source_parameter::CoupledReverb(..)
+                | Source_parameter::RoomReverb(..)
+                | Source_parameter::RoomModel(..)
+                | Source_parameter::RoomModes(..))
+                    if brir_sets.is_some() => parameter,
+                Source_parameter::Occlusion(id, occlusion) => {
+                    spatializer_bank.set_occlusion(id, &occlusion);
+                    continue;
+                }
+                Source_parameter::Transmission(id, transmission) => {
+                    spatializer_bank.set_transmission(id, transmission.as_ref());
+                    continue;
+                }
+                Source_parameter::Diffraction(id, paths, mut channels) => {
+                    // one virtual source per path, heard from the diffracting edge
+                    spatializer_bank.set_virtual_sources(id, DIFFRACTION_SOURCES, paths.len(), &mut channels);
+                    for (idx, path) in paths.iter().enumerate() {
+                        let virtual_id = virtual_source_id(id, DIFFRACTION_SOURCES.start + idx);
+                        spatializer_bank.set_transmission(virtual_id, Some(&path.to_transmission()));
+                        spatializer_bank.set_distance(virtual_id, path.get_length());
+                    }
+                    Source_parameter::Diffraction(id, paths, channels)
+                }
+                Source_parameter::Reflections(id, listener, reflections, mut channels) => {
+                    // one virtual source per path or cluster, heard from its image source
+                    spatializer_bank.set_virtual_sources(id, REFLECTION_SOURCES, reflections.len(), &mut channels);
+                    for (idx, reflection) in reflections.iter().enumerate() {
+                        let virtual_id = virtual_source_id(id, REFLECTION_SOURCES.start + idx);
+                        spatializer_bank.set_transmission(virtual_id, Some(&reflection.to_transmission(&listener)));
+                        spatializer_bank.set_distance(virtual_id, reflection.get_length());
+                        spatializer_bank.set_emission_direction(virtual_id, reflection.get_emission());
+                    }
+                    Source_parameter::Reflections(id, listener, reflections, channels)
+                }
+                Source_parameter::Directivity(id, directivity) => {
+                    Source_parameter::Directivity(id, spatializer_bank.set_source_directivity(id, directivity))
+                }
+                Source_parameter::CoupledReverb(id, coupled_reverb) => {
+                    Source_parameter::CoupledReverb(id, spatializer_bank.set_coupled_reverb(id, coupled_reverb))
+                }
+                Source_parameter::RoomReverb(id, room_reverb) => {
+                    Source_parameter::RoomReverb(id, spatializer_bank.set_room_reverb(id, room_reverb))
+                }
+                Source_parameter::RoomModes(id, modes) => Source_parameter::RoomModes(id, spatializer_bank.set_room_modes(id, modes)),
+                Source_parameter::RoomModel(id, room_model, mut channels) => {
+                    let room_model = spatializer_bank.set_room_model(id, room_model, &mut channels);
+                    Source_parameter::RoomModel(id, room_model, channels)
+                }
+            };
+            dispose(Garbage::Parameter(garbage));
+        }
+        for channel in spatializer_bank.take_removed() {
+            dispose(Garbage::Channel(channel));
+        }
+
+        // update sources if a new scene arrived
+        if let Ok(scene_update) = scene_update {
+            // the channels are added and removed by id with Source_parameter::Sources
+            let SceneUpdate { source_ids, scene_data } = &scene_update;
+            let listener_transform = &*scene_data.listener.transform;
+            let listener_position = get_position(listener_transform);
+
+            for (&source_id, source_transform) in source_ids.iter().zip(scene_data.sources.transforms.iter()) {
+                if let Some(brir_sets) = brir_sets.as_ref() {
+                    // BRIRs are measured for source positions and head orientations, not
+                    // source directions
+                    let set_idx = brir_sets.find_closest_set(&get_position(source_transform));
+                    let set_changed = spatializer_bank
+                        .get_channel(source_id)
+                        .is_some_and(|channel| channel.get_active_storage_idx() != set_idx);
+                    let (yaw, pitch) = head_yaw_pitch(&get_quaternion(listener_transform));
+                    if set_changed || spatializer_bank.needs_filter_update(source_id, yaw, pitch) {
+                        let filter_id = brir_sets.get_set(set_idx).find_closest_filter(yaw, pitch);
+                        spatializer_bank.set_filter_for_direction_in_storage(source_id, set_idx, filter_id, yaw, pitch);
+                    }
+                    continue;
+                }
+
+                let source_position = get_position(source_transform);
+                spatializer_bank.set_distance(source_id, (source_position - listener_position).norm());
+
+                // room modes and room model nodes follow source and listener (both are built
+                // by the scene handler)
+                spatializer_bank.set_room_mode_positions(source_id, &source_position, &listener_position);
+                spatializer_bank.set_room_positions(source_id, &source_position, &listener_position);
+
+                // virtual sources (diffraction paths, room model nodes) share the transform of their source
+                let virtual_ids = (0..MAX_VIRTUAL_SOURCES).map(|idx| virtual_source_id(source_id, idx));
+                for id in std::iter::once(source_id).chain(virtual_ids) {
+                    if !spatializer_bank.contains_source(id) {
+                        continue;
+                    }
+                    // sources outside the room are heard from their transmission point
+                    let apparent_position = spatializer_bank.get_apparent_position(id).unwrap_or(source_position);
+                    let (_, azimuth, elevation) =
+                        calculate_azimuth_and_elevation_of_direction(listener_transform, &(apparent_position - listener_position));
+                    let (azimuth, elevation) = (azimuth.to_degrees(), elevation.to_degrees());
+                    if spatializer_bank.needs_filter_update(id, azimuth, elevation) {
+                        let filter_id = hrtf_tree.find_closest_stereo_filter_angle(
+                            BinauralFilterType::DirectSound,
+                            azimuth,
+                            elevation,
+                        );
+                        spatializer_bank.set_filter_for_direction(id, filter_id, azimuth, elevation);
+                    }
+
+                    // emission direction in the frame of the source: along the first leg of a
+                    // reflection, otherwise towards the listener (the edge for diffraction paths)
+                    let target = match id == source_id {
+                        true => listener_position,
+                        false => apparent_position,
+                    };
+                    if let Some(source_directivity) = spatializer_bank.get_source_directivity(id) {
+                        let emission = spatializer_bank.get_emission_direction(id).unwrap_or(target - source_position);
+                        let (_, emission_azimuth, emission_elevation) =
+                            calculate_azimuth_and_elevation_of_direction(source_transform, &emission);
+                        let sd_filter_id = source_directivity.find_closest_filter(
+                            emission_azimuth.to_degrees(),
+                            emission_elevation.to_degrees(),
+                        );
+                        spatializer_bank.set_directivity_filter(id, sd_filter_id);
+                    }
+                }
+            }
+        }
+
+        // read audio for every obejct.
+        spatializer_bank.read_source_signals();
+        match brir_sets.as_ref() {
+            Some(brir_sets) => spatializer_bank.process_per_source(block, &|idx| brir_sets.get_set(idx).get_storage(), None),
+            None => spatializer_bank.process(block, &hrtf_storage, None),
+        }
+    };
+
+    // the device asks for any number of frames with any number of channels: the rendered
+    // blocks are queued, the stereo signal goes to the first two channels (mixed for mono devices)
+    let mut block = vec![0.0; 2 * buffer_size];
+    let mut block_pos = buffer_size;
     // Create Stream
     let stream = devcice.build_output_stream(
         config,
-        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            // read audio for every obejct.
-            // collect
-            let scene_data = match rx.try_recv() {
-                Ok(v) => v,
-                Err(_) => panic!("This should not panic!"),
-            };
-            for i in 0..n_sources {
-                // calc image n_sources
-
-                spatializer.process(input, data, active_hrtfs[i], prev_hrtfs[i]);
-                audio_process(data, channels, scene_data);
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            for frame in data.chunks_mut(channels) {
+                if block_pos == buffer_size {
+                    render_block(&mut block);
+                    block_pos = 0;
+                }
+                let (left, right) = (block[2 * block_pos], block[2 * block_pos + 1]);
+                match frame {
+                    [mono] => *mono = T::from_sample(0.5 * (left + right)),
+                    [l, r, rest @ ..] => {
+                        *l = T::from_sample(left);
+                        *r = T::from_sample(right);
+                        rest.fill(T::EQUILIBRIUM);
+                    }
+                    [] => {}
+                }
+                block_pos += 1;
             }
         },
         error_callback,
@@ -85,14 +388,8 @@ where
     )?;
 
     stream.play()?;
-
-    Ok(())
-}
-
-fn audio_process<T>(output_buffer: &mut [T], channels: usize, scene_data: Scene_data)
-where
-    T: Sample + FromSample<f32>,
-{
-    todo!();
-    // UpdateEngine(scene_data);
+    // the stream stops when it is dropped
+    loop {
+        thread::park();
+    }
 }
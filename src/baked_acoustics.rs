@@ -0,0 +1,492 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nalgebra::{Point3, Quaternion, Vector3};
+
+use crate::{
+    biquad::OCTAVE_BAND_CENTRES,
+    image_source_method::{ISMAcousticScene, ISMListener},
+    ray_tracer::{LateReverb, RayTracerSettings},
+    transmission::WallTransmission,
+};
+
+const MAGIC: &[u8; 4] = b"RBAK";
+// version 2 added the energy of the late reverberation
+const VERSION: u32 = 2;
+const N_BANDS: usize = OCTAVE_BAND_CENTRES.len();
+
+// strongest early reflections kept per probe
+pub const MAX_BAKED_REFLECTIONS: usize = 16;
+// static sources may move this far from their baked position (m)
+const SOURCE_TOLERANCE: f32 = 0.1;
+
+// Early reflection arriving at a probe. path identifies the image source, so the same
+// reflection can be found in the neighbouring probes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BakedReflection {
+    path: u32,
+    length: f32,
+    // wall reflections and occlusion, without the spreading loss
+    gain: f32,
+    // direction of arrival (world frame)
+    direction: Vector3<f32>,
+}
+
+impl BakedReflection {
+    pub fn new(path: u32, length: f32, gain: f32, direction: Vector3<f32>) -> Self {
+        Self {
+            path,
+            length,
+            gain,
+            direction,
+        }
+    }
+
+    pub fn get_path(&self) -> u32 {
+        self.path
+    }
+
+    pub fn get_length(&self) -> f32 {
+        self.length
+    }
+
+    pub fn get_gain(&self) -> f32 {
+        self.gain
+    }
+
+    pub fn get_direction(&self) -> Vector3<f32> {
+        self.direction
+    }
+
+    pub fn get_level(&self) -> f32 {
+        self.gain / self.length.max(f32::EPSILON)
+    }
+
+    pub fn get_delay(&self, speed_of_sound: f32) -> f32 {
+        self.length / speed_of_sound
+    }
+
+    // rendered like a source at the image source position of the reflection
+    pub fn to_transmission(&self, listener: &Point3<f32>) -> WallTransmission {
+        WallTransmission::from_band_gains(listener + self.direction * self.length, [self.gain; N_BANDS])
+    }
+}
+
+// Parametric response of one source at one probe position.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProbeData {
+    reflections: Vec<BakedReflection>,
+    // per octave band (s), 0 if unknown
+    decay_times: [f32; N_BANDS],
+    // energy of the late reverberation below 1 kHz (see LateReverb), 0 if unknown
+    late_energy: f32,
+}
+
+impl ProbeData {
+    pub fn get_reflections(&self) -> &[BakedReflection] {
+        &self.reflections
+    }
+
+    pub fn get_decay_times(&self) -> [f32; N_BANDS] {
+        self.decay_times
+    }
+
+    // drives the late reverberation instead of the traced one of the room, None if the probe
+    // was baked without the ray tracer
+    pub fn get_late_reverb(&self) -> Option<LateReverb> {
+        let late_reverb = LateReverb {
+            decay_times: self.decay_times,
+            energy: self.late_energy,
+        };
+        (late_reverb.energy > 0.0 && late_reverb.get_decay_range().0 > 0.0).then_some(late_reverb)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BakeSettings {
+    // distance of the probes (m)
+    pub spacing: f32,
+    pub max_reflections: usize,
+    // decay times are only baked with ray tracer settings
+    pub ray_tracer: Option<RayTracerSettings>,
+}
+
+impl Default for BakeSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 1.0,
+            max_reflections: MAX_BAKED_REFLECTIONS,
+            ray_tracer: Some(RayTracerSettings {
+                n_rays: 2000,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+// Probes on a regular grid filling the room, for one static source. At runtime the probes
+// around the listener are interpolated trilinearly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeGrid {
+    source: Point3<f32>,
+    origin: Point3<f32>,
+    spacing: f32,
+    size: [usize; 3],
+    probes: Vec<ProbeData>,
+}
+
+impl ProbeGrid {
+    // moves the listener of the scene through the probe positions
+    pub fn bake(scene: &mut ISMAcousticScene, source_idx: usize, settings: &BakeSettings) -> Self {
+        let (min, max) = scene.get_room().get_bounds();
+        let extent = max - min;
+        let size = [0, 1, 2].map(|axis| ((extent[axis] / settings.spacing).floor() as usize).max(1));
+        // centred in the room
+        let origin = min + (extent - Vector3::from(size.map(|n| (n - 1) as f32 * settings.spacing))) / 2.0;
+        let mut grid = Self {
+            source: scene.get_source_position(source_idx),
+            origin,
+            spacing: settings.spacing,
+            size,
+            probes: Vec::with_capacity(size.iter().product()),
+        };
+        for idx in 0..size.iter().product() {
+            let position = grid.get_probe_position(idx);
+            scene.set_listener(ISMListener::new(position, Quaternion::identity()));
+            let probe = bake_probe(scene, source_idx, &position, settings);
+            grid.probes.push(probe);
+        }
+        grid
+    }
+
+    pub fn get_source_position(&self) -> Point3<f32> {
+        self.source
+    }
+
+    pub fn get_size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    pub fn get_spacing(&self) -> f32 {
+        self.spacing
+    }
+
+    pub fn get_probes(&self) -> &[ProbeData] {
+        &self.probes
+    }
+
+    // x runs fastest
+    pub fn get_probe_position(&self, idx: usize) -> Point3<f32> {
+        let cell = [idx % self.size[0], idx / self.size[0] % self.size[1], idx / (self.size[0] * self.size[1])];
+        self.origin + Vector3::from(cell.map(|n| n as f32 * self.spacing))
+    }
+
+    // trilinear interpolation of the probes around the listener (clamped to the grid). A
+    // reflection missing in some of the probes fades out towards them.
+    pub fn interpolate(&self, listener: &Point3<f32>) -> ProbeData {
+        let mut cells = [(0, 0, 0.0); 3];
+        for axis in 0..3 {
+            let u = ((listener[axis] - self.origin[axis]) / self.spacing).clamp(0.0, (self.size[axis] - 1) as f32);
+            let lower = (u.floor() as usize).min(self.size[axis].saturating_sub(2));
+            let upper = (lower + 1).min(self.size[axis] - 1);
+            cells[axis] = (lower, upper, u - lower as f32);
+        }
+        // path -> (weight, weighted gain, weighted length, weighted direction)
+        let mut reflections: HashMap<u32, (f32, f32, f32, Vector3<f32>)> = HashMap::new();
+        let mut decay_times = [(0.0, 0.0); N_BANDS];
+        let mut late_energy = (0.0, 0.0);
+        for corner in 0..8 {
+            let mut idx = 0;
+            let mut weight = 1.0;
+            for axis in (0..3).rev() {
+                let (lower, upper, fraction) = cells[axis];
+                let upper_side = corner >> axis & 1 == 1;
+                weight *= if upper_side { fraction } else { 1.0 - fraction };
+                idx = idx * self.size[axis] + if upper_side { upper } else { lower };
+            }
+            if weight <= 0.0 {
+                continue;
+            }
+            let probe = &self.probes[idx];
+            for reflection in probe.reflections.iter() {
+                let entry = reflections.entry(reflection.path).or_insert((0.0, 0.0, 0.0, Vector3::zeros()));
+                entry.0 += weight;
+                entry.1 += weight * reflection.gain;
+                entry.2 += weight * reflection.length;
+                entry.3 += weight * reflection.direction;
+            }
+            for (sum, decay_time) in decay_times.iter_mut().zip(probe.decay_times) {
+                if decay_time > 0.0 {
+                    *sum = (sum.0 + weight * decay_time, sum.1 + weight);
+                }
+            }
+            if probe.late_energy > 0.0 {
+                late_energy = (late_energy.0 + weight * probe.late_energy, late_energy.1 + weight);
+            }
+        }
+        let average = |(sum, weight): (f32, f32)| if weight > 0.0 { sum / weight } else { 0.0 };
+        let mut reflections: Vec<BakedReflection> = reflections
+            .into_iter()
+            .map(|(path, (weight, gain, length, direction))| BakedReflection {
+                path,
+                length: length / weight,
+                gain,
+                direction: direction.try_normalize(f32::EPSILON).unwrap_or_else(Vector3::zeros),
+            })
+            .collect();
+        reflections.sort_by(|a, b| b.get_level().total_cmp(&a.get_level()).then(a.path.cmp(&b.path)));
+        reflections.truncate(MAX_BAKED_REFLECTIONS);
+        ProbeData {
+            reflections,
+            decay_times: decay_times.map(average),
+            late_energy: average(late_energy),
+        }
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_f32s(writer, self.source.coords.as_slice())?;
+        write_f32s(writer, self.origin.coords.as_slice())?;
+        writer.write_f32::<LittleEndian>(self.spacing)?;
+        for n in self.size {
+            writer.write_u32::<LittleEndian>(n as u32)?;
+        }
+        for probe in self.probes.iter() {
+            writer.write_u32::<LittleEndian>(probe.reflections.len() as u32)?;
+            for reflection in probe.reflections.iter() {
+                writer.write_u32::<LittleEndian>(reflection.path)?;
+                write_f32s(writer, &[reflection.length, reflection.gain])?;
+                write_f32s(writer, reflection.direction.as_slice())?;
+            }
+            write_f32s(writer, &probe.decay_times)?;
+            writer.write_f32::<LittleEndian>(probe.late_energy)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let source = Point3::from(read_f32s::<3>(reader)?);
+        let origin = Point3::from(read_f32s::<3>(reader)?);
+        let spacing = reader.read_f32::<LittleEndian>()?;
+        let mut size = [0; 3];
+        for n in size.iter_mut() {
+            *n = reader.read_u32::<LittleEndian>()? as usize;
+        }
+        if size.contains(&0) || spacing <= 0.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid probe grid"));
+        }
+        let mut probes = Vec::new();
+        for _ in 0..size.iter().product() {
+            let n_reflections = reader.read_u32::<LittleEndian>()?;
+            let mut reflections = Vec::new();
+            for _ in 0..n_reflections {
+                let path = reader.read_u32::<LittleEndian>()?;
+                let [length, gain] = read_f32s::<2>(reader)?;
+                let direction = Vector3::from(read_f32s::<3>(reader)?);
+                reflections.push(BakedReflection::new(path, length, gain, direction));
+            }
+            let decay_times = read_f32s::<N_BANDS>(reader)?;
+            let late_energy = reader.read_f32::<LittleEndian>()?;
+            probes.push(ProbeData {
+                reflections,
+                decay_times,
+                late_energy,
+            });
+        }
+        Ok(Self {
+            source,
+            origin,
+            spacing,
+            size,
+            probes,
+        })
+    }
+}
+
+// Probe grids of all static sources of a scene, stored in one file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BakedAcoustics {
+    grids: Vec<ProbeGrid>,
+}
+
+impl BakedAcoustics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_grid(&mut self, grid: ProbeGrid) {
+        self.grids.push(grid);
+    }
+
+    pub fn get_grids(&self) -> &[ProbeGrid] {
+        &self.grids
+    }
+
+    // grid baked for a source at this position
+    pub fn find_grid(&self, source: &Point3<f32>) -> Option<&ProbeGrid> {
+        self.grids
+            .iter()
+            .map(|grid| ((grid.source - source).norm(), grid))
+            .filter(|(distance, _)| *distance < SOURCE_TOLERANCE)
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, grid)| grid)
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        BakedAcoustics::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<LittleEndian>(VERSION)?;
+        writer.write_u32::<LittleEndian>(self.grids.len() as u32)?;
+        self.grids.iter().try_for_each(|grid| grid.write(writer))
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || reader.read_u32::<LittleEndian>()? != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a baked acoustics file"));
+        }
+        let n_grids = reader.read_u32::<LittleEndian>()?;
+        let grids = (0..n_grids).map(|_| ProbeGrid::read(reader)).collect::<io::Result<_>>()?;
+        Ok(Self { grids })
+    }
+}
+
+fn bake_probe(scene: &ISMAcousticScene, source_idx: usize, position: &Point3<f32>, settings: &BakeSettings) -> ProbeData {
+    let (_, occlusion) = scene.get_path_occlusion(source_idx);
+    let mut reflections: Vec<BakedReflection> = scene
+        .get_image_source_positions(source_idx)
+        .iter()
+        .zip(occlusion.iter())
+        .enumerate()
+        .filter_map(|(path, (image_source, occlusion))| {
+            let offset = image_source - position;
+            let length = offset.norm();
+            let direction = offset.try_normalize(f32::EPSILON)?;
+            let gain = scene.get_room().get_reflection_gain(image_source) * occlusion.gain();
+            Some(BakedReflection::new(path as u32, length, gain, direction))
+        })
+        .collect();
+    reflections.sort_by(|a, b| b.get_level().total_cmp(&a.get_level()));
+    reflections.truncate(settings.max_reflections);
+
+    let late_reverb = settings
+        .ray_tracer
+        .as_ref()
+        .and_then(|ray_tracer| scene.trace_rays(source_idx, ray_tracer).get_late_reverb());
+    ProbeData {
+        reflections,
+        decay_times: late_reverb.map_or([0.0; N_BANDS], |late_reverb| late_reverb.decay_times),
+        late_energy: late_reverb.map_or(0.0, |late_reverb| late_reverb.energy),
+    }
+}
+
+fn write_f32s(writer: &mut impl Write, values: &[f32]) -> io::Result<()> {
+    values.iter().try_for_each(|v| writer.write_f32::<LittleEndian>(*v))
+}
+
+fn read_f32s<const N: usize>(reader: &mut impl Read) -> io::Result<[f32; N]> {
+    let mut values = [0.0; N];
+    for value in values.iter_mut() {
+        *value = reader.read_f32::<LittleEndian>()?;
+    }
+    Ok(values)
+}
+
+#[test]
+fn test_probe_bake_and_interpolation() {
+    use crate::image_source_method::{ISMRoom, ISMSoundSource};
+
+    let room = ISMRoom::new(Vector3::new(6.0, 3.0, 4.0), [0.2; 6], 343.0);
+    let source = Point3::new(1.2, 1.5, 1.1);
+    let listener = ISMListener::new(Point3::new(2.0, 3.0, 1.5), Quaternion::identity());
+    let mut scene = ISMAcousticScene::new(room, listener, vec![ISMSoundSource::new(source, Quaternion::identity())], 2);
+    let settings = BakeSettings {
+        ray_tracer: None,
+        ..Default::default()
+    };
+    let grid = ProbeGrid::bake(&mut scene, 0, &settings);
+    assert_eq!(grid.get_size(), [4, 6, 3]);
+
+    // first order reflections lose sqrt(0.8), second order 0.8
+    let probe = &grid.get_probes()[0];
+    assert_eq!(probe.get_reflections().len(), MAX_BAKED_REFLECTIONS);
+    for reflection in probe.get_reflections() {
+        let order = if reflection.get_path() < 6 { 1 } else { 2 };
+        assert!((reflection.get_gain() - 0.8f32.powf(order as f32 / 2.0)).abs() < 1e-5);
+    }
+
+    // between the probes the interpolation follows the image sources
+    let position = Point3::new(2.2, 2.9, 1.3);
+    scene.set_listener(ISMListener::new(position, Quaternion::identity()));
+    let image_sources = scene.get_image_source_positions(0);
+    let interpolated = grid.interpolate(&position);
+    for reflection in interpolated.get_reflections().iter().take(6) {
+        let image_source = image_sources[reflection.get_path() as usize];
+        let error = (reflection.to_transmission(&position).get_point() - image_source).norm();
+        assert!(error < 0.05 * reflection.get_length(), "{error}");
+    }
+    // probes are reproduced exactly
+    let (baked, interpolated) = (grid.get_probes()[5].get_reflections()[0], grid.interpolate(&grid.get_probe_position(5)).get_reflections()[0]);
+    assert_eq!(baked.get_path(), interpolated.get_path());
+    assert!((baked.get_length() - interpolated.get_length()).abs() < 1e-4);
+
+    let mut baked = BakedAcoustics::new();
+    baked.add_grid(grid);
+    let mut bytes = Vec::new();
+    baked.write(&mut bytes).unwrap();
+    let loaded = BakedAcoustics::read(&mut bytes.as_slice()).unwrap();
+    assert_eq!(loaded, baked);
+    assert!(loaded.find_grid(&(source + Vector3::new(0.05, 0.0, 0.0))).is_some());
+    assert!(loaded.find_grid(&Point3::new(3.0, 3.0, 1.0)).is_none());
+}
+
+#[test]
+fn test_probe_late_reverb() {
+    use crate::image_source_method::{ISMRoom, ISMSoundSource};
+
+    let room = ISMRoom::new(Vector3::new(4.0, 3.0, 3.0), [0.2; 6], 343.0);
+    let source = Point3::new(1.0, 1.5, 1.0);
+    let listener = ISMListener::new(Point3::new(2.0, 1.5, 1.5), Quaternion::identity());
+    let mut scene = ISMAcousticScene::new(room, listener, vec![ISMSoundSource::new(source, Quaternion::identity())], 1);
+    let settings = BakeSettings {
+        spacing: 2.0,
+        ray_tracer: Some(RayTracerSettings {
+            n_rays: 500,
+            max_time: 1.0,
+            diffuse_only: false,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let grid = ProbeGrid::bake(&mut scene, 0, &settings);
+    let late_reverbs: Vec<LateReverb> = grid.get_probes().iter().map(|probe| probe.get_late_reverb().unwrap()).collect();
+
+    // the interpolated late reverberation lies between the probes
+    let interpolated = grid.interpolate(&Point3::new(2.0, 1.5, 1.5)).get_late_reverb().unwrap();
+    let (min, max) = late_reverbs.iter().fold((f32::MAX, 0.0f32), |(min, max), r| (min.min(r.energy), max.max(r.energy)));
+    assert!(interpolated.energy >= min && interpolated.energy <= max);
+    let low_decay = |late_reverb: &LateReverb| late_reverb.get_decay_range().0;
+    assert!(late_reverbs.iter().any(|r| low_decay(r) <= low_decay(&interpolated) + 1e-6));
+    assert!(late_reverbs.iter().any(|r| low_decay(r) >= low_decay(&interpolated) - 1e-6));
+
+    // and survives the file
+    let mut baked = BakedAcoustics::new();
+    baked.add_grid(grid);
+    let mut bytes = Vec::new();
+    baked.write(&mut bytes).unwrap();
+    let loaded = BakedAcoustics::read(&mut bytes.as_slice()).unwrap();
+    assert_eq!(loaded.get_grids()[0].get_probes()[0].get_late_reverb(), Some(late_reverbs[0]));
+}
@@ -0,0 +1,457 @@
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+// nominal octave band centre frequencies (Hz)
+pub const OCTAVE_BAND_CENTRES: [f32; 8] = [63.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0];
+
+// Filter designs of the RBJ audio EQ cookbook. Gains are in dB.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BiquadType {
+    LowPass,
+    HighPass,
+    BandPass,
+    LowShelf(f32),
+    HighShelf(f32),
+    Peaking(f32),
+    AllPass,
+}
+
+// normalized coefficients (a0 = 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BiquadCoefficients {
+    pub b0: f32,
+    pub b1: f32,
+    pub b2: f32,
+    pub a1: f32,
+    pub a2: f32,
+}
+
+impl Default for BiquadCoefficients {
+    // identity
+    fn default() -> Self {
+        Self {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+        }
+    }
+}
+
+impl BiquadCoefficients {
+    pub fn design(filter_type: BiquadType, frequency: f32, q: f32, sample_rate: f32) -> Self {
+        let w0 = 2.0 * PI * frequency.clamp(1.0, 0.499 * sample_rate) / sample_rate;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * q.max(1e-3));
+
+        let (b0, b1, b2, a0, a1, a2) = match filter_type {
+            BiquadType::LowPass => {
+                let b1 = 1.0 - cos_w0;
+                (b1 / 2.0, b1, b1 / 2.0, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha)
+            }
+            BiquadType::HighPass => {
+                let b1 = -(1.0 + cos_w0);
+                (-b1 / 2.0, b1, -b1 / 2.0, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha)
+            }
+            // constant 0 dB peak gain
+            BiquadType::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha),
+            BiquadType::LowShelf(gain) => {
+                let a = 10f32.powf(gain / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + k),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - k),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + k,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - k,
+                )
+            }
+            BiquadType::HighShelf(gain) => {
+                let a = 10f32.powf(gain / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + k),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - k),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + k,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - k,
+                )
+            }
+            BiquadType::Peaking(gain) => {
+                let a = 10f32.powf(gain / 40.0);
+                (
+                    1.0 + alpha * a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha * a,
+                    1.0 + alpha / a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha / a,
+                )
+            }
+            BiquadType::AllPass => (
+                1.0 - alpha,
+                -2.0 * cos_w0,
+                1.0 + alpha,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+        };
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    // magnitude response at the given frequency
+    pub fn magnitude(&self, frequency: f32, sample_rate: f32) -> f32 {
+        let w = 2.0 * PI * frequency / sample_rate;
+        let (sin_w, cos_w) = w.sin_cos();
+        let (sin_2w, cos_2w) = (2.0 * w).sin_cos();
+        let num_re = self.b0 + self.b1 * cos_w + self.b2 * cos_2w;
+        let num_im = -(self.b1 * sin_w + self.b2 * sin_2w);
+        let den_re = 1.0 + self.a1 * cos_w + self.a2 * cos_2w;
+        let den_im = -(self.a1 * sin_w + self.a2 * sin_2w);
+        ((num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im)).sqrt()
+    }
+
+    fn lerp(&self, other: &BiquadCoefficients, t: f32) -> Self {
+        Self {
+            b0: self.b0 + (other.b0 - self.b0) * t,
+            b1: self.b1 + (other.b1 - self.b1) * t,
+            b2: self.b2 + (other.b2 - self.b2) * t,
+            a1: self.a1 + (other.a1 - self.a1) * t,
+            a2: self.a2 + (other.a2 - self.a2) * t,
+        }
+    }
+}
+
+// Biquad in transposed direct form II. New coefficients are interpolated linearly over
+// ramp_length samples, which avoids zipper noise when filters are updated every block.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Biquad {
+    coefficients: BiquadCoefficients,
+    start: BiquadCoefficients,
+    target: BiquadCoefficients,
+    ramp_length: usize,
+    ramp_position: usize,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    pub fn new(coefficients: BiquadCoefficients) -> Self {
+        Self {
+            coefficients,
+            start: coefficients,
+            target: coefficients,
+            ..Default::default()
+        }
+    }
+
+    pub fn get_coefficients(&self) -> BiquadCoefficients {
+        self.target
+    }
+
+    pub fn set_coefficients(&mut self, coefficients: BiquadCoefficients, ramp_length: usize) {
+        self.start = self.coefficients;
+        self.target = coefficients;
+        self.ramp_length = ramp_length;
+        self.ramp_position = 0;
+        if ramp_length == 0 {
+            self.coefficients = coefficients;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        if self.ramp_position < self.ramp_length {
+            self.ramp_position += 1;
+            self.coefficients = self
+                .start
+                .lerp(&self.target, self.ramp_position as f32 / self.ramp_length as f32);
+        }
+        let c = &self.coefficients;
+        let y = c.b0 * x + self.z1;
+        self.z1 = c.b1 * x - c.a1 * y + self.z2;
+        self.z2 = c.b2 * x - c.a2 * y;
+        y
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        for s in buffer.iter_mut() {
+            *s = self.process_sample(*s);
+        }
+    }
+}
+
+// Series connection of biquads, e.g. a parametric EQ or a higher order filter.
+#[derive(Debug, Clone, Default)]
+pub struct BiquadCascade {
+    stages: Vec<Biquad>,
+}
+
+impl BiquadCascade {
+    pub fn new(coefficients: &[BiquadCoefficients]) -> Self {
+        Self {
+            stages: coefficients.iter().map(|c| Biquad::new(*c)).collect(),
+        }
+    }
+
+    pub fn get_n_stages(&self) -> usize {
+        self.stages.len()
+    }
+
+    // the number of stages has to stay the same, otherwise the filter is rebuilt
+    pub fn set_coefficients(&mut self, coefficients: &[BiquadCoefficients], ramp_length: usize) {
+        if coefficients.len() != self.stages.len() {
+            *self = BiquadCascade::new(coefficients);
+            return;
+        }
+        for (stage, c) in self.stages.iter_mut().zip(coefficients.iter()) {
+            stage.set_coefficients(*c, ramp_length);
+        }
+    }
+
+    pub fn magnitude(&self, frequency: f32, sample_rate: f32) -> f32 {
+        self.stages
+            .iter()
+            .map(|s| s.get_coefficients().magnitude(frequency, sample_rate))
+            .product()
+    }
+
+    pub fn reset(&mut self) {
+        self.stages.iter_mut().for_each(|s| s.reset());
+    }
+
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        self.stages.iter_mut().fold(x, |s, stage| stage.process_sample(s))
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        for stage in self.stages.iter_mut() {
+            stage.process(buffer);
+        }
+    }
+}
+
+// 4th order Linkwitz-Riley crossover (two cascaded Butterworth sections per output)
+#[derive(Debug, Clone)]
+struct LinkwitzRileyCrossover {
+    low: [Biquad; 2],
+    high: [Biquad; 2],
+}
+
+impl LinkwitzRileyCrossover {
+    fn new(frequency: f32, sample_rate: f32) -> Self {
+        let low = Biquad::new(BiquadCoefficients::design(BiquadType::LowPass, frequency, FRAC_1_SQRT_2, sample_rate));
+        let high = Biquad::new(BiquadCoefficients::design(BiquadType::HighPass, frequency, FRAC_1_SQRT_2, sample_rate));
+        Self {
+            low: [low, low],
+            high: [high, high],
+        }
+    }
+
+    fn process_sample(&mut self, x: f32) -> (f32, f32) {
+        let low = self.low[0].process_sample(x);
+        let low = self.low[1].process_sample(low);
+        let high = self.high[0].process_sample(x);
+        let high = self.high[1].process_sample(high);
+        (low, high)
+    }
+}
+
+// Splits a signal into bands with Linkwitz-Riley crossovers. The lower bands are passed
+// through the allpasses of the higher crossovers, so the bands sum up to an allpass
+// (flat magnitude) again.
+#[derive(Debug, Clone)]
+pub struct LinkwitzRileyFilterbank {
+    crossovers: Vec<LinkwitzRileyCrossover>,
+    // phase compensation, allpasses[band] holds one allpass per higher crossover
+    allpasses: Vec<Vec<Biquad>>,
+    band_samples: Vec<f32>,
+}
+
+impl LinkwitzRileyFilterbank {
+    // crossover frequencies in ascending order, n_bands = crossover_frequencies.len() + 1
+    pub fn new(crossover_frequencies: &[f32], sample_rate: f32) -> Self {
+        let crossovers = crossover_frequencies
+            .iter()
+            .map(|f| LinkwitzRileyCrossover::new(*f, sample_rate))
+            .collect();
+        let allpasses = (0..=crossover_frequencies.len())
+            .map(|band| {
+                crossover_frequencies
+                    .iter()
+                    .skip(band + 1)
+                    .map(|f| {
+                        Biquad::new(BiquadCoefficients::design(BiquadType::AllPass, *f, FRAC_1_SQRT_2, sample_rate))
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            crossovers,
+            allpasses,
+            band_samples: vec![0.0; crossover_frequencies.len() + 1],
+        }
+    }
+
+    // bands around OCTAVE_BAND_CENTRES, crossovers at the upper band edges
+    pub fn octave_bands(sample_rate: f32) -> Self {
+        let crossover_frequencies: Vec<f32> = OCTAVE_BAND_CENTRES[..OCTAVE_BAND_CENTRES.len() - 1]
+            .iter()
+            .map(|f| f * 2f32.sqrt())
+            .collect();
+        LinkwitzRileyFilterbank::new(&crossover_frequencies, sample_rate)
+    }
+
+    pub fn get_n_bands(&self) -> usize {
+        self.crossovers.len() + 1
+    }
+
+    pub fn reset(&mut self) {
+        for crossover in self.crossovers.iter_mut() {
+            crossover.low.iter_mut().chain(crossover.high.iter_mut()).for_each(|b| b.reset());
+        }
+        self.allpasses.iter_mut().flatten().for_each(|b| b.reset());
+    }
+
+    // writes one sample per band into bands
+    pub fn process_sample(&mut self, x: f32, bands: &mut [f32]) {
+        let mut remainder = x;
+        for (band, crossover) in self.crossovers.iter_mut().enumerate() {
+            let (low, high) = crossover.process_sample(remainder);
+            bands[band] = low;
+            remainder = high;
+        }
+        bands[self.crossovers.len()] = remainder;
+        for (band, allpasses) in self.allpasses.iter_mut().enumerate() {
+            for allpass in allpasses.iter_mut() {
+                bands[band] = allpass.process_sample(bands[band]);
+            }
+        }
+    }
+
+    // splits input into bands[band][sample]
+    pub fn process(&mut self, input: &[f32], bands: &mut [Vec<f32>]) {
+        let mut band_samples = std::mem::take(&mut self.band_samples);
+        for (i, x) in input.iter().enumerate() {
+            self.process_sample(*x, &mut band_samples);
+            for (band, y) in band_samples.iter().enumerate() {
+                bands[band][i] = *y;
+            }
+        }
+        self.band_samples = band_samples;
+    }
+}
+
+// Band-wise gains on top of a Linkwitz-Riley filterbank, e.g. for frequency-dependent
+// material losses. Gain changes are ramped over one block.
+#[derive(Debug, Clone)]
+pub struct BandEqualizer {
+    filterbank: LinkwitzRileyFilterbank,
+    gains: Vec<f32>,
+    target_gains: Vec<f32>,
+    steps: Vec<f32>,
+    band_samples: Vec<f32>,
+}
+
+impl BandEqualizer {
+    pub fn new(filterbank: LinkwitzRileyFilterbank) -> Self {
+        let n_bands = filterbank.get_n_bands();
+        Self {
+            filterbank,
+            gains: vec![1.0; n_bands],
+            target_gains: vec![1.0; n_bands],
+            steps: vec![0.0; n_bands],
+            band_samples: vec![0.0; n_bands],
+        }
+    }
+
+    pub fn octave_bands(sample_rate: f32) -> Self {
+        BandEqualizer::new(LinkwitzRileyFilterbank::octave_bands(sample_rate))
+    }
+
+    pub fn get_n_bands(&self) -> usize {
+        self.gains.len()
+    }
+
+    // linear gain per band, missing bands keep their gain
+    pub fn set_gains(&mut self, gains: &[f32]) {
+        for (target, gain) in self.target_gains.iter_mut().zip(gains.iter()) {
+            *target = *gain;
+        }
+    }
+
+    pub fn get_gains(&self) -> &[f32] {
+        &self.target_gains
+    }
+
+    // clears the filter states, the gains jump to their targets
+    pub fn reset(&mut self) {
+        self.filterbank.reset();
+        self.gains.copy_from_slice(&self.target_gains);
+    }
+
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        let n_points = buffer.len() as f32;
+        for ((step, gain), target) in self.steps.iter_mut().zip(self.gains.iter()).zip(self.target_gains.iter()) {
+            *step = (target - gain) / n_points;
+        }
+        for s in buffer.iter_mut() {
+            self.filterbank.process_sample(*s, &mut self.band_samples);
+            let mut y = 0.0;
+            for ((band, gain), step) in self.band_samples.iter().zip(self.gains.iter_mut()).zip(self.steps.iter()) {
+                *gain += step;
+                y += band * *gain;
+            }
+            *s = y;
+        }
+        self.gains.copy_from_slice(&self.target_gains);
+    }
+}
+
+#[test]
+fn test_rbj_designs() {
+    let fs = 48000.0;
+    let low_pass = BiquadCoefficients::design(BiquadType::LowPass, 1000.0, FRAC_1_SQRT_2, fs);
+    assert!((low_pass.magnitude(10.0, fs) - 1.0).abs() < 1e-3);
+    assert!((low_pass.magnitude(1000.0, fs) - FRAC_1_SQRT_2).abs() < 1e-3);
+    let peaking = BiquadCoefficients::design(BiquadType::Peaking(6.0), 1000.0, 1.0, fs);
+    assert!((20.0 * peaking.magnitude(1000.0, fs).log10() - 6.0).abs() < 1e-2);
+    let high_shelf = BiquadCoefficients::design(BiquadType::HighShelf(-12.0), 2000.0, FRAC_1_SQRT_2, fs);
+    assert!((20.0 * high_shelf.magnitude(20000.0, fs).log10() + 12.0).abs() < 0.1);
+    let all_pass = BiquadCoefficients::design(BiquadType::AllPass, 500.0, FRAC_1_SQRT_2, fs);
+    assert!((all_pass.magnitude(3000.0, fs) - 1.0).abs() < 1e-4);
+}
+
+#[test]
+fn test_filterbank_sums_to_allpass() {
+    let fs = 48000.0;
+    let mut filterbank = LinkwitzRileyFilterbank::octave_bands(fs);
+    let n = 8192;
+    let mut input = vec![0.0; n];
+    input[0] = 1.0;
+    let mut bands = vec![vec![0.0; n]; filterbank.get_n_bands()];
+    filterbank.process(&input, &mut bands);
+    let sum: Vec<f32> = (0..n).map(|i| bands.iter().map(|b| b[i]).sum()).collect();
+    // magnitude of the summed impulse response
+    for frequency in [100.0, 1000.0, 5000.0, 15000.0] {
+        let w = 2.0 * PI * frequency / fs;
+        let (re, im) = sum.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, h)| {
+            (re + h * (w * i as f32).cos(), im - h * (w * i as f32).sin())
+        });
+        let magnitude: f32 = (re * re + im * im).sqrt();
+        assert!((magnitude - 1.0).abs() < 1e-2, "{frequency}: {magnitude}");
+    }
+}
@@ -0,0 +1,174 @@
+use std::{fs::File, io::{self, BufReader}};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use nalgebra::{Point3, Quaternion, UnitQuaternion, Vector3};
+
+use crate::filter::{BinauralFilterType, FFTManager, FilterStorage, FilterTree};
+
+// Measured binaural room impulse responses of one source position, indexed by the
+// head orientation (yaw, pitch in degrees) of the listener. Used by the data-based
+// rendering mode instead of HRTF + image sources.
+pub struct BrirSet {
+    storage: FilterStorage,
+    tree: FilterTree,
+}
+
+impl BrirSet {
+    // see FilterStorage::from_files for the file layout
+    pub fn new(filterpath: &str, anglepath: &str, filter_length: usize, fft: &mut FFTManager, blocksize: usize) -> io::Result<Self> {
+        let (storage, tree) = FilterStorage::from_files(
+            filterpath,
+            anglepath,
+            filter_length,
+            BinauralFilterType::LateReverberation,
+            fft,
+            blocksize,
+        )?;
+        Ok(Self { storage, tree })
+    }
+
+    pub fn from_storage(storage: FilterStorage, tree: FilterTree) -> Self {
+        Self { storage, tree }
+    }
+
+    pub fn get_storage(&self) -> &FilterStorage {
+        &self.storage
+    }
+
+    pub fn get_n_segments(&self) -> usize {
+        self.storage.get_max_n_stereo_segments()
+    }
+
+    // filter id of the measured head orientation closest to yaw/pitch (degrees)
+    pub fn find_closest_filter(&self, yaw: f32, pitch: f32) -> usize {
+        self.tree.find_closest_stereo_filter_angle(BinauralFilterType::LateReverberation, yaw, pitch)
+    }
+}
+
+// BRIR sets measured at several source positions. Every source is rendered with the set
+// measured closest to it.
+#[derive(Default)]
+pub struct BrirSets {
+    sets: Vec<(Point3<f32>, BrirSet)>,
+}
+
+impl BrirSets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // positionpath holds the measured source positions (x, y, z as f32, little endian), the
+    // set of position k is read from <prefix>_binary_<k>.dat and <prefix>_angles_<k>.dat
+    pub fn from_files(positionpath: &str, prefix: &str, filter_length: usize, fft: &mut FFTManager, blocksize: usize) -> io::Result<Self> {
+        let mut position_buf_reader = BufReader::new(File::open(positionpath)?);
+        let mut sets = BrirSets::new();
+        loop {
+            let x = match position_buf_reader.read_f32::<LittleEndian>() {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let y = position_buf_reader.read_f32::<LittleEndian>()?;
+            let z = position_buf_reader.read_f32::<LittleEndian>()?;
+            let k = sets.get_n_sets();
+            let set = BrirSet::new(
+                &format!("{prefix}_binary_{k}.dat"),
+                &format!("{prefix}_angles_{k}.dat"),
+                filter_length,
+                fft,
+                blocksize,
+            )?;
+            sets.add_set(Point3::new(x, y, z), set);
+        }
+        Ok(sets)
+    }
+
+    pub fn add_set(&mut self, position: Point3<f32>, set: BrirSet) {
+        self.sets.push((position, set));
+    }
+
+    pub fn get_n_sets(&self) -> usize {
+        self.sets.len()
+    }
+
+    pub fn get_set(&self, idx: usize) -> &BrirSet {
+        &self.sets[idx].1
+    }
+
+    // index of the set measured closest to the source position
+    pub fn find_closest_set(&self, position: &Point3<f32>) -> usize {
+        (0..self.sets.len())
+            .min_by(|a, b| {
+                let distance = |idx: &usize| (self.sets[*idx].0 - position).norm_squared();
+                distance(a).total_cmp(&distance(b))
+            })
+            .unwrap()
+    }
+
+    // partitions of the longest filter of all sets
+    pub fn get_n_segments(&self) -> usize {
+        self.sets.iter().map(|(_, set)| set.get_n_segments()).max().unwrap_or(0)
+    }
+}
+
+// yaw and pitch (degrees) of the viewing direction (+z) of the listener
+pub fn head_yaw_pitch(orientation: &Quaternion<f32>) -> (f32, f32) {
+    let forward = UnitQuaternion::from_quaternion(*orientation).transform_vector(&Vector3::z());
+    let yaw = forward[0].atan2(forward[2]);
+    let pitch = forward[1].atan2((forward[0].powi(2) + forward[2].powi(2)).sqrt());
+    (yaw.to_degrees(), pitch.to_degrees())
+}
+
+#[test]
+fn test_brir_set_per_source_position() {
+    use crate::convolver::{test_filter_storage, SpatializerBank, TEST_BUFFER_SIZE};
+
+    let mut fft_manager = FFTManager::new(2 * TEST_BUFFER_SIZE);
+    // one set per position, the sets only differ in gain
+    let mut sets = BrirSets::new();
+    for (position, gain) in [(Point3::origin(), 1.0), (Point3::new(5.0, 0.0, 0.0), 0.5)] {
+        let (storage, tree) = test_filter_storage(&mut fft_manager, BinauralFilterType::LateReverberation, &[(0, gain)]);
+        sets.add_set(position, BrirSet::from_storage(storage, tree));
+    }
+    assert_eq!(sets.find_closest_set(&Point3::new(1.0, 2.0, 0.0)), 0);
+    assert_eq!(sets.find_closest_set(&Point3::new(4.0, 0.0, 0.0)), 1);
+
+    let mut bank = SpatializerBank::with_n_segments(TEST_BUFFER_SIZE, fft_manager, sets.get_n_segments());
+    bank.add_source(0);
+    bank.add_source(1);
+    for (id, position) in [(0, Point3::new(0.5, 0.0, 0.0)), (1, Point3::new(4.5, 0.0, 0.0))] {
+        let set_idx = sets.find_closest_set(&position);
+        let filter_id = sets.get_set(set_idx).find_closest_filter(0.0, 0.0);
+        bank.set_filter_for_direction_in_storage(id, set_idx, filter_id, 0.0, 0.0);
+    }
+    assert_eq!(bank.get_channel(1).unwrap().get_active_storage_idx(), 1);
+
+    // only source 1 plays, it is rendered with the set of its position once the crossfade
+    // from the initial filter is done
+    let mut output = vec![0.0; 2 * TEST_BUFFER_SIZE];
+    for _ in 0..4 {
+        bank.input_mut(0).unwrap().fill(0.0);
+        bank.input_mut(1).unwrap().fill(1.0);
+        bank.process_per_source(&mut output, &|idx| sets.get_set(idx).get_storage(), None);
+    }
+    assert!(output.iter().all(|s| (s - 0.5).abs() < 1e-4));
+}
+
+#[test]
+fn test_brir_sets_missing_file() {
+    use byteorder::WriteBytesExt;
+
+    let mut fft_manager = FFTManager::new(16);
+    let dir = std::env::temp_dir();
+    let positionpath = dir.join("test_brir_sets_missing_file_positions.dat");
+    let mut file = File::create(&positionpath).unwrap();
+    for v in [1.0f32, 0.0, 0.0] {
+        file.write_f32::<LittleEndian>(v).unwrap();
+    }
+    drop(file);
+
+    let prefix = dir.join("test_brir_sets_missing_file");
+    let result = BrirSets::from_files(positionpath.to_str().unwrap(), prefix.to_str().unwrap(), 8, &mut fft_manager, 8);
+    assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+    std::fs::remove_file(positionpath).unwrap();
+}
@@ -1,8 +1,44 @@
 
 use num_traits::Zero;
 use num_complex::Complex;
-use crate::filter::{FilterStorage, FFTManager, BinauralFilterType, MonoFilterType, BinauralFilter, MonoFilter};
-use std::f32::consts::PI;
+use nohash_hasher::NoHashHasher;
+use crate::air_absorption::{AirAbsorptionFilter, Atmosphere};
+use crate::biquad::{BandEqualizer, Biquad, BiquadCoefficients, BiquadType};
+use crate::crossfade::{Crossfade, CrossfadeCurve};
+use crate::delay_line::{propagation_delay, DelayLine, DelayTap, Interpolation};
+use crate::directivity::{DirectivityStorage, MAX_DIRECTIVITY_SEGMENTS};
+use crate::distance::DistanceAttenuation;
+use crate::fdn::FeedbackDelayNetwork;
+use crate::obstacle::Occlusion;
+use crate::reflection_lod::MAX_REFLECTION_PATHS;
+use crate::room_modes::RoomModeBank;
+use crate::sdn::{ScatteringDelayNetwork, N_NODES};
+use crate::transmission::WallTransmission;
+use nalgebra::{Point3, Vector3};
+use crate::filter::{FilterStorage, FFTManager, BinauralFilterType, BinauralFilter, MonoFilter};
+#[cfg(test)]
+use crate::filter::MonoFilterType;
+#[cfg(test)]
+use crate::ray_tracer::LateReverb;
+use crate::simd::{complex_mac, complex_mac_interleaved, SplitComplex};
+use crate::worker_pool::{SharedPtr, WorkerPool};
+use std::{collections::HashMap, hash::BuildHasherDefault, ops::Range, sync::Arc};
+
+// Multiplies the input history (newest block at index) with the filter segments and sums the
+// products into the interleaved output spectrum for the inverse FFT. The history and the filters
+// stay in split-complex form, the last segment is added while writing the output.
+fn convolve_segments(output: &mut [Complex<f32>], acc: &mut SplitComplex, input_f: &[SplitComplex], index: usize, segments: &[SplitComplex]) {
+    let Some((last, segments)) = segments.split_last() else {
+        output.fill(Complex::zero());
+        return;
+    };
+    acc.clear();
+    for (segm, segment) in segments.iter().enumerate() {
+        complex_mac(acc, &input_f[(index + input_f.len() - segm) % input_f.len()], segment);
+    }
+    let last_idx = (index + input_f.len() - segments.len()) % input_f.len();
+    complex_mac_interleaved(output, acc, &input_f[last_idx], last);
+}
 
 #[allow(unused)]
 pub struct Spatializer {
@@ -18,7 +54,12 @@ pub struct Spatializer {
     overlap: Vec<Vec<f32>>,    
     overlap_prev: Vec<Vec<f32>>,    
     input_buf: Vec<f32>,
-    input_f: Vec<Vec<Complex<f32>>>,
+    input_spectrum: Vec<Complex<f32>>,
+    input_f: Vec<SplitComplex>,
+    acc_l: SplitComplex,
+    acc_r: SplitComplex,
+    acc_prev_l: SplitComplex,
+    acc_prev_r: SplitComplex,
     temp_buf_l: Vec<Complex<f32>>,
     temp_buf_r: Vec<Complex<f32>>,
     temp_buf_prev_l: Vec<Complex<f32>>,
@@ -34,9 +75,14 @@ pub struct Spatializer {
     fft_manager: FFTManager,
     
     // filter crossfading 
+    crossfade: Crossfade,
     fade_in: Vec<f32>,
     fade_out: Vec<f32>,
 
+    // source directivity stage (applied before binauralization)
+    directivity: Option<MonoConvolver>,
+    directivity_buf: Vec<f32>,
+
     // risky
     old_slice: Vec<f32>
 }
@@ -45,19 +91,20 @@ impl Spatializer {
 
     // pub fn new (blocksize: usize, fft_manager: FFTManager, filter_storage:  FilterStorage) -> Self {
     pub fn new (blocksize: usize, fft_manager: FFTManager, filter_storage: &FilterStorage) -> Self {
+        let n_segments_ds: usize = filter_storage.get_n_stereo_segments(BinauralFilterType::DirectSound);
+        Spatializer::with_n_segments(blocksize, fft_manager, n_segments_ds)
+    }
+
+    pub fn with_n_segments (blocksize: usize, fft_manager: FFTManager, n_segments_ds: usize) -> Self {
         let n_points = blocksize;
         
-        // init crossfading // sin²
-        let mut fade_in: Vec<f32> = vec![0.0; n_points];
-        let mut fade_out: Vec<f32> = vec![0.0; n_points];
-        for i in 0..n_points {
-            fade_out[i] = (( (PI/2.0) * (i as f32)  / ((2*n_points-1) as f32)).cos()).powf(2.0);
-            fade_in[i] =  (( (PI/2.0) * (i as f32)  / ((2*n_points-1) as f32)).sin()).powf(2.0);
-        }
+        // init crossfading (gains are filled per block)
+        let crossfade = Crossfade::default();
+        let fade_in: Vec<f32> = vec![0.0; n_points];
+        let fade_out: Vec<f32> = vec![0.0; n_points];
         
         // fade_in.reverse();
         // init segmentation values        
-        let n_segments_ds: usize = filter_storage.get_n_stereo_segments(BinauralFilterType::DirectSound);
         let mut n_segments_total: usize = n_segments_ds;
       
         // init temporary buffers
@@ -73,8 +120,10 @@ impl Spatializer {
         let temp_buf_prev_l = vec![Complex::zero(); n_points + 1];
         let temp_buf_prev_r = vec![Complex::zero(); n_points + 1];
         let input_buf = vec![0.0; 2*n_points];
-        let input_f = vec![vec![Complex::zero(); n_points+1]; n_segments_total];
+        let input_spectrum = vec![Complex::zero(); n_points+1];
+        let input_f = vec![SplitComplex::zeros(n_points+1); n_segments_total];
         let old_slice = vec![0.0; n_points];
+        let directivity_buf = vec![0.0; n_points];
 
         Self {
             n_points,
@@ -97,14 +146,75 @@ impl Spatializer {
             fft_manager,
             index: 0,
             input_buf,
+            input_spectrum,
             input_f,
+            crossfade,
+            acc_l: SplitComplex::zeros(n_points+1),
+            acc_r: SplitComplex::zeros(n_points+1),
+            acc_prev_l: SplitComplex::zeros(n_points+1),
+            acc_prev_r: SplitComplex::zeros(n_points+1),
             fade_in,
             fade_out,
+            directivity: None,
+            directivity_buf,
             // riksy
             old_slice,
         }
     }
 
+    // enables the source directivity stage for mono filters with n_segments_sd partitions
+    pub fn enable_directivity(&mut self, n_segments_sd: usize) {
+        self.directivity = Some(MonoConvolver::new(self.n_points, self.fft_manager.clone(), n_segments_sd));
+    }
+
+    pub fn disable_directivity(&mut self) {
+        self.directivity = None;
+    }
+
+    pub fn has_directivity(&self) -> bool {
+        self.directivity.is_some()
+    }
+
+    // curve and length limits of the filter crossfades
+    pub fn set_crossfade(&mut self, crossfade: Crossfade) {
+        self.crossfade = Crossfade::with_settings_of(&crossfade);
+    }
+
+    // starts a crossfade to a new filter, the length depends on the direction jump (degrees).
+    // The previous filter has to be passed to process until is_crossfading returns false.
+    pub fn start_crossfade(&mut self, jump: f32) {
+        self.crossfade.start(jump);
+    }
+
+    pub fn is_crossfading(&self) -> bool {
+        self.crossfade.is_active()
+    }
+
+    pub fn get_crossfade_progress(&self) -> f32 {
+        self.crossfade.get_progress()
+    }
+
+    // filters the input with the (crossfaded) source directivity first and binauralizes the result.
+    // Falls back to plain binauralization when the directivity stage is not enabled.
+    pub fn process_with_directivity(&mut self,
+                    input: &[f32],
+                    output: &mut [f32],
+                    active_ds_filter: &BinauralFilter,
+                    active_sd_filter: &MonoFilter,
+                    prev_ds_filter: &BinauralFilter,
+                    prev_sd_filter: &MonoFilter,
+                ) {
+        let mut directivity_buf = std::mem::take(&mut self.directivity_buf);
+        match self.directivity.as_mut() {
+            Some(directivity) => {
+                directivity.process(input, &mut directivity_buf, active_sd_filter, prev_sd_filter);
+                self.process(&directivity_buf, output, active_ds_filter, prev_ds_filter);
+            },
+            None => self.process(input, output, active_ds_filter, prev_ds_filter),
+        }
+        self.directivity_buf = directivity_buf;
+    }
+
     // process function! full implementation block
     pub fn process(&mut self, 
                     input: &[f32], 
@@ -127,84 +237,35 @@ impl Spatializer {
 
         // FFT stuff )
         self.index = (self.index + 1) % self.n_segments_total;
-        self.fft_manager.transform_to_f_with_scratch(&mut self.input_buf, &mut self.input_f[self.index]);
+        self.fft_manager.transform_to_f_with_scratch(&mut self.input_buf, &mut self.input_spectrum);
+        self.input_f[self.index].copy_from_interleaved(&self.input_spectrum);
         
         // Loop through history of input FTs, multiply with filter FTs, accumulate the result.
         // Side note: Due to the 1/N scaling in the real-FFT, the elements in frequency-transformed segments
         // of the source directivity have to be scaled again by the length of the original transform signal.
-        // first convolve new block
-        let segm = 0;
-        let mut hist_idx = (self.index + self.n_segments_total - segm) % self.n_segments_total;
-        if true { // deactivate active conv when false (debugging)
-        self.temp_buf_l.iter_mut()
-                        .zip(self.input_f[hist_idx]
-                        .iter()
-                        .zip(active_ds_filter.data_f_l[segm].iter()
-                            ))
-                        .for_each(|(c,(a,b))|{*c = a*b; });
-
-        self.temp_buf_r.iter_mut()
-                        .zip(self.input_f[hist_idx]
-                        .iter()
-                        .zip(active_ds_filter.data_f_r[segm].iter()
-                            ))
-                        .for_each(|(c,(a,b))|{*c = a*b;});
-
-        for segm in 1..self.n_segments_ds {
-            let hist_idx = (self.index + self.n_segments_total - segm) % self.n_segments_total;
-            self.temp_buf_l.iter_mut()                        
-                        .zip(self.input_f[hist_idx]
-                        .iter()
-                        .zip(active_ds_filter.data_f_l[segm].iter()
-                            ))
-                        .for_each(|(c,(a,b))|{*c += a*b;});
-            self.temp_buf_r.iter_mut()
-                        .zip(self.input_f[hist_idx]
-                        .iter()
-                        .zip(active_ds_filter.data_f_r[segm].iter()
-                            ))
-                        .for_each(|(c,(a,b))|{*c += a*b;});
-        }
-
-    } // deactivated active convolution
+        let n_segments = self.n_segments_ds.min(active_ds_filter.get_n_segments());
+        convolve_segments(&mut self.temp_buf_l, &mut self.acc_l, &self.input_f, self.index, &active_ds_filter.data_f_l[..n_segments]);
+        convolve_segments(&mut self.temp_buf_r, &mut self.acc_r, &self.input_f, self.index, &active_ds_filter.data_f_r[..n_segments]);
+
         // From here on the previous filters are applied for crossfading..
+        // Identical filters (same storage entry) need no crossfade, so the previous path is skipped.
+        let filter_changed = !std::ptr::eq(active_ds_filter, prev_ds_filter);
+        if filter_changed {
+            // filter switch without start_crossfade: use the shortest crossfade
+            if !self.crossfade.is_active() {
+                self.crossfade.start_default();
+            }
+            self.crossfade.next_block(&mut self.fade_in, &mut self.fade_out);
+        } else {
+            self.crossfade.reset();
+        }
 
         // DS
-        if true {        
-
-            let segm = 0;
-            hist_idx = (self.index + self.n_segments_total - segm) % self.n_segments_total;
-            self.temp_buf_prev_l.iter_mut()
-                        .zip(self.input_f[hist_idx]
-                        .iter()
-                        .zip(prev_ds_filter.data_f_l[segm].iter()
-                            ))
-                        .for_each(|(c,(a,b))|{*c = a*b;});
-            self.temp_buf_prev_r.iter_mut()
-                        .zip(self.input_f[hist_idx]
-                        .iter()
-                        .zip(prev_ds_filter.data_f_r[segm].iter()
-                            ))
-                        .for_each(|(c,(a,b))|{*c = a*b;});
-
-            for segm in 1..self.n_segments_ds {
-                hist_idx = (self.index + self.n_segments_total - segm) % self.n_segments_total;
-                self.temp_buf_prev_l.iter_mut()
-                        .zip(self.input_f[hist_idx]
-                        .iter()
-                        .zip(prev_ds_filter.data_f_l[segm].iter()
-                            ))
-                        .for_each(|(c,(a,b))|{*c += a*b;});
-                self.temp_buf_prev_r.iter_mut()
-                        .zip(self.input_f[hist_idx]
-                        .iter()
-                        .zip(prev_ds_filter.data_f_r[segm].iter()
-                            ))
-                        .for_each(|(c,(a,b))|{*c += a*b;});
-            }
+        if filter_changed {        
+            let n_segments = self.n_segments_ds.min(prev_ds_filter.get_n_segments());
+            convolve_segments(&mut self.temp_buf_prev_l, &mut self.acc_prev_l, &self.input_f, self.index, &prev_ds_filter.data_f_l[..n_segments]);
+            convolve_segments(&mut self.temp_buf_prev_r, &mut self.acc_prev_r, &self.input_f, self.index, &prev_ds_filter.data_f_r[..n_segments]);
         }    
-        
-        // }
 
         // IFFT result, store result and overlap
         self.fft_manager.transform_to_t_with_scratch(
@@ -215,6 +276,15 @@ impl Spatializer {
                 &mut self.temp_buf_r, 
                 &mut self.temp_output_buf_r
             );           
+
+        if !filter_changed {
+            output.chunks_mut(2).enumerate().for_each(|(i, s)| {
+                s[0] += self.temp_output_buf_l[self.n_points + i];
+                s[1] += self.temp_output_buf_r[self.n_points + i];
+            });
+            return;
+        }
+
         self.fft_manager.transform_to_t_with_scratch(
                 &mut self.temp_buf_prev_l,
                 &mut self.temp_output_prev_buf_l
@@ -234,4 +304,1362 @@ impl Spatializer {
                      
     }
            
-}
\ No newline at end of file
+}
+
+// Uniformly partitioned convolver for mono filters (e.g. source directivity).
+// Works like the Spatializer, but writes a single time-domain block, so it can be
+// placed in front of the binaural stage without adding latency.
+#[allow(unused)]
+pub struct MonoConvolver {
+    n_points: usize,
+    n_segments: usize,
+
+    // temporary buffers
+    input_buf: Vec<f32>,
+    input_f: Vec<Vec<Complex<f32>>>,
+    temp_buf: Vec<Complex<f32>>,
+    temp_buf_prev: Vec<Complex<f32>>,
+    temp_output_buf: Vec<f32>,
+    temp_output_prev_buf: Vec<f32>,
+    old_slice: Vec<f32>,
+    index: usize,
+
+    // FFTManager
+    fft_manager: FFTManager,
+
+    // filter crossfading
+    fade_in: Vec<f32>,
+    fade_out: Vec<f32>,
+}
+
+impl MonoConvolver {
+    pub fn new(blocksize: usize, fft_manager: FFTManager, n_segments: usize) -> Self {
+        let n_points = blocksize;
+        let mut fade_in: Vec<f32> = vec![0.0; n_points];
+        let mut fade_out: Vec<f32> = vec![0.0; n_points];
+        CrossfadeCurve::default().fill(&mut fade_in, &mut fade_out);
+        Self {
+            n_points,
+            n_segments,
+            input_buf: vec![0.0; 2*n_points],
+            input_f: vec![vec![Complex::zero(); n_points+1]; n_segments],
+            temp_buf: vec![Complex::zero(); n_points+1],
+            temp_buf_prev: vec![Complex::zero(); n_points+1],
+            temp_output_buf: vec![0.0; 2*n_points],
+            temp_output_prev_buf: vec![0.0; 2*n_points],
+            old_slice: vec![0.0; n_points],
+            index: 0,
+            fft_manager,
+            fade_in,
+            fade_out,
+        }
+    }
+
+    // convolves one block with the active filter and crossfades from the previous one.
+    // Filters with fewer partitions than the convolver are zero padded.
+    // The output is overwritten, not accumulated.
+    pub fn process(&mut self, input: &[f32], output: &mut [f32], active_filter: &MonoFilter, prev_filter: &MonoFilter) {
+        // gather input
+        self.input_buf[0..self.n_points].copy_from_slice(&self.old_slice);
+        self.old_slice.copy_from_slice(input);
+        self.input_buf[self.n_points..].copy_from_slice(input);
+
+        self.index = (self.index + 1) % self.n_segments;
+        self.fft_manager.transform_to_f_with_scratch(&mut self.input_buf, &mut self.input_f[self.index]);
+
+        // identical filters need no crossfade
+        let filter_changed = !std::ptr::eq(active_filter, prev_filter);
+
+        self.temp_buf.iter_mut().for_each(|c| *c = Complex::zero());
+        self.temp_buf_prev.iter_mut().for_each(|c| *c = Complex::zero());
+        for segm in 0..self.n_segments {
+            let hist_idx = (self.index + self.n_segments - segm) % self.n_segments;
+            if let Some(data_f) = active_filter.data_f.get(segm) {
+                self.temp_buf.iter_mut()
+                            .zip(self.input_f[hist_idx].iter()
+                            .zip(data_f.iter()))
+                            .for_each(|(c,(a,b))|{*c += a*b;});
+            }
+            if let Some(data_f) = prev_filter.data_f.get(segm).filter(|_| filter_changed) {
+                self.temp_buf_prev.iter_mut()
+                            .zip(self.input_f[hist_idx].iter()
+                            .zip(data_f.iter()))
+                            .for_each(|(c,(a,b))|{*c += a*b;});
+            }
+        }
+
+        self.fft_manager.transform_to_t_with_scratch(&mut self.temp_buf, &mut self.temp_output_buf);
+        if !filter_changed {
+            output.copy_from_slice(&self.temp_output_buf[self.n_points..]);
+            return;
+        }
+        self.fft_manager.transform_to_t_with_scratch(&mut self.temp_buf_prev, &mut self.temp_output_prev_buf);
+
+        output.iter_mut().enumerate().for_each(|(i, s)| {
+            *s = self.temp_output_buf[self.n_points + i] * self.fade_in[i] + self.temp_output_prev_buf[self.n_points + i] * self.fade_out[i];
+        });
+    }
+
+    pub fn get_n_segments(&self) -> usize {
+        self.n_segments
+    }
+}
+
+// smaller direction changes (degrees) keep the current filter
+pub const FILTER_UPDATE_THRESHOLD: f32 = 0.5;
+
+// great-circle angle between two directions (degrees)
+fn angular_distance(azimuth_a: f32, elevation_a: f32, azimuth_b: f32, elevation_b: f32) -> f32 {
+    let (azimuth_a, elevation_a) = (azimuth_a.to_radians(), elevation_a.to_radians());
+    let (azimuth_b, elevation_b) = (azimuth_b.to_radians(), elevation_b.to_radians());
+    let cos_angle = elevation_a.sin() * elevation_b.sin()
+        + elevation_a.cos() * elevation_b.cos() * (azimuth_a - azimuth_b).cos();
+    cos_angle.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+// Per-source state of the bank. Every source keeps its own spatializer, so the
+// frequency-domain input history (input_f) is never shared between sources.
+#[allow(unused)]
+pub struct SpatializerChannel {
+    id: u32,
+    spatializer: Spatializer,
+    input: Vec<f32>,
+    active_filter_id: usize,
+    prev_filter_id: usize,
+    // storages of the active and the previous filter (see process_per_source)
+    active_storage_idx: usize,
+    prev_storage_idx: usize,
+    // (storage, filter, direction jump) waiting for the running crossfade
+    pending_filter: Option<(usize, usize, f32)>,
+    active_sd_filter_id: usize,
+    prev_sd_filter_id: usize,
+    // last filter direction (azimuth, elevation in degrees)
+    direction: Option<(f32, f32)>,
+    // directivity of the source if it differs from the one passed to process
+    directivity: Option<Arc<DirectivityStorage>>,
+    // direction in which a reflection leaves the source (world frame), None for the direction
+    // towards the apparent position
+    emission: Option<Vector3<f32>>,
+    // distance gain, ramped from gain to target_gain over one block
+    distance_attenuation: DistanceAttenuation,
+    distance: Option<f32>,
+    gain: f32,
+    target_gain: f32,
+    // propagation delay of the path, only applied once the distance is known
+    delay_line: DelayLine,
+    delay_tap: DelayTap,
+    // tap of the previous distance, faded out during the next block (without Doppler)
+    prev_delay_tap: Option<DelayTap>,
+    // delay changes are ramped (pitch shift) instead of crossfaded
+    doppler: bool,
+    // blocks since the last distance update, used as interpolation time for the delay
+    blocks_since_update: usize,
+    // high-frequency loss of the direct path
+    air_absorption: AirAbsorptionFilter,
+    // obstacles between source and listener
+    occlusion_gain: f32,
+    occlusion_filter: Biquad,
+    // source outside the room, heard through a wall from the transmission point. The
+    // equalizer is allocated with the channel and only runs while there is a transmission.
+    transmission: Option<Point3<f32>>,
+    transmission_equalizer: BandEqualizer,
+    // virtual source (e.g. a diffraction path), takes its input from the parent source
+    // unless the input is written by the parent's room model
+    parent: Option<u32>,
+    external_input: bool,
+    // position the filters are chosen for, if it is not the source position (room model nodes)
+    apparent_position: Option<Point3<f32>>,
+    // room model of the source, renders into the node virtual sources
+    room: Option<Box<ScatteringDelayNetwork>>,
+    // low-frequency room modes, excited by the unattenuated input and mixed to both ears
+    modes: Option<Box<RoomModeBank>>,
+    // late reverberation of the room of the source if the listener is in a neighbouring room
+    coupled_reverb: Option<Box<FeedbackDelayNetwork>>,
+    // late reverberation of the room of the listener, if the source is in the same room
+    room_reverb: Option<Box<FeedbackDelayNetwork>>,
+    // looped signal of the source and the read position, written to the input by read_source_signals
+    signal: Option<(Arc<[f32]>, usize)>,
+}
+
+impl SpatializerChannel {
+    // renders the source into the (interleaved stereo) bus and accepts the active filters
+    fn render<'a>(&mut self, bus: &mut [f32], filter_storage: &dyn Fn(usize) -> &'a FilterStorage, directivity_storage: Option<&DirectivityStorage>) {
+        if let Some(modes) = self.modes.as_mut() {
+            modes.process(&self.input, bus);
+        }
+        if let Some(coupled_reverb) = self.coupled_reverb.as_mut() {
+            coupled_reverb.process(&self.input, bus);
+        }
+        if let Some(room_reverb) = self.room_reverb.as_mut() {
+            room_reverb.process(&self.input, bus);
+        }
+        self.apply_gain();
+        if self.distance.is_some() {
+            match self.prev_delay_tap.take() {
+                Some(mut prev_delay_tap) => {
+                    self.delay_line.process_crossfade_in_place(&mut self.input, &mut prev_delay_tap, &mut self.delay_tap)
+                }
+                None => self.delay_line.process_in_place(&mut self.input, &mut self.delay_tap),
+            }
+        }
+        self.air_absorption.process(&mut self.input);
+        self.occlusion_filter.process(&mut self.input);
+        if self.transmission.is_some() {
+            self.transmission_equalizer.process(&mut self.input);
+        }
+        self.blocks_since_update += 1;
+        let active_filter = filter_storage(self.active_storage_idx).get_binaural_filter(BinauralFilterType::DirectSound, self.active_filter_id);
+        let prev_filter = filter_storage(self.prev_storage_idx).get_binaural_filter(BinauralFilterType::DirectSound, self.prev_filter_id);
+        // omnidirectional sources skip the directivity stage
+        let directivity_storage = self.directivity.as_deref().or(directivity_storage).filter(|d| !d.is_omnidirectional());
+        match directivity_storage {
+            Some(directivity_storage) if self.spatializer.has_directivity() => {
+                let active_sd_filter = directivity_storage.get_mono_filter(self.active_sd_filter_id);
+                let prev_sd_filter = directivity_storage.get_mono_filter(self.prev_sd_filter_id);
+                self.spatializer.process_with_directivity(&self.input, bus, active_filter, active_sd_filter, prev_filter, prev_sd_filter);
+            },
+            _ => self.spatializer.process(&self.input, bus, active_filter, prev_filter),
+        }
+        // crossfade done, keep the active filters for the next block and start the one that
+        // arrived in between
+        if !self.spatializer.is_crossfading() {
+            self.prev_filter_id = self.active_filter_id;
+            self.prev_storage_idx = self.active_storage_idx;
+            if let Some((storage_idx, filter_id, jump)) = self.pending_filter.take() {
+                self.switch_filter(storage_idx, filter_id, jump);
+            }
+        }
+        self.prev_sd_filter_id = self.active_sd_filter_id;
+    }
+
+    fn apply_gain(&mut self) {
+        let n_points = self.input.len() as f32;
+        let step = (self.target_gain - self.gain) / n_points;
+        for (i, s) in self.input.iter_mut().enumerate() {
+            *s *= self.gain + step * (i + 1) as f32;
+        }
+        self.gain = self.target_gain;
+    }
+
+    pub fn get_gain(&self) -> f32 {
+        self.gain
+    }
+
+    fn update_target_gain(&mut self) {
+        let distance_gain = self.distance.map_or(1.0, |d| self.distance_attenuation.gain(d));
+        self.target_gain = distance_gain * self.occlusion_gain;
+    }
+
+    // a filter that arrives during a crossfade waits for its end, only the latest one is kept
+    fn switch_filter(&mut self, storage_idx: usize, filter_id: usize, jump: f32) {
+        if self.spatializer.is_crossfading() {
+            self.pending_filter = ((storage_idx, filter_id) != (self.active_storage_idx, self.active_filter_id))
+                .then_some((storage_idx, filter_id, jump));
+            return;
+        }
+        if (storage_idx, filter_id) == (self.active_storage_idx, self.active_filter_id) {
+            return;
+        }
+        self.prev_filter_id = self.active_filter_id;
+        self.prev_storage_idx = self.active_storage_idx;
+        self.active_filter_id = filter_id;
+        self.active_storage_idx = storage_idx;
+        self.spatializer.start_crossfade(jump);
+    }
+
+    pub fn get_active_filter_id(&self) -> usize {
+        self.active_filter_id
+    }
+
+    pub fn get_active_sd_filter_id(&self) -> usize {
+        self.active_sd_filter_id
+    }
+
+    pub fn get_prev_filter_id(&self) -> usize {
+        self.prev_filter_id
+    }
+
+    pub fn get_active_storage_idx(&self) -> usize {
+        self.active_storage_idx
+    }
+
+    pub fn get_parent(&self) -> Option<u32> {
+        self.parent
+    }
+}
+
+// longest propagation path the delay lines can hold (m)
+const MAX_PROPAGATION_DISTANCE: f32 = 200.0;
+
+// ids of virtual sources: flag bit, parent id (23 bit) and path index (8 bit). The ids of
+// sources are below MAX_SOURCE_ID, the scene handler rejects others.
+const VIRTUAL_SOURCE_FLAG: u32 = 1 << 31;
+pub const MAX_SOURCE_ID: u32 = 1 << 23;
+pub const MAX_VIRTUAL_SOURCES: usize = 256;
+
+// index ranges of the virtual sources of one parent
+pub const DIFFRACTION_SOURCES: Range<usize> = 0..16;
+pub const ROOM_NODE_SOURCES: Range<usize> = 16..16 + N_NODES;
+pub const REFLECTION_SOURCES: Range<usize> = 16 + N_NODES..16 + N_NODES + MAX_REFLECTION_PATHS;
+
+pub fn virtual_source_id(parent: u32, idx: usize) -> u32 {
+    // masked instead of checked, this runs on the audio thread
+    VIRTUAL_SOURCE_FLAG | (parent % MAX_SOURCE_ID) << 8 | (idx % MAX_VIRTUAL_SOURCES) as u32
+}
+
+fn propagation_delay_line(speed_of_sound: f32, sample_rate: f32) -> DelayLine {
+    let max_delay = propagation_delay(MAX_PROPAGATION_DISTANCE, speed_of_sound, sample_rate);
+    DelayLine::new(max_delay.ceil() as usize, Interpolation::default())
+}
+
+// sources and virtual sources the bank holds without reallocation
+pub const MAX_CHANNELS: usize = 1024;
+
+// Builds the channels of a SpatializerBank with the settings of the bank. The audio thread
+// does not allocate: the scene handler keeps a copy of the factory and sends new channels
+// along with the parameters that add sources.
+#[derive(Clone)]
+pub struct ChannelFactory {
+    n_points: usize,
+    n_segments_ds: usize,
+    n_segments_sd: Option<usize>,
+    fft_manager: FFTManager,
+    crossfade: Crossfade,
+    doppler: bool,
+    sample_rate: f32,
+    speed_of_sound: f32,
+}
+
+impl ChannelFactory {
+    pub fn new_channel(&self) -> Box<SpatializerChannel> {
+        let mut spatializer = Spatializer::with_n_segments(self.n_points, self.fft_manager.clone(), self.n_segments_ds);
+        spatializer.set_crossfade(self.crossfade);
+        if let Some(n_segments_sd) = self.n_segments_sd {
+            spatializer.enable_directivity(n_segments_sd);
+        }
+        Box::new(SpatializerChannel {
+            id: 0,
+            spatializer,
+            input: vec![0.0; self.n_points],
+            active_filter_id: 0,
+            prev_filter_id: 0,
+            active_storage_idx: 0,
+            prev_storage_idx: 0,
+            pending_filter: None,
+            active_sd_filter_id: 0,
+            prev_sd_filter_id: 0,
+            direction: None,
+            directivity: None,
+            emission: None,
+            distance_attenuation: DistanceAttenuation::default(),
+            distance: None,
+            gain: 1.0,
+            target_gain: 1.0,
+            delay_line: propagation_delay_line(self.speed_of_sound, self.sample_rate),
+            delay_tap: DelayTap::default(),
+            prev_delay_tap: None,
+            doppler: self.doppler,
+            blocks_since_update: 0,
+            air_absorption: AirAbsorptionFilter::default(),
+            occlusion_gain: 1.0,
+            occlusion_filter: Biquad::default(),
+            transmission: None,
+            transmission_equalizer: BandEqualizer::octave_bands(self.sample_rate),
+            parent: None,
+            external_input: false,
+            apparent_position: None,
+            room: None,
+            modes: None,
+            coupled_reverb: None,
+            room_reverb: None,
+            signal: None,
+        })
+    }
+
+    pub fn new_channels(&self, n: usize) -> Vec<Box<SpatializerChannel>> {
+        (0..n).map(|_| self.new_channel()).collect()
+    }
+
+    pub fn get_sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    // the delay lines of new channels hold MAX_PROPAGATION_DISTANCE at this speed of sound
+    pub fn set_atmosphere(&mut self, atmosphere: Atmosphere) {
+        self.speed_of_sound = atmosphere.speed_of_sound();
+    }
+}
+
+// Holds one Spatializer per sound source, keyed by the stable source id
+// (Source_transform.id), and mixes all of them into a single output bus.
+#[allow(unused)]
+pub struct SpatializerBank {
+    n_points: usize,
+    n_segments_ds: usize,
+    n_segments_sd: Option<usize>,
+    fft_manager: FFTManager,
+    channels: HashMap<u32, Box<SpatializerChannel>, BuildHasherDefault<NoHashHasher<u32>>>,
+    crossfade: Crossfade,
+
+    // propagation settings (delay, air absorption)
+    doppler: bool,
+    sample_rate: f32,
+    atmosphere: Atmosphere,
+    speed_of_sound: f32,
+
+    // rendering order (sorted source ids), keeps the multi-threaded mix deterministic
+    order: Vec<u32>,
+    channel_ptrs: Vec<SharedPtr<SpatializerChannel>>,
+    // node outputs of the room model of the source that is rendered
+    room_outputs: Vec<Vec<f32>>,
+    // removed channels, dropped by the owner of the bank (see take_removed). Boxed like in
+    // channels, handing them on moves a pointer instead of the channel.
+    #[allow(clippy::vec_box)]
+    removed: Vec<Box<SpatializerChannel>>,
+
+    // optional multi-threaded rendering, one bus per pool thread
+    worker_pool: Option<WorkerPool>,
+    thread_buses: Vec<Vec<f32>>,
+}
+
+impl SpatializerBank {
+    pub fn new(blocksize: usize, fft_manager: FFTManager, filter_storage: &FilterStorage) -> Self {
+        let n_segments_ds = filter_storage.get_n_stereo_segments(BinauralFilterType::DirectSound);
+        SpatializerBank::with_n_segments(blocksize, fft_manager, n_segments_ds)
+    }
+
+    // n_segments_ds: maximum number of partitions of the filters passed to process
+    pub fn with_n_segments(blocksize: usize, fft_manager: FFTManager, n_segments_ds: usize) -> Self {
+        Self {
+            n_points: blocksize,
+            n_segments_ds,
+            n_segments_sd: None,
+            fft_manager,
+            channels: HashMap::with_capacity_and_hasher(MAX_CHANNELS, BuildHasherDefault::default()),
+            crossfade: Crossfade::default(),
+            doppler: false,
+            sample_rate: 48000.0,
+            atmosphere: Atmosphere::default(),
+            speed_of_sound: Atmosphere::default().speed_of_sound(),
+            order: Vec::with_capacity(MAX_CHANNELS),
+            channel_ptrs: Vec::with_capacity(MAX_CHANNELS),
+            room_outputs: vec![vec![0.0; blocksize]; N_NODES],
+            removed: Vec::with_capacity(MAX_CHANNELS),
+            worker_pool: None,
+            thread_buses: Vec::new(),
+        }
+    }
+
+    // spreads the sources over the threads of the pool. Without a pool, everything
+    // is rendered on the calling thread.
+    pub fn set_worker_pool(&mut self, worker_pool: WorkerPool) {
+        self.thread_buses = vec![vec![0.0; 2*self.n_points]; worker_pool.get_n_threads()];
+        self.worker_pool = Some(worker_pool);
+    }
+
+    pub fn remove_worker_pool(&mut self) -> Option<WorkerPool> {
+        self.thread_buses.clear();
+        self.worker_pool.take()
+    }
+
+    // enables the source directivity stage for all current and future sources. The stage
+    // holds MAX_DIRECTIVITY_SEGMENTS partitions, so sources can switch directivities without
+    // reallocation. It only runs for sources with a directivity that is not omnidirectional.
+    pub fn enable_directivity(&mut self) {
+        let n_segments_sd = MAX_DIRECTIVITY_SEGMENTS;
+        self.n_segments_sd = Some(n_segments_sd);
+        for channel in self.channels.values_mut() {
+            channel.spatializer.enable_directivity(n_segments_sd);
+        }
+    }
+
+    // crossfade curve and length limits for all current and future sources
+    pub fn set_crossfade(&mut self, crossfade: Crossfade) {
+        self.crossfade = crossfade;
+        for channel in self.channels.values_mut() {
+            channel.spatializer.set_crossfade(crossfade);
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        for channel in self.channels.values_mut() {
+            channel.delay_line = propagation_delay_line(self.speed_of_sound, sample_rate);
+            let mut transmission_equalizer = BandEqualizer::octave_bands(sample_rate);
+            transmission_equalizer.set_gains(channel.transmission_equalizer.get_gains());
+            transmission_equalizer.reset();
+            channel.transmission_equalizer = transmission_equalizer;
+            if let Some(distance) = channel.distance {
+                channel.delay_tap = DelayTap::new(propagation_delay(distance, self.speed_of_sound, sample_rate));
+                channel.prev_delay_tap = None;
+                channel.air_absorption.set_distance(&self.atmosphere, distance, sample_rate);
+            }
+        }
+    }
+
+    // atmospheric conditions for air absorption and the speed of sound of the propagation delays
+    pub fn set_atmosphere(&mut self, atmosphere: Atmosphere) {
+        self.atmosphere = atmosphere;
+        self.speed_of_sound = atmosphere.speed_of_sound();
+        for channel in self.channels.values_mut() {
+            if let Some(distance) = channel.distance {
+                channel.air_absorption.set_distance(&atmosphere, distance, self.sample_rate);
+            }
+        }
+    }
+
+    pub fn get_atmosphere(&self) -> Atmosphere {
+        self.atmosphere
+    }
+
+    // Doppler for sources added later on, disabled unless set
+    pub fn set_doppler_default(&mut self, enabled: bool) {
+        self.doppler = enabled;
+    }
+
+    // The propagation delay is always rendered. With Doppler, the delay is ramped between two
+    // distance updates, so moving sources change their pitch. Without it, the tap of the new
+    // delay is crossfaded in within one block. Also applies to the virtual sources of the source.
+    pub fn set_doppler(&mut self, id: u32, enabled: bool) {
+        for channel in self.channels.values_mut().filter(|c| c.id == id || c.parent == Some(id)) {
+            channel.doppler = enabled;
+        }
+    }
+
+    pub fn has_doppler(&self, id: u32) -> bool {
+        self.channels.get(&id).is_some_and(|c| c.doppler)
+    }
+
+    // current propagation delay (samples), None before the first distance update
+    pub fn get_propagation_delay(&self, id: u32) -> Option<f32> {
+        let channel = self.channels.get(&id)?;
+        channel.distance.map(|_| channel.delay_tap.get_delay())
+    }
+
+    // settings of the channels of this bank, see ChannelFactory
+    pub fn get_channel_factory(&self) -> ChannelFactory {
+        ChannelFactory {
+            n_points: self.n_points,
+            n_segments_ds: self.n_segments_ds,
+            n_segments_sd: self.n_segments_sd,
+            fft_manager: self.fft_manager.clone(),
+            crossfade: self.crossfade,
+            doppler: self.doppler,
+            sample_rate: self.sample_rate,
+            speed_of_sound: self.speed_of_sound,
+        }
+    }
+
+    // adds a source with a channel of the ChannelFactory of the bank, the channel is handed
+    // back if the id is taken. Up to MAX_CHANNELS channels, this does not allocate.
+    pub fn add_channel(&mut self, id: u32, mut channel: Box<SpatializerChannel>) -> Option<Box<SpatializerChannel>> {
+        if self.channels.contains_key(&id) {
+            return Some(channel);
+        }
+        channel.id = id;
+        self.channels.insert(id, channel);
+        let pos = self.order.binary_search(&id).unwrap_or_else(|pos| pos);
+        self.order.insert(pos, id);
+        self.channel_ptrs.reserve(self.order.len());
+        None
+    }
+
+    // allocates the channel, the audio thread uses add_channel
+    pub fn add_source(&mut self, id: u32) {
+        if !self.channels.contains_key(&id) {
+            self.add_channel(id, self.get_channel_factory().new_channel());
+        }
+    }
+
+    fn remove_channel(&mut self, id: u32) -> bool {
+        match self.channels.remove(&id) {
+            Some(channel) => {
+                self.order.retain(|i| *i != id);
+                self.removed.push(channel);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // removes the source and its virtual sources
+    pub fn remove_source(&mut self, id: u32) -> bool {
+        self.set_virtual_sources(id, 0..MAX_VIRTUAL_SOURCES, 0, &mut Vec::new());
+        self.remove_channel(id)
+    }
+
+    // channels removed since the last call. The bank does not drop them, so the audio thread
+    // can hand them to another thread.
+    pub fn take_removed(&mut self) -> std::vec::Drain<'_, Box<SpatializerChannel>> {
+        self.removed.drain(..)
+    }
+
+    // adds new sources and removes the ones that are not part of the scene anymore. New
+    // sources take their channel from channels (see ChannelFactory), without channels left
+    // they are not added.
+    pub fn sync_sources(&mut self, ids: &[u32], channels: &mut Vec<Box<SpatializerChannel>>) {
+        for idx in (0..self.order.len()).rev() {
+            let id = self.order[idx];
+            if !ids.contains(&self.channels[&id].parent.unwrap_or(id)) {
+                self.order.remove(idx);
+                self.removed.push(self.channels.remove(&id).unwrap());
+            }
+        }
+        for &id in ids {
+            if self.channels.contains_key(&id) {
+                continue;
+            }
+            match channels.pop() {
+                Some(channel) => self.add_channel(id, channel),
+                None => return,
+            };
+        }
+    }
+
+    // keeps n virtual sources of a parent source in the given index range (ids
+    // virtual_source_id(parent, group.start..group.start + n)), existing ones keep their state.
+    // New virtual sources take their channel from channels, like in sync_sources.
+    pub fn set_virtual_sources(&mut self, parent: u32, group: Range<usize>, n: usize, channels: &mut Vec<Box<SpatializerChannel>>) {
+        let n = n.min(group.len());
+        for idx in group.start + n..group.end {
+            self.remove_channel(virtual_source_id(parent, idx));
+        }
+        if !self.channels.contains_key(&parent) {
+            return;
+        }
+        let distance_attenuation = self.channels[&parent].distance_attenuation.clone();
+        let directivity = self.channels[&parent].directivity.clone();
+        let doppler = self.channels[&parent].doppler;
+        for idx in group.start..group.start + n {
+            let id = virtual_source_id(parent, idx);
+            if self.channels.contains_key(&id) {
+                continue;
+            }
+            let Some(channel) = channels.pop() else {
+                return;
+            };
+            self.add_channel(id, channel);
+            let channel = self.channels.get_mut(&id).unwrap();
+            channel.parent = Some(parent);
+            channel.distance_attenuation = distance_attenuation.clone();
+            channel.directivity = directivity.clone();
+            channel.doppler = doppler;
+        }
+    }
+
+    pub fn virtual_source_ids(&self, parent: u32) -> impl Iterator<Item = u32> + '_ {
+        self.order
+            .iter()
+            .copied()
+            .filter(move |id| self.channels[id].parent == Some(parent))
+    }
+
+    pub fn contains_source(&self, id: u32) -> bool {
+        self.channels.contains_key(&id)
+    }
+
+    pub fn get_n_sources(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn get_channel(&self, id: u32) -> Option<&SpatializerChannel> {
+        self.channels.get(&id).map(|channel| &**channel)
+    }
+
+    pub fn source_ids(&self) -> impl Iterator<Item = &u32> {
+        self.channels.keys()
+    }
+
+    // input block of a source for the next call to process
+    pub fn input_mut(&mut self, id: u32) -> Option<&mut [f32]> {
+        self.channels.get_mut(&id).map(|c| &mut c.input[..])
+    }
+
+    // signal played (looped) by a source, None silences the source. Returns the previous
+    // signal (the given one without such source), so it is not dropped on the audio thread.
+    pub fn set_source_signal(&mut self, id: u32, signal: Option<Arc<[f32]>>) -> Option<Arc<[f32]>> {
+        match self.channels.get_mut(&id) {
+            Some(channel) => {
+                channel.input.fill(0.0);
+                let prev = std::mem::replace(&mut channel.signal, signal.map(|signal| (signal, 0)));
+                prev.map(|(signal, _)| signal)
+            }
+            None => signal,
+        }
+    }
+
+    // writes the next block of the source signals to the inputs of their sources
+    pub fn read_source_signals(&mut self) {
+        for channel in self.channels.values_mut() {
+            let Some((signal, position)) = channel.signal.as_mut().filter(|(signal, _)| !signal.is_empty()) else {
+                continue;
+            };
+            for sample in channel.input.iter_mut() {
+                *sample = signal[*position];
+                *position = (*position + 1) % signal.len();
+            }
+        }
+    }
+
+    // switches to a new filter of the active storage with the shortest crossfade
+    pub fn set_filter(&mut self, id: u32, filter_id: usize) {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            channel.switch_filter(channel.active_storage_idx, filter_id, 0.0);
+        }
+    }
+
+    // switches to the filter of a new direction (degrees). The crossfade length adapts to
+    // the angle between the old and the new direction.
+    // false if the filter was chosen for a direction closer than FILTER_UPDATE_THRESHOLD, the
+    // filter lookup can be skipped
+    pub fn needs_filter_update(&self, id: u32, azimuth: f32, elevation: f32) -> bool {
+        match self.channels.get(&id).and_then(|channel| channel.direction) {
+            Some((prev_azimuth, prev_elevation)) => {
+                angular_distance(prev_azimuth, prev_elevation, azimuth, elevation) >= FILTER_UPDATE_THRESHOLD
+            }
+            None => true,
+        }
+    }
+
+    pub fn set_filter_for_direction(&mut self, id: u32, filter_id: usize, azimuth: f32, elevation: f32) {
+        self.set_filter_for_direction_in_storage(id, 0, filter_id, azimuth, elevation);
+    }
+
+    // like set_filter_for_direction, the filter is taken from storage storage_idx of process_per_source
+    pub fn set_filter_for_direction_in_storage(&mut self, id: u32, storage_idx: usize, filter_id: usize, azimuth: f32, elevation: f32) {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            let jump = match channel.direction {
+                Some((prev_azimuth, prev_elevation)) => angular_distance(prev_azimuth, prev_elevation, azimuth, elevation),
+                None => 0.0,
+            };
+            channel.direction = Some((azimuth, elevation));
+            channel.switch_filter(storage_idx, filter_id, jump);
+        }
+    }
+
+    // also applies to the virtual sources of the source. Returns the previous attenuation of
+    // the source (the given one without such source), like set_source_signal.
+    pub fn set_distance_attenuation(&mut self, id: u32, distance_attenuation: DistanceAttenuation) -> DistanceAttenuation {
+        for channel in self.channels.values_mut().filter(|c| c.parent == Some(id)) {
+            channel.distance_attenuation = distance_attenuation.clone();
+            channel.update_target_gain();
+        }
+        match self.channels.get_mut(&id) {
+            Some(channel) => {
+                let prev = std::mem::replace(&mut channel.distance_attenuation, distance_attenuation);
+                channel.update_target_gain();
+                prev
+            }
+            None => distance_attenuation,
+        }
+    }
+
+    // distance between source and listener (m), the gain is ramped in during the next block.
+    // With Doppler, the propagation delay moves to the new distance over the time since the
+    // previous update, so the motion between two scene updates is interpolated.
+    pub fn set_distance(&mut self, id: u32, distance: f32) {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            let delay = propagation_delay(distance, self.speed_of_sound, self.sample_rate);
+            match (channel.distance, channel.doppler) {
+                (Some(_), true) => channel.delay_tap.set_delay(delay, channel.blocks_since_update.max(1) * self.n_points),
+                (Some(_), false) if delay != channel.delay_tap.get_target_delay() => {
+                    // a tap that is still fading out keeps fading out
+                    channel.prev_delay_tap.get_or_insert(channel.delay_tap);
+                    channel.delay_tap = DelayTap::new(delay);
+                }
+                (Some(_), false) => {}
+                (None, _) => channel.delay_tap = DelayTap::new(delay),
+            }
+            channel.air_absorption.set_distance(&self.atmosphere, distance, self.sample_rate);
+            channel.blocks_since_update = 0;
+            let first_update = channel.distance.is_none();
+            channel.distance = Some(distance);
+            channel.update_target_gain();
+            // a new path starts at its gain instead of fading in from unity
+            if first_update {
+                channel.gain = channel.target_gain;
+            }
+        }
+    }
+
+    // attenuation and low-pass of the obstacles on the direct path, faded in over one block
+    pub fn set_occlusion(&mut self, id: u32, occlusion: &Occlusion) {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            let coefficients = if occlusion.get_cutoff() < 0.45 * self.sample_rate {
+                BiquadCoefficients::design(BiquadType::LowPass, occlusion.get_cutoff(), std::f32::consts::FRAC_1_SQRT_2, self.sample_rate)
+            } else {
+                BiquadCoefficients::default()
+            };
+            if coefficients != channel.occlusion_filter.get_coefficients() {
+                channel.occlusion_filter.set_coefficients(coefficients, self.n_points);
+            }
+            channel.occlusion_gain = occlusion.gain();
+            channel.update_target_gain();
+        }
+    }
+
+    // directivity filter id, looked up from the emission angle of the source
+    pub fn set_directivity_filter(&mut self, id: u32, filter_id: usize) {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            channel.prev_sd_filter_id = channel.active_sd_filter_id;
+            channel.active_sd_filter_id = filter_id;
+        }
+    }
+
+    // directivity of a source and its virtual sources, None for the storage passed to process.
+    // Filters longer than the directivity stage (MAX_DIRECTIVITY_SEGMENTS) are truncated.
+    // Sources with an omnidirectional storage are rendered without directivity stage. Returns
+    // the previous directivity of the source, like set_source_signal.
+    pub fn set_source_directivity(&mut self, id: u32, directivity: Option<Arc<DirectivityStorage>>) -> Option<Arc<DirectivityStorage>> {
+        for channel in self.channels.values_mut().filter(|c| c.id == id || c.parent == Some(id)) {
+            // the filter ids of the previous storage are invalid, the next scene update looks
+            // up the filters of the new one
+            channel.active_sd_filter_id = 0;
+            channel.prev_sd_filter_id = 0;
+            if channel.id != id {
+                channel.directivity = directivity.clone();
+            }
+        }
+        match self.channels.get_mut(&id) {
+            Some(channel) => std::mem::replace(&mut channel.directivity, directivity),
+            None => directivity,
+        }
+    }
+
+    // None for sources without directivity stage
+    pub fn get_source_directivity(&self, id: u32) -> Option<&DirectivityStorage> {
+        self.channels.get(&id)?.directivity.as_deref().filter(|d| !d.is_omnidirectional())
+    }
+
+    // direction in which a virtual source (reflection) leaves its parent, world frame
+    pub fn set_emission_direction(&mut self, id: u32, emission: Option<Vector3<f32>>) {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            channel.emission = emission;
+        }
+    }
+
+    pub fn get_emission_direction(&self, id: u32) -> Option<Vector3<f32>> {
+        self.channels.get(&id)?.emission
+    }
+
+    // wall transmission of a source outside the room, None once it is back inside
+    pub fn set_transmission(&mut self, id: u32, transmission: Option<&WallTransmission>) {
+        if let Some(channel) = self.channels.get_mut(&id) {
+            match transmission {
+                Some(transmission) => {
+                    channel.transmission_equalizer.set_gains(transmission.get_band_gains());
+                    // no fade in from the gains of an earlier transmission
+                    if channel.transmission.is_none() {
+                        channel.transmission_equalizer.reset();
+                    }
+                    channel.transmission = Some(transmission.get_point());
+                }
+                None => channel.transmission = None,
+            }
+        }
+    }
+
+    // point on the wall a source outside the room is heard from
+    pub fn get_transmission_point(&self, id: u32) -> Option<Point3<f32>> {
+        self.channels.get(&id)?.transmission
+    }
+
+    // position the source is heard from: the transmission point, the node of a room model,
+    // None for the source position itself
+    pub fn get_apparent_position(&self, id: u32) -> Option<Point3<f32>> {
+        let channel = self.channels.get(&id)?;
+        self.get_transmission_point(id).or(channel.apparent_position)
+    }
+
+    // Scattering Delay Network for the reflections of a source, one virtual source per node
+    // (ROOM_NODE_SOURCES, channels as in set_virtual_sources). None removes the room model.
+    // Returns the previous network, like set_source_signal.
+    pub fn set_room_model(
+        &mut self,
+        id: u32,
+        room: Option<Box<ScatteringDelayNetwork>>,
+        channels: &mut Vec<Box<SpatializerChannel>>,
+    ) -> Option<Box<ScatteringDelayNetwork>> {
+        if !self.channels.contains_key(&id) {
+            return room;
+        }
+        let n_nodes = if room.is_some() { N_NODES } else { 0 };
+        self.set_virtual_sources(id, ROOM_NODE_SOURCES, n_nodes, channels);
+        for idx in ROOM_NODE_SOURCES.take(n_nodes) {
+            if let Some(node) = self.channels.get_mut(&virtual_source_id(id, idx)) {
+                node.external_input = true;
+            }
+        }
+        let prev = std::mem::replace(&mut self.channels.get_mut(&id).unwrap().room, room);
+        self.update_room_nodes(id);
+        prev
+    }
+
+    pub fn get_room_model(&self, id: u32) -> Option<&ScatteringDelayNetwork> {
+        self.channels.get(&id)?.room.as_deref()
+    }
+
+    // moves the nodes of the room model, the delays follow over one block
+    pub fn set_room_positions(&mut self, id: u32, source: &Point3<f32>, listener: &Point3<f32>) {
+        let n_points = self.n_points;
+        if let Some(room) = self.channels.get_mut(&id).and_then(|c| c.room.as_mut()) {
+            room.set_positions(source, listener, n_points);
+            self.update_room_nodes(id);
+        }
+    }
+
+    // analytic room modes of a shoebox room for the source, None removes them. Returns the
+    // previous modes, like set_source_signal.
+    pub fn set_room_modes(&mut self, id: u32, modes: Option<Box<RoomModeBank>>) -> Option<Box<RoomModeBank>> {
+        match self.channels.get_mut(&id) {
+            Some(channel) => std::mem::replace(&mut channel.modes, modes),
+            None => modes,
+        }
+    }
+
+    pub fn get_room_modes(&self, id: u32) -> Option<&RoomModeBank> {
+        self.channels.get(&id)?.modes.as_deref()
+    }
+
+    // mode gains follow over one block
+    pub fn set_room_mode_positions(&mut self, id: u32, source: &Point3<f32>, listener: &Point3<f32>) {
+        if let Some(modes) = self.channels.get_mut(&id).and_then(|c| c.modes.as_mut()) {
+            modes.set_positions(source, listener);
+        }
+    }
+
+    // late reverberation of a coupled room, None removes it. Returns the previous network,
+    // like set_source_signal.
+    pub fn set_coupled_reverb(&mut self, id: u32, coupled_reverb: Option<Box<FeedbackDelayNetwork>>) -> Option<Box<FeedbackDelayNetwork>> {
+        match self.channels.get_mut(&id) {
+            Some(channel) => std::mem::replace(&mut channel.coupled_reverb, coupled_reverb),
+            None => coupled_reverb,
+        }
+    }
+
+    pub fn get_coupled_reverb(&self, id: u32) -> Option<&FeedbackDelayNetwork> {
+        self.channels.get(&id)?.coupled_reverb.as_deref()
+    }
+
+    // late reverberation of the room of the listener, None removes it. A network replacing
+    // another one continues its tail. Returns the previous network, like set_source_signal.
+    pub fn set_room_reverb(&mut self, id: u32, mut room_reverb: Option<Box<FeedbackDelayNetwork>>) -> Option<Box<FeedbackDelayNetwork>> {
+        let Some(channel) = self.channels.get_mut(&id) else {
+            return room_reverb;
+        };
+        if let (Some(new), Some(previous)) = (room_reverb.as_mut(), channel.room_reverb.as_mut()) {
+            new.take_tail(previous);
+        }
+        std::mem::replace(&mut channel.room_reverb, room_reverb)
+    }
+
+    pub fn get_room_reverb(&self, id: u32) -> Option<&FeedbackDelayNetwork> {
+        self.channels.get(&id)?.room_reverb.as_deref()
+    }
+
+    fn update_room_nodes(&mut self, id: u32) {
+        let nodes = match self.channels.get(&id).and_then(|c| c.room.as_ref()) {
+            Some(room) => *room.get_node_positions(),
+            None => return,
+        };
+        for (idx, node) in ROOM_NODE_SOURCES.zip(nodes.iter()) {
+            if let Some(channel) = self.channels.get_mut(&virtual_source_id(id, idx)) {
+                channel.apparent_position = Some(*node);
+            }
+        }
+    }
+
+    // renders all sources and mixes them into the (interleaved stereo) output bus.
+    // The directivity stage is skipped if no directivity storage is given.
+    // With a worker pool, thread k renders the sources k, k + n_threads, ... (in id order)
+    // into its own bus, the buses are summed in thread order afterwards.
+    pub fn process(&mut self, output: &mut [f32], filter_storage: &FilterStorage, directivity_storage: Option<&DirectivityStorage>) {
+        self.process_per_source(output, &|_| filter_storage, directivity_storage);
+    }
+
+    // like process, but the filters are taken from one of several storages, filter_storage maps
+    // the storage index of a source (set_filter_for_direction_in_storage) to its storage
+    // (e.g. the measured BRIR set of the source position)
+    pub fn process_per_source<'a>(&mut self, output: &mut [f32], filter_storage: &(dyn Fn(usize) -> &'a FilterStorage + Sync), directivity_storage: Option<&DirectivityStorage>) {
+        output.iter_mut().for_each(|s| *s = 0.0);
+
+        // room models write the inputs of their nodes, the other virtual sources render the
+        // input of their parent
+        for idx in 0..self.order.len() {
+            let id = self.order[idx];
+            let channel = self.channels.get_mut(&id).unwrap();
+            if let Some(room) = channel.room.as_mut() {
+                room.process(&channel.input, &mut self.room_outputs);
+                for (node_idx, node_output) in ROOM_NODE_SOURCES.zip(self.room_outputs.iter()) {
+                    if let Some(node) = self.channels.get_mut(&virtual_source_id(id, node_idx)) {
+                        node.input.copy_from_slice(node_output);
+                    }
+                }
+            }
+        }
+        for idx in 0..self.order.len() {
+            let id = self.order[idx];
+            let channel = &self.channels[&id];
+            if let (Some(parent), false) = (channel.parent, channel.external_input) {
+                let input = std::mem::take(&mut self.channels.get_mut(&parent).unwrap().input);
+                self.channels.get_mut(&id).unwrap().input.copy_from_slice(&input);
+                self.channels.get_mut(&parent).unwrap().input = input;
+            }
+        }
+
+        // capacity is reserved in add_channel, so this does not allocate
+        self.channel_ptrs.clear();
+        for id in self.order.iter() {
+            let channel = self.channels.get_mut(id).unwrap();
+            self.channel_ptrs.push(SharedPtr::new(&mut **channel as *mut SpatializerChannel));
+        }
+
+        let worker_pool = match self.worker_pool.as_mut() {
+            Some(worker_pool) if self.channel_ptrs.len() > 1 => worker_pool,
+            _ => {
+                for channel_ptr in self.channel_ptrs.iter() {
+                    let channel = unsafe { &mut *channel_ptr.get() };
+                    channel.render(output, filter_storage, directivity_storage);
+                }
+                return;
+            }
+        };
+
+        let n_threads = worker_pool.get_n_threads();
+        let channel_ptrs = &self.channel_ptrs;
+        let bus_ptrs = SharedPtr::new(self.thread_buses.as_mut_ptr());
+        worker_pool.run(&|thread_idx: usize| {
+            // every thread only touches its own bus and its own channels
+            let bus = unsafe { &mut *bus_ptrs.get().add(thread_idx) };
+            bus.iter_mut().for_each(|s| *s = 0.0);
+            for channel_ptr in channel_ptrs.iter().skip(thread_idx).step_by(n_threads) {
+                let channel = unsafe { &mut *channel_ptr.get() };
+                channel.render(bus, filter_storage, directivity_storage);
+            }
+        });
+
+        for bus in self.thread_buses.iter() {
+            output.iter_mut().zip(bus.iter()).for_each(|(o, b)| *o += b);
+        }
+    }
+}
+
+// Test fixture: short blocks and filters that only delay and scale, so that the expected
+// output of a test can be written down sample by sample.
+#[cfg(test)]
+pub(crate) const TEST_BUFFER_SIZE: usize = 8;
+
+// one block, gain at sample delay
+#[cfg(test)]
+pub(crate) fn test_impulse(delay: usize, gain: f32) -> Vec<f32> {
+    let mut impulse = vec![0.0; TEST_BUFFER_SIZE];
+    impulse[delay] = gain;
+    impulse
+}
+
+// one filter per (delay, gain), the same for both ears, at the azimuths 0, 10, 20, .. degrees
+#[cfg(test)]
+pub(crate) fn test_filter_storage(fft_manager: &mut FFTManager, filter_type: BinauralFilterType, impulses: &[(usize, f32)]) -> (FilterStorage, crate::filter::FilterTree) {
+    let filters = impulses
+        .iter()
+        .enumerate()
+        .map(|(idx, (delay, gain))| {
+            let impulse = test_impulse(*delay, *gain);
+            let filter = BinauralFilter::from_vec(impulse.clone(), impulse, fft_manager, filter_type, TEST_BUFFER_SIZE);
+            ([10.0 * idx as f32, 0.0], filter)
+        })
+        .collect();
+    FilterStorage::from_filters(filters)
+}
+
+// bank of single block filters with the direct sound filters of test_filter_storage
+#[cfg(test)]
+pub(crate) fn test_bank(impulses: &[(usize, f32)]) -> (SpatializerBank, FilterStorage) {
+    let mut fft_manager = FFTManager::new(2 * TEST_BUFFER_SIZE);
+    let (filter_storage, _) = test_filter_storage(&mut fft_manager, BinauralFilterType::DirectSound, impulses);
+    let bank = SpatializerBank::with_n_segments(TEST_BUFFER_SIZE, fft_manager, 1);
+    (bank, filter_storage)
+}
+
+// interleaved stereo output of n_blocks, the source plays a unit impulse at the start
+#[cfg(test)]
+fn test_impulse_response(bank: &mut SpatializerBank, filter_storage: &FilterStorage, id: u32, n_blocks: usize) -> Vec<f32> {
+    let mut response = Vec::new();
+    let mut output = vec![0.0; 2 * TEST_BUFFER_SIZE];
+    for block in 0..n_blocks {
+        let input = if block == 0 { test_impulse(0, 1.0) } else { vec![0.0; TEST_BUFFER_SIZE] };
+        bank.input_mut(id).unwrap().copy_from_slice(&input);
+        bank.process(&mut output, filter_storage, None);
+        response.extend_from_slice(&output);
+    }
+    response
+}
+
+// index of the first sample above threshold
+#[cfg(test)]
+fn arrival(signal: &[f32], threshold: f32) -> Option<usize> {
+    signal.iter().position(|s| s.abs() > threshold)
+}
+
+#[test]
+fn test_mono_convolver_identity() {
+    let mut fft_manager = FFTManager::new(2 * TEST_BUFFER_SIZE);
+    let filter = MonoFilter::from_time_domain(test_impulse(0, 1.0), &mut fft_manager, MonoFilterType::SourceDirectivity, TEST_BUFFER_SIZE);
+    let other_filter = MonoFilter::from_time_domain(test_impulse(0, 1.0), &mut fft_manager, MonoFilterType::SourceDirectivity, TEST_BUFFER_SIZE);
+    let mut convolver = MonoConvolver::new(TEST_BUFFER_SIZE, fft_manager, filter.get_n_segments());
+
+    // the output is the input, without delay: unchanged filter (no crossfade) and crossfade
+    // between two identical responses
+    let input: Vec<f32> = (0..TEST_BUFFER_SIZE).map(|i| i as f32).collect();
+    let mut output = vec![0.0; TEST_BUFFER_SIZE];
+    convolver.process(&input, &mut output, &filter, &filter);
+    for (o, i) in output.iter().zip(input.iter()) {
+        assert!((o - i).abs() < 1e-4);
+    }
+    convolver.process(&input, &mut output, &filter, &other_filter);
+    for (o, i) in output.iter().zip(input.iter()) {
+        assert!((o - i).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn test_spatializer_identity() {
+    let mut fft_manager = FFTManager::new(2 * TEST_BUFFER_SIZE);
+    let (filter_storage, _) = test_filter_storage(&mut fft_manager, BinauralFilterType::DirectSound, &[(0, 1.0)]);
+    let filter = filter_storage.get_binaural_filter(BinauralFilterType::DirectSound, 0);
+    let mut spatializer = Spatializer::with_n_segments(TEST_BUFFER_SIZE, fft_manager, filter.get_n_segments());
+
+    // both ears get the input, without delay
+    let input: Vec<f32> = (0..TEST_BUFFER_SIZE).map(|i| i as f32).collect();
+    let mut output = vec![0.0; 2 * TEST_BUFFER_SIZE];
+    spatializer.process(&input, &mut output, filter, filter);
+    for (s, i) in output.chunks(2).zip(input.iter()) {
+        assert!((s[0] - i).abs() < 1e-4);
+        assert!((s[1] - i).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn test_bank_add_remove_mix() {
+    // one sample delay, the first output sample comes from the input history
+    let (mut bank, filter_storage) = test_bank(&[(1, 1.0)]);
+    let mut output = vec![0.0; 2 * TEST_BUFFER_SIZE];
+
+    // both sources are mixed into the bus, they take their channels from the spare ones
+    let mut channels = bank.get_channel_factory().new_channels(3);
+    bank.sync_sources(&[3, 7], &mut channels);
+    assert_eq!(bank.get_n_sources(), 2);
+    assert_eq!(channels.len(), 1);
+    for _ in 0..2 {
+        bank.input_mut(3).unwrap().iter_mut().enumerate().for_each(|(i, s)| *s = i as f32);
+        bank.input_mut(7).unwrap().fill(1.0);
+        bank.process(&mut output, &filter_storage, None);
+    }
+    for (i, frame) in output.chunks(2).enumerate() {
+        let expected = (i + TEST_BUFFER_SIZE - 1) % TEST_BUFFER_SIZE + 1;
+        assert!((frame[0] - expected as f32).abs() < 1e-4);
+        assert!((frame[1] - expected as f32).abs() < 1e-4);
+    }
+
+    // removing a source keeps the ids and the input history of the others, the removed
+    // channel is handed back
+    bank.sync_sources(&[7, 9], &mut channels);
+    assert!(channels.is_empty());
+    assert_eq!(bank.take_removed().count(), 1);
+    assert_eq!(bank.take_removed().count(), 0);
+    assert!(!bank.contains_source(3));
+    assert!(bank.contains_source(7) && bank.contains_source(9));
+    bank.input_mut(7).unwrap().fill(1.0);
+    bank.input_mut(9).unwrap().fill(0.0);
+    bank.process(&mut output, &filter_storage, None);
+    assert!(output.iter().all(|s| (s - 1.0).abs() < 1e-4));
+}
+
+#[test]
+fn test_bank_source_signal() {
+    let (mut bank, _) = test_bank(&[(0, 1.0)]);
+    bank.add_source(1);
+
+    // the signal is looped over the blocks
+    bank.set_source_signal(1, Some(Arc::from([1.0, 2.0, 3.0])));
+    bank.read_source_signals();
+    assert_eq!(bank.input_mut(1).unwrap(), &[1.0, 2.0, 3.0, 1.0, 2.0, 3.0, 1.0, 2.0]);
+    bank.read_source_signals();
+    assert_eq!(bank.input_mut(1).unwrap()[..2], [3.0, 1.0]);
+
+    // silenced
+    bank.set_source_signal(1, None);
+    bank.read_source_signals();
+    assert!(bank.input_mut(1).unwrap().iter().all(|s| *s == 0.0));
+}
+
+#[test]
+fn test_bank_omnidirectional_source() {
+    use crate::directivity::DirectivityPattern;
+
+    let (mut bank, filter_storage) = test_bank(&[(0, 1.0)]);
+    let mut fft_manager = FFTManager::new(2 * TEST_BUFFER_SIZE);
+    let omni = DirectivityStorage::from_pattern(DirectivityPattern::Omnidirectional, &mut fft_manager, TEST_BUFFER_SIZE);
+    let cardioid = DirectivityStorage::from_pattern(DirectivityPattern::Cardioid, &mut fft_manager, TEST_BUFFER_SIZE);
+    assert!(omni.is_omnidirectional() && !cardioid.is_omnidirectional());
+
+    // an omnidirectional pattern replaces a previous directivity, the source skips the stage
+    bank.enable_directivity();
+    bank.add_source(1);
+    bank.set_source_directivity(1, Some(Arc::new(cardioid)));
+    assert!(bank.get_source_directivity(1).is_some());
+    bank.set_source_directivity(1, Some(Arc::new(omni)));
+    assert!(bank.get_source_directivity(1).is_none());
+
+    // unity response of the direct sound filter
+    let response = test_impulse_response(&mut bank, &filter_storage, 1, 2);
+    assert!((response[0] - 1.0).abs() < 1e-4 && (response[1] - 1.0).abs() < 1e-4);
+    assert!(response[2..].iter().all(|s| s.abs() < 1e-4));
+}
+
+#[test]
+fn test_bank_filter_switch_during_crossfade() {
+    // filters of gain 1, 2 and 3
+    let (mut bank, filter_storage) = test_bank(&[(0, 1.0), (0, 2.0), (0, 3.0)]);
+    bank.add_source(1);
+    bank.set_filter_for_direction(1, 0, 0.0, 0.0);
+    let mut output = vec![0.0; 2 * TEST_BUFFER_SIZE];
+    let mut process = |bank: &mut SpatializerBank| {
+        bank.input_mut(1).unwrap().fill(1.0);
+        bank.process(&mut output, &filter_storage, None);
+        output[0]
+    };
+    process(&mut bank);
+
+    // the second switch waits until the first crossfade (4 blocks) is done, the output never
+    // jumps back to the first filter
+    bank.set_filter_for_direction(1, 1, 90.0, 0.0);
+    let mut levels = vec![process(&mut bank)];
+    bank.set_filter_for_direction(1, 2, 180.0, 0.0);
+    let channel = bank.get_channel(1).unwrap();
+    assert_eq!((channel.get_prev_filter_id(), channel.get_active_filter_id()), (0, 1));
+    for _ in 0..32 {
+        levels.push(process(&mut bank));
+    }
+    assert!(levels.windows(2).all(|pair| pair[1] >= pair[0] - 1e-4), "{levels:?}");
+    assert!((levels.last().unwrap() - 3.0).abs() < 1e-4);
+    let channel = bank.get_channel(1).unwrap();
+    assert_eq!((channel.get_prev_filter_id(), channel.get_active_filter_id()), (2, 2));
+}
+
+#[test]
+fn test_bank_propagation_delay() {
+    let (mut bank, filter_storage) = test_bank(&[(0, 1.0)]);
+    bank.set_sample_rate(48000.0);
+    bank.add_source(1);
+    assert!(!bank.has_doppler(1));
+    let delay = |distance: f32| propagation_delay(distance, Atmosphere::default().speed_of_sound(), 48000.0);
+
+    // with and without Doppler, an impulse arrives after the propagation delay of the
+    // distance, nothing comes earlier
+    for doppler in [false, true] {
+        bank.remove_source(1);
+        bank.add_source(1);
+        bank.set_doppler(1, doppler);
+        bank.set_distance(1, 0.2);
+        let left: Vec<f32> = test_impulse_response(&mut bank, &filter_storage, 1, 6).into_iter().step_by(2).collect();
+        let peak = (0..left.len()).max_by(|a, b| left[*a].abs().total_cmp(&left[*b].abs())).unwrap();
+        assert_eq!(peak, delay(0.2).round() as usize);
+        assert!(left[..delay(0.2).floor() as usize - 1].iter().all(|s| s.abs() < 1e-3));
+
+        // the delay follows a new distance: ramped over the time since the previous update
+        // (6 blocks) with Doppler, within one block without
+        bank.set_distance(1, 0.4);
+        test_impulse_response(&mut bank, &filter_storage, 1, 1);
+        let expected = match doppler {
+            true => delay(0.2) + (delay(0.4) - delay(0.2)) / 6.0,
+            false => delay(0.4),
+        };
+        assert!((bank.get_propagation_delay(1).unwrap() - expected).abs() < 1e-3);
+    }
+}
+
+#[test]
+fn test_bank_reflection_arrival() {
+    let (mut bank, filter_storage) = test_bank(&[(0, 1.0)]);
+    let sample_rate = 4800.0;
+    bank.set_sample_rate(sample_rate);
+    bank.add_source(1);
+    bank.set_virtual_sources(1, REFLECTION_SOURCES, 1, &mut bank.get_channel_factory().new_channels(1));
+    let reflection_id = virtual_source_id(1, REFLECTION_SOURCES.start);
+    let (direct_length, reflection_length) = (1.0, 3.0);
+    bank.set_distance(1, direct_length);
+    bank.set_distance(reflection_id, reflection_length);
+
+    // the image source arrives (length difference) / c after the direct sound
+    let left: Vec<f32> = test_impulse_response(&mut bank, &filter_storage, 1, 8).into_iter().step_by(2).collect();
+    let delay = |length: f32| propagation_delay(length, Atmosphere::default().speed_of_sound(), sample_rate).round() as usize;
+    let peak = |range: Range<usize>| range.clone().max_by(|a, b| left[*a].abs().total_cmp(&left[*b].abs())).unwrap();
+    let middle = (delay(direct_length) + delay(reflection_length)) / 2;
+    let direct = peak(0..middle);
+    let reflection = peak(middle..left.len());
+    assert_eq!(direct, delay(direct_length));
+    assert_eq!(reflection, delay(reflection_length));
+    // nothing before the direct sound but the spread of the fractional delay
+    assert!(arrival(&left, 1e-3).unwrap() + 2 >= direct);
+    // spreading loss of the longer path (the filters keep the DC gain)
+    let ratio = left[middle..].iter().sum::<f32>() / left[..middle].iter().sum::<f32>();
+    assert!((ratio - direct_length / reflection_length).abs() < 0.01, "{ratio}");
+}
+
+#[test]
+fn test_bank_coupled_reverb_tail() {
+    let (mut bank, filter_storage) = test_bank(&[(0, 1.0)]);
+    bank.add_source(1);
+    let reverb = crate::multi_room::CoupledReverb { room_idx: 0, rt60: 1.0, coupling: 0.5 };
+    let sample_rate = 480.0;
+    bank.set_coupled_reverb(1, Some(Box::new(FeedbackDelayNetwork::new(reverb, sample_rate))));
+    assert_eq!(bank.get_coupled_reverb(1).unwrap().get_late_reverb(), LateReverb::broadband(reverb.rt60, reverb.coupling));
+
+    // the direct sound arrives at once, the reverberation of the coupled room after the
+    // shortest delay line of the network on the left, the second one on the right
+    let response = test_impulse_response(&mut bank, &filter_storage, 1, 4);
+    let line_length = |line: usize| (crate::fdn::LINE_LENGTHS[line] as f32 * sample_rate / 48000.0) as usize;
+    for (ear, line) in [(0, 0), (1, 1)] {
+        let signal: Vec<f32> = response.iter().skip(ear).step_by(2).copied().collect();
+        assert!((signal[0] - 1.0).abs() < 1e-4);
+        assert_eq!(arrival(&signal[1..], 1e-6).map(|idx| idx + 1), Some(line_length(line)));
+    }
+
+    bank.set_coupled_reverb(1, None);
+    let response = test_impulse_response(&mut bank, &filter_storage, 1, 1);
+    assert!(response[2..].iter().all(|s| s.abs() < 1e-6));
+}
+
+#[test]
+fn test_bank_room_reverb_tail() {
+    let (mut bank, filter_storage) = test_bank(&[(0, 1.0)]);
+    bank.add_source(1);
+    let sample_rate = 480.0;
+    let room_reverb = |decay_time: f32| {
+        Some(Box::new(FeedbackDelayNetwork::from_late_reverb(LateReverb::broadband(decay_time, 0.5), sample_rate)))
+    };
+    bank.set_room_reverb(1, room_reverb(1.0));
+
+    // the tail of the room follows the direct sound after the shortest delay line
+    let response = test_impulse_response(&mut bank, &filter_storage, 1, 4);
+    let left: Vec<f32> = response.iter().step_by(2).copied().collect();
+    let line_length = (crate::fdn::LINE_LENGTHS[0] as f32 * sample_rate / 48000.0) as usize;
+    assert!((left[0] - 1.0).abs() < 1e-4);
+    assert_eq!(arrival(&left[1..], 1e-6).map(|idx| idx + 1), Some(line_length));
+
+    // a network with a new decay time continues the tail, the previous one comes back empty
+    let mut previous = bank.set_room_reverb(1, room_reverb(0.5)).unwrap();
+    assert_eq!(bank.get_room_reverb(1).unwrap().get_late_reverb(), LateReverb::broadband(0.5, 0.5));
+    let mut output = vec![0.0; 2 * TEST_BUFFER_SIZE];
+    bank.input_mut(1).unwrap().fill(0.0);
+    bank.process(&mut output, &filter_storage, None);
+    assert!(output.iter().any(|s| s.abs() > 1e-6));
+    let mut previous_output = vec![0.0; 2 * TEST_BUFFER_SIZE];
+    previous.process(&[0.0; TEST_BUFFER_SIZE], &mut previous_output);
+    assert!(previous_output.iter().all(|s| *s == 0.0));
+
+    bank.set_room_reverb(1, None);
+    let response = test_impulse_response(&mut bank, &filter_storage, 1, 1);
+    assert!(response[2..].iter().all(|s| s.abs() < 1e-6));
+}
@@ -0,0 +1,158 @@
+use std::f32::consts::PI;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CrossfadeCurve {
+    Linear,
+    // sin/cos, constant power for uncorrelated signals
+    EqualPower,
+    // sin²/cos², constant amplitude for correlated signals (e.g. neighbouring HRTFs)
+    #[default]
+    RaisedCosine,
+}
+
+impl CrossfadeCurve {
+    // (fade_in, fade_out) gains at position t in [0, 1] of the crossfade
+    pub fn gains(&self, t: f32) -> (f32, f32) {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            CrossfadeCurve::Linear => (t, 1.0 - t),
+            CrossfadeCurve::EqualPower => ((PI / 2.0 * t).sin(), (PI / 2.0 * t).cos()),
+            CrossfadeCurve::RaisedCosine => {
+                ((PI / 2.0 * t).sin().powi(2), (PI / 2.0 * t).cos().powi(2))
+            }
+        }
+    }
+
+    // fills fade_in/fade_out with a complete crossfade over their length
+    pub fn fill(&self, fade_in: &mut [f32], fade_out: &mut [f32]) {
+        let n_points = fade_in.len();
+        for i in 0..n_points {
+            (fade_in[i], fade_out[i]) = self.gains((i + 1) as f32 / n_points as f32);
+        }
+    }
+}
+
+// Crossfade between two filters that may span several blocks. The length (in blocks)
+// grows with the angular distance between the old and the new filter direction, so
+// large head turns are smoothed while small movements stay responsive.
+#[derive(Debug, Clone, Copy)]
+pub struct Crossfade {
+    curve: CrossfadeCurve,
+    min_blocks: usize,
+    max_blocks: usize,
+    // jump (degrees) at which the crossfade reaches max_blocks
+    max_jump: f32,
+
+    n_blocks: usize,
+    block: usize,
+}
+
+impl Default for Crossfade {
+    fn default() -> Self {
+        Crossfade::new(CrossfadeCurve::default(), 1, 4, 30.0)
+    }
+}
+
+impl Crossfade {
+    pub fn new(curve: CrossfadeCurve, min_blocks: usize, max_blocks: usize, max_jump: f32) -> Self {
+        let min_blocks = min_blocks.max(1);
+        Self {
+            curve,
+            min_blocks,
+            max_blocks: max_blocks.max(min_blocks),
+            max_jump,
+            n_blocks: 0,
+            block: 0,
+        }
+    }
+
+    // same settings, no crossfade running
+    pub fn with_settings_of(other: &Crossfade) -> Self {
+        Crossfade::new(other.curve, other.min_blocks, other.max_blocks, other.max_jump)
+    }
+
+    pub fn get_curve(&self) -> CrossfadeCurve {
+        self.curve
+    }
+
+    // crossfade length in blocks for a direction jump (degrees)
+    pub fn n_blocks_for_jump(&self, jump: f32) -> usize {
+        let ratio = if self.max_jump > 0.0 {
+            (jump.abs() / self.max_jump).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        self.min_blocks + (ratio * (self.max_blocks - self.min_blocks) as f32).round() as usize
+    }
+
+    pub fn start(&mut self, jump: f32) {
+        self.n_blocks = self.n_blocks_for_jump(jump);
+        self.block = 0;
+    }
+
+    // crossfade with the shortest length, used when the jump is unknown
+    pub fn start_default(&mut self) {
+        self.n_blocks = self.min_blocks;
+        self.block = 0;
+    }
+
+    pub fn reset(&mut self) {
+        self.n_blocks = 0;
+        self.block = 0;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.block < self.n_blocks
+    }
+
+    // progress in [0, 1]
+    pub fn get_progress(&self) -> f32 {
+        if self.n_blocks == 0 {
+            return 1.0;
+        }
+        self.block as f32 / self.n_blocks as f32
+    }
+
+    // fills the gains of the current block and moves on to the next one
+    pub fn next_block(&mut self, fade_in: &mut [f32], fade_out: &mut [f32]) {
+        let n_points = fade_in.len();
+        let length = (self.n_blocks * n_points) as f32;
+        let offset = self.block * n_points;
+        for i in 0..n_points {
+            (fade_in[i], fade_out[i]) = self.curve.gains((offset + i + 1) as f32 / length);
+        }
+        self.block += 1;
+    }
+}
+
+#[test]
+fn test_crossfade_curves_are_complementary() {
+    for i in 0..=10 {
+        let t = i as f32 / 10.0;
+        let (fade_in, fade_out) = CrossfadeCurve::Linear.gains(t);
+        assert!((fade_in + fade_out - 1.0).abs() < 1e-6);
+        let (fade_in, fade_out) = CrossfadeCurve::RaisedCosine.gains(t);
+        assert!((fade_in + fade_out - 1.0).abs() < 1e-6);
+        let (fade_in, fade_out) = CrossfadeCurve::EqualPower.gains(t);
+        assert!((fade_in.powi(2) + fade_out.powi(2) - 1.0).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_crossfade_spans_blocks() {
+    let mut crossfade = Crossfade::new(CrossfadeCurve::Linear, 1, 4, 40.0);
+    assert_eq!(crossfade.n_blocks_for_jump(0.0), 1);
+    assert_eq!(crossfade.n_blocks_for_jump(20.0), 3);
+    assert_eq!(crossfade.n_blocks_for_jump(90.0), 4);
+
+    let mut fade_in = vec![0.0; 4];
+    let mut fade_out = vec![0.0; 4];
+    crossfade.start(90.0);
+    for _ in 0..4 {
+        assert!(crossfade.is_active());
+        crossfade.next_block(&mut fade_in, &mut fade_out);
+    }
+    assert!(!crossfade.is_active());
+    assert!((fade_in[3] - 1.0).abs() < 1e-6);
+    assert!(fade_out[3].abs() < 1e-6);
+}
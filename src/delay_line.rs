@@ -0,0 +1,314 @@
+use std::f32::consts::PI;
+
+// sub-sample resolution of the windowed sinc table
+const SINC_OVERSAMPLING: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    // odd order Lagrange interpolation (order + 1 taps)
+    Lagrange(usize),
+    // first-order Thiran allpass (flat magnitude, stateful)
+    Thiran,
+    // Hann-windowed sinc with 2 * half_length taps
+    WindowedSinc(usize),
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Interpolation::Lagrange(3)
+    }
+}
+
+impl Interpolation {
+    // smallest delay (samples) the interpolator can produce without reading future samples
+    pub fn get_min_delay(&self) -> f32 {
+        match self {
+            Interpolation::Linear => 0.0,
+            Interpolation::Lagrange(order) => ((order.max(&1) - 1) / 2) as f32,
+            Interpolation::Thiran => 0.5,
+            Interpolation::WindowedSinc(half_length) => half_length.saturating_sub(1) as f32,
+        }
+    }
+
+    // number of past samples needed beyond the integer delay
+    fn get_n_taps(&self) -> usize {
+        match self {
+            Interpolation::Linear | Interpolation::Thiran => 2,
+            Interpolation::Lagrange(order) => order + 1,
+            Interpolation::WindowedSinc(half_length) => 2 * half_length,
+        }
+    }
+}
+
+// Propagation delay in samples for a path of the given length (m).
+pub fn propagation_delay(distance: f32, speed_of_sound: f32, sample_rate: f32) -> f32 {
+    distance / speed_of_sound * sample_rate
+}
+
+// Read position of a delay line. Delay changes are ramped linearly, so moving sources
+// produce a smooth (Doppler-like) pitch change instead of clicks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DelayTap {
+    delay: f32,
+    target_delay: f32,
+    step: f32,
+    ramp_remaining: usize,
+    // Thiran allpass state
+    thiran_y1: f32,
+}
+
+impl DelayTap {
+    pub fn new(delay: f32) -> Self {
+        Self {
+            delay,
+            target_delay: delay,
+            ..Default::default()
+        }
+    }
+
+    pub fn get_delay(&self) -> f32 {
+        self.delay
+    }
+
+    pub fn get_target_delay(&self) -> f32 {
+        self.target_delay
+    }
+
+    // moves the delay to target_delay within ramp_length samples
+    pub fn set_delay(&mut self, target_delay: f32, ramp_length: usize) {
+        self.target_delay = target_delay;
+        if ramp_length == 0 {
+            self.delay = target_delay;
+            self.ramp_remaining = 0;
+            return;
+        }
+        self.step = (target_delay - self.delay) / ramp_length as f32;
+        self.ramp_remaining = ramp_length;
+    }
+
+    fn advance(&mut self) {
+        if self.ramp_remaining > 0 {
+            self.ramp_remaining -= 1;
+            self.delay = if self.ramp_remaining == 0 {
+                self.target_delay
+            } else {
+                self.delay + self.step
+            };
+        }
+    }
+}
+
+// Circular buffer with fractionally delayed read taps. One line can feed any number of
+// taps, e.g. the direct path and all image source paths of a source.
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    mask: usize,
+    write_pos: usize,
+    interpolation: Interpolation,
+    sinc_table: Vec<f32>,
+}
+
+impl DelayLine {
+    pub fn new(max_delay: usize, interpolation: Interpolation) -> Self {
+        let capacity = (max_delay + interpolation.get_n_taps() + 1).next_power_of_two();
+        let sinc_table = match interpolation {
+            Interpolation::WindowedSinc(half_length) => windowed_sinc_table(half_length),
+            _ => Vec::new(),
+        };
+        Self {
+            buffer: vec![0.0; capacity],
+            mask: capacity - 1,
+            write_pos: 0,
+            interpolation,
+            sinc_table,
+        }
+    }
+
+    pub fn get_interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    // largest delay (samples) that can be read
+    pub fn get_max_delay(&self) -> f32 {
+        (self.buffer.len() - self.interpolation.get_n_taps() - 1) as f32
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.iter_mut().for_each(|s| *s = 0.0);
+    }
+
+    pub fn write(&mut self, sample: f32) {
+        self.write_pos = (self.write_pos + 1) & self.mask;
+        self.buffer[self.write_pos] = sample;
+    }
+
+    // sample written `age` samples ago (0 = last written sample)
+    fn at(&self, age: usize) -> f32 {
+        self.buffer[(self.write_pos.wrapping_sub(age)) & self.mask]
+    }
+
+    // reads the tap at its current delay and moves its delay ramp on by one sample
+    pub fn read(&self, tap: &mut DelayTap) -> f32 {
+        let delay = tap
+            .delay
+            .clamp(self.interpolation.get_min_delay(), self.get_max_delay());
+        let sample = match self.interpolation {
+            Interpolation::Linear => {
+                let d_int = delay.floor();
+                let frac = delay - d_int;
+                let d_int = d_int as usize;
+                self.at(d_int) * (1.0 - frac) + self.at(d_int + 1) * frac
+            }
+            Interpolation::Lagrange(order) => self.read_lagrange(delay, order.max(1)),
+            Interpolation::Thiran => self.read_thiran(delay, &mut tap.thiran_y1),
+            Interpolation::WindowedSinc(half_length) => self.read_sinc(delay, half_length),
+        };
+        tap.advance();
+        sample
+    }
+
+    // single tap convenience: delays a block of samples
+    pub fn process(&mut self, input: &[f32], output: &mut [f32], tap: &mut DelayTap) {
+        for (x, y) in input.iter().zip(output.iter_mut()) {
+            self.write(*x);
+            *y = self.read(tap);
+        }
+    }
+
+    pub fn process_in_place(&mut self, buffer: &mut [f32], tap: &mut DelayTap) {
+        for s in buffer.iter_mut() {
+            self.write(*s);
+            *s = self.read(tap);
+        }
+    }
+
+    // like process_in_place, but the output is crossfaded linearly from the tap `from` to the
+    // tap `to` over the block: the delay changes without a pitch shift
+    pub fn process_crossfade_in_place(&mut self, buffer: &mut [f32], from: &mut DelayTap, to: &mut DelayTap) {
+        let n_points = buffer.len() as f32;
+        for (i, s) in buffer.iter_mut().enumerate() {
+            self.write(*s);
+            let t = (i + 1) as f32 / n_points;
+            *s = (1.0 - t) * self.read(from) + t * self.read(to);
+        }
+    }
+
+    fn read_lagrange(&self, delay: f32, order: usize) -> f32 {
+        // centre the fractional part in the interpolation interval
+        let half = ((order - 1) / 2) as f32;
+        let base = (delay - half).floor();
+        let d_local = delay - base;
+        let base = base as usize;
+        let mut sample = 0.0;
+        for k in 0..=order {
+            let mut h = 1.0;
+            for m in 0..=order {
+                if m != k {
+                    h *= (d_local - m as f32) / (k as f32 - m as f32);
+                }
+            }
+            sample += h * self.at(base + k);
+        }
+        sample
+    }
+
+    fn read_thiran(&self, delay: f32, y1: &mut f32) -> f32 {
+        // keep the fractional delay in [0.5, 1.5) where the allpass behaves well
+        let mut d_int = delay.floor();
+        let mut frac = delay - d_int;
+        if frac < 0.5 && d_int >= 1.0 {
+            frac += 1.0;
+            d_int -= 1.0;
+        }
+        let a = (1.0 - frac) / (1.0 + frac);
+        let d_int = d_int as usize;
+        let y = a * self.at(d_int) + self.at(d_int + 1) - a * *y1;
+        *y1 = y;
+        y
+    }
+
+    fn read_sinc(&self, delay: f32, half_length: usize) -> f32 {
+        let d_int = delay.floor();
+        let frac = delay - d_int;
+        let phase = ((frac * SINC_OVERSAMPLING as f32).round() as usize).min(SINC_OVERSAMPLING);
+        let kernel = &self.sinc_table[phase * 2 * half_length..(phase + 1) * 2 * half_length];
+        let d_int = d_int as usize;
+        // taps d_int - half_length + 1 ..= d_int + half_length
+        let mut sample = 0.0;
+        for (k, h) in kernel.iter().enumerate() {
+            sample += h * self.at(d_int + k + 1 - half_length);
+        }
+        sample
+    }
+}
+
+// kernels for the fractional delays 0, 1/SINC_OVERSAMPLING, ..., 1
+fn windowed_sinc_table(half_length: usize) -> Vec<f32> {
+    let n_taps = 2 * half_length;
+    let mut table = vec![0.0; (SINC_OVERSAMPLING + 1) * n_taps];
+    for phase in 0..=SINC_OVERSAMPLING {
+        let frac = phase as f32 / SINC_OVERSAMPLING as f32;
+        for k in 0..n_taps {
+            // distance of the tap to the (fractional) read position
+            let t = k as f32 + 1.0 - half_length as f32 - frac;
+            let sinc = if t.abs() < 1e-6 { 1.0 } else { (PI * t).sin() / (PI * t) };
+            let window = 0.5 + 0.5 * (PI * t / (half_length as f32 + 1.0)).cos();
+            table[phase * n_taps + k] = sinc * window;
+        }
+    }
+    table
+}
+
+#[test]
+fn test_integer_delay() {
+    for interpolation in [
+        Interpolation::Linear,
+        Interpolation::Lagrange(3),
+        Interpolation::Thiran,
+        Interpolation::WindowedSinc(8),
+    ] {
+        let mut delay_line = DelayLine::new(64, interpolation);
+        let mut tap = DelayTap::new(10.0);
+        let mut input = vec![0.0; 32];
+        input[0] = 1.0;
+        let mut output = vec![0.0; 32];
+        delay_line.process(&input, &mut output, &mut tap);
+        for (i, y) in output.iter().enumerate() {
+            let expected = if i == 10 { 1.0 } else { 0.0 };
+            assert!((y - expected).abs() < 1e-3, "{:?} at {}: {}", interpolation, i, y);
+        }
+    }
+}
+
+#[test]
+fn test_fractional_delay_of_sine() {
+    // a slow sine delayed by 10.25 samples must match the analytic result
+    let omega = 2.0 * PI * 0.01;
+    for interpolation in [Interpolation::Lagrange(3), Interpolation::WindowedSinc(8)] {
+        let mut delay_line = DelayLine::new(64, interpolation);
+        let mut tap = DelayTap::new(10.25);
+        for n in 0..200 {
+            delay_line.write((omega * n as f32).sin());
+            let y = delay_line.read(&mut tap);
+            if n > 30 {
+                let expected = (omega * (n as f32 - 10.25)).sin();
+                assert!((y - expected).abs() < 1e-3, "{:?} at {}: {}", interpolation, n, y);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_delay_ramp() {
+    let mut tap = DelayTap::new(0.0);
+    tap.set_delay(10.0, 10);
+    for _ in 0..5 {
+        tap.advance();
+    }
+    assert!((tap.get_delay() - 5.0).abs() < 1e-5);
+    for _ in 0..10 {
+        tap.advance();
+    }
+    assert_eq!(tap.get_delay(), 10.0);
+}
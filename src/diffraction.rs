@@ -0,0 +1,228 @@
+use std::f32::consts::PI;
+
+use nalgebra::{Point3, Vector3};
+use num_complex::Complex;
+
+use crate::{
+    biquad::OCTAVE_BAND_CENTRES,
+    obstacle::{Obstacle, Occlusion},
+    transmission::WallTransmission,
+};
+
+// strongest diffraction paths kept per source
+pub const MAX_DIFFRACTION_PATHS: usize = 4;
+
+type BandGains = [f32; OCTAVE_BAND_CENTRES.len()];
+
+// Wedge edge. face_0 and face_1 point from the edge along the two faces of the wedge,
+// the solid part is the (smaller) angle between them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    start: Point3<f32>,
+    end: Point3<f32>,
+    face_0: Vector3<f32>,
+    face_1: Vector3<f32>,
+}
+
+impl Edge {
+    pub fn new(start: Point3<f32>, end: Point3<f32>, face_0: Vector3<f32>, face_1: Vector3<f32>) -> Self {
+        let direction = (end - start).normalize();
+        // keep the face directions perpendicular to the edge
+        let perpendicular = |v: Vector3<f32>| (v - direction * v.dot(&direction)).normalize();
+        Self {
+            start,
+            end,
+            face_0: perpendicular(face_0),
+            face_1: perpendicular(face_1),
+        }
+    }
+
+    pub fn get_start(&self) -> Point3<f32> {
+        self.start
+    }
+
+    pub fn get_end(&self) -> Point3<f32> {
+        self.end
+    }
+
+    fn direction(&self) -> Vector3<f32> {
+        (self.end - self.start).normalize()
+    }
+
+    // exterior wedge angle divided by pi (2 for a thin plate, 1.5 for a right angle)
+    pub fn get_wedge_index(&self) -> f32 {
+        let interior = self.face_0.dot(&self.face_1).clamp(-1.0, 1.0).acos();
+        (2.0 * PI - interior) / PI
+    }
+
+    // axes of the plane perpendicular to the edge: x along face 0, y into the exterior
+    fn basis(&self) -> (Vector3<f32>, Vector3<f32>) {
+        let x = self.face_0;
+        let y = -(self.face_1 - x * self.face_1.dot(&x));
+        let y = y
+            .try_normalize(1e-6)
+            .unwrap_or_else(|| self.direction().cross(&x).normalize());
+        (x, y)
+    }
+
+    // angle of a point around the edge, measured from face 0 through the exterior
+    fn angle(&self, apex: &Point3<f32>, point: &Point3<f32>) -> f32 {
+        let (x, y) = self.basis();
+        let v = point - apex;
+        v.dot(&y).atan2(v.dot(&x)).rem_euclid(2.0 * PI)
+    }
+
+    // point on the edge with the shortest path from a to b
+    pub fn apex(&self, a: &Point3<f32>, b: &Point3<f32>) -> Point3<f32> {
+        // unfolding the two legs into one plane, the apex divides the edge in the ratio of
+        // the distances of a and b from the edge
+        let direction = self.direction();
+        let (t_a, t_b) = ((a - self.start).dot(&direction), (b - self.start).dot(&direction));
+        let r_a = ((a - self.start) - direction * t_a).norm();
+        let r_b = ((b - self.start) - direction * t_b).norm();
+        let t = if r_a + r_b > f32::EPSILON {
+            t_a + (t_b - t_a) * r_a / (r_a + r_b)
+        } else {
+            t_a
+        };
+        self.start + direction * t.clamp(0.0, (self.end - self.start).norm())
+    }
+
+    // UTD band gains (Kouyoumjian-Pathak, acoustically hard wedge) of the path from source
+    // over the apex to the listener, relative to free field propagation over the same length
+    pub fn band_gains(&self, source: &Point3<f32>, listener: &Point3<f32>, apex: &Point3<f32>, speed_of_sound: f32) -> BandGains {
+        let n = self.get_wedge_index();
+        let s_source = (apex - source).norm().max(1e-3);
+        let s_listener = (listener - apex).norm().max(1e-3);
+        let sin_beta = ((apex - source) / s_source).cross(&self.direction()).norm().max(1e-3);
+        let distance_parameter = s_source * s_listener * sin_beta * sin_beta / (s_source + s_listener);
+        let phi_source = self.angle(apex, source);
+        let phi_listener = self.angle(apex, listener);
+        let spreading = ((s_source + s_listener) / (s_source * s_listener)).sqrt();
+
+        OCTAVE_BAND_CENTRES.map(|frequency| {
+            let k = 2.0 * PI * frequency / speed_of_sound;
+            let kl = k * distance_parameter;
+            let mut sum = Complex::new(0.0, 0.0);
+            for beta in [phi_listener - phi_source, phi_listener + phi_source] {
+                for sign in [1.0, -1.0] {
+                    sum += utd_term(n, beta, sign, kl);
+                }
+            }
+            let prefactor = -Complex::from_polar(1.0, -PI / 4.0) / (2.0 * n * (2.0 * PI * k).sqrt() * sin_beta);
+            ((prefactor * sum).norm() * spreading).min(1.0)
+        })
+    }
+}
+
+// cot((pi + sign * beta) / 2n) F(kL a(beta))
+fn utd_term(n: f32, mut beta: f32, sign: f32, kl: f32) -> Complex<f32> {
+    let mut x = (PI + sign * beta) / (2.0 * n);
+    // the shadow and reflection boundaries are removable singularities, step off them
+    if x.sin().abs() < 1e-4 {
+        beta += sign * 2.0 * n * 1e-4;
+        x = (PI + sign * beta) / (2.0 * n);
+    }
+    let big_n = ((beta + sign * PI) / (2.0 * n * PI)).round();
+    let a = 2.0 * ((2.0 * n * PI * big_n - beta) / 2.0).cos().powi(2);
+    transition_function(kl * a) / x.tan()
+}
+
+// UTD transition function F(X) = 2j sqrt(X) e^(jX) int_sqrt(X)^inf e^(-j t^2) dt, with the
+// rational Fresnel integral approximation of Abramowitz & Stegun (7.3.32/33)
+fn transition_function(x: f32) -> Complex<f32> {
+    let x = x.max(0.0);
+    let u = (2.0 * x / PI).sqrt();
+    let f = (1.0 + 0.926 * u) / (2.0 + 1.792 * u + 3.104 * u * u);
+    let g = 1.0 / (2.0 + 4.142 * u + 3.492 * u * u + 6.670 * u * u * u);
+    Complex::new(f, g) * (2.0 * PI * x).sqrt()
+}
+
+// Path from a source over an edge to the listener, rendered as a virtual source at the apex.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffractionPath {
+    apex: Point3<f32>,
+    length: f32,
+    band_gains: BandGains,
+}
+
+impl DiffractionPath {
+    pub fn get_apex(&self) -> Point3<f32> {
+        self.apex
+    }
+
+    pub fn get_length(&self) -> f32 {
+        self.length
+    }
+
+    pub fn get_band_gains(&self) -> &BandGains {
+        &self.band_gains
+    }
+
+    pub fn get_energy(&self) -> f32 {
+        self.band_gains.iter().map(|g| g * g).sum::<f32>() / self.length.max(1.0).powi(2)
+    }
+
+    pub fn to_transmission(&self) -> WallTransmission {
+        WallTransmission::from_band_gains(self.apex, self.band_gains)
+    }
+}
+
+// Diffraction paths around the edges of the obstacles. Only computed if the direct path is
+// blocked, both legs of a path have to be free.
+pub fn find_diffraction_paths(
+    obstacles: &[Obstacle],
+    source: &Point3<f32>,
+    listener: &Point3<f32>,
+    speed_of_sound: f32,
+) -> Vec<DiffractionPath> {
+    if !Occlusion::along_segment(obstacles, source, listener).is_occluded() {
+        return Vec::new();
+    }
+    let mut paths: Vec<DiffractionPath> = Vec::new();
+    for edge in obstacles.iter().flat_map(|o| o.get_edges()) {
+        let apex = edge.apex(source, listener);
+        // move the apex off the surface, otherwise the legs would hit the obstacle itself
+        let (x, y) = edge.basis();
+        let outside = apex + (y - x).normalize() * 1e-3;
+        if Occlusion::along_segment(obstacles, source, &outside).is_occluded()
+            || Occlusion::along_segment(obstacles, &outside, listener).is_occluded()
+        {
+            continue;
+        }
+        paths.push(DiffractionPath {
+            apex,
+            length: (apex - source).norm() + (listener - apex).norm(),
+            band_gains: edge.band_gains(source, listener, &apex, speed_of_sound),
+        });
+    }
+    paths.sort_by(|a, b| b.get_energy().total_cmp(&a.get_energy()));
+    paths.truncate(MAX_DIFFRACTION_PATHS);
+    paths
+}
+
+#[test]
+fn test_half_plane_diffraction() {
+    // thin plate below the edge (z axis), extending towards -y
+    let edge = Edge::new(
+        Point3::new(0.0, 0.0, -10.0),
+        Point3::new(0.0, 0.0, 10.0),
+        -Vector3::y(),
+        -Vector3::y(),
+    );
+    assert_eq!(edge.get_wedge_index(), 2.0);
+    let source = Point3::new(-5.0, 0.0, 0.0);
+
+    // on the shadow boundary the diffracted field is about half the incident field
+    let listener = Point3::new(5.0, 0.0, 0.0);
+    let apex = edge.apex(&source, &listener);
+    assert!(apex.coords.norm() < 1e-3);
+    let gains = edge.band_gains(&source, &listener, &apex, 343.0);
+    assert!((gains[7] - 0.5).abs() < 0.1, "{:?}", gains);
+
+    // deep in the shadow, high frequencies are attenuated more
+    let listener = Point3::new(5.0, -3.0, 0.0);
+    let gains = edge.band_gains(&source, &listener, &edge.apex(&source, &listener), 343.0);
+    assert!(gains.windows(2).all(|g| g[1] < g[0]), "{:?}", gains);
+    assert!(gains[7] < 0.1);
+}
@@ -0,0 +1,168 @@
+use std::{collections::HashMap, fs::File, hash::BuildHasherDefault, io::{self, BufReader}};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use kdtree;
+use nohash_hasher::NoHashHasher;
+
+use crate::filter::{FFTManager, MonoFilter, MonoFilterType};
+
+// resolution of the emission angle grid used for the analytic patterns (degrees)
+const PATTERN_ANGLE_STEP: f32 = 5.0;
+
+// longest directivity filters (in partitions of the block size) the sources can switch to
+pub const MAX_DIRECTIVITY_SEGMENTS: usize = 4;
+
+// First-order directivity patterns: g(theta) = alpha + (1 - alpha) * cos(theta)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DirectivityPattern {
+    #[default]
+    Omnidirectional,
+    Cardioid,
+    Supercardioid,
+    Figure8,
+}
+
+impl DirectivityPattern {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "omnidirectional" => Some(DirectivityPattern::Omnidirectional),
+            "cardioid" => Some(DirectivityPattern::Cardioid),
+            "supercardioid" => Some(DirectivityPattern::Supercardioid),
+            "figure8" => Some(DirectivityPattern::Figure8),
+            _ => None,
+        }
+    }
+
+    pub fn get_alpha(&self) -> f32 {
+        match self {
+            DirectivityPattern::Omnidirectional => 1.0,
+            DirectivityPattern::Cardioid => 0.5,
+            DirectivityPattern::Supercardioid => 0.366,
+            DirectivityPattern::Figure8 => 0.0,
+        }
+    }
+
+    // magnitude for an emission angle (radians) measured from the on-axis direction
+    pub fn gain(&self, emission_angle: f32) -> f32 {
+        let alpha = self.get_alpha();
+        (alpha + (1.0 - alpha) * emission_angle.cos()).abs()
+    }
+}
+
+// angle between the on-axis direction (+z) of a source and the emission direction,
+// given as azimuth/elevation in the local frame of the source (radians)
+pub fn emission_angle(azimuth: f32, elevation: f32) -> f32 {
+    (azimuth.cos() * elevation.cos()).clamp(-1.0, 1.0).acos()
+}
+
+// Holds the mono source directivity filters of one source type. Filters are addressed by id,
+// ids are looked up by emission direction (azimuth, elevation in degrees).
+#[allow(unused)]
+pub struct DirectivityStorage {
+    storage: HashMap<usize, MonoFilter, BuildHasherDefault<NoHashHasher<usize>>>,
+    angles: kdtree::KdTree<f32, usize, [f32; 2]>,
+    // analytic patterns are rotationally symmetric and only indexed by the emission angle
+    symmetric: bool,
+    // all filters are unity, the directivity stage can be skipped
+    omnidirectional: bool,
+}
+
+#[allow(unused)]
+impl DirectivityStorage {
+    // broadband analytic pattern, sampled in PATTERN_ANGLE_STEP steps between 0° and 180°
+    pub fn from_pattern(pattern: DirectivityPattern, fft: &mut FFTManager, blocksize: usize) -> Self {
+        let mut angles: kdtree::KdTree<f32, usize, [f32; 2]> = kdtree::KdTree::new(2);
+        let mut storage: HashMap<usize, MonoFilter, BuildHasherDefault<NoHashHasher<usize>>> = HashMap::with_hasher(BuildHasherDefault::default());
+
+        let n_angles = (180.0 / PATTERN_ANGLE_STEP) as usize + 1;
+        for id in 0..n_angles {
+            let theta = id as f32 * PATTERN_ANGLE_STEP;
+            let mut data_t = vec![0.0; blocksize];
+            data_t[0] = pattern.gain(theta.to_radians());
+            angles.add([theta, 0.0], id).unwrap();
+            storage.insert(id, MonoFilter::from_time_domain(data_t, fft, MonoFilterType::SourceDirectivity, blocksize));
+        }
+
+        Self { storage, angles, symmetric: true, omnidirectional: pattern == DirectivityPattern::Omnidirectional }
+    }
+
+    // measured directivity: impulse responses of filter_length samples (f32, little endian) stored
+    // back to back in filterpath, the matching azimuth/elevation pairs (degrees) in anglepath
+    pub fn from_files(filterpath: &str, anglepath: &str, filter_length: usize, fft: &mut FFTManager, blocksize: usize) -> io::Result<Self> {
+        let mut angles: kdtree::KdTree<f32, usize, [f32; 2]> = kdtree::KdTree::new(2);
+        let mut storage: HashMap<usize, MonoFilter, BuildHasherDefault<NoHashHasher<usize>>> = HashMap::with_hasher(BuildHasherDefault::default());
+
+        let mut filter_buf_reader = BufReader::new(File::open(filterpath)?);
+        let mut angles_buf_reader = BufReader::new(File::open(anglepath)?);
+        let mut id: usize = 0;
+        loop {
+            let azimuth = match angles_buf_reader.read_f32::<LittleEndian>() {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let elevation = angles_buf_reader.read_f32::<LittleEndian>()?;
+            let mut data_t: Vec<f32> = Vec::with_capacity(filter_length);
+            for _ in 0..filter_length {
+                data_t.push(filter_buf_reader.read_f32::<LittleEndian>()?);
+            }
+            angles.add([azimuth, elevation], id).unwrap();
+            storage.insert(id, MonoFilter::from_time_domain(data_t, fft, MonoFilterType::SourceDirectivity, blocksize));
+            id += 1;
+        }
+        if storage.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no directivity filters in {filterpath}")));
+        }
+
+        Ok(Self { storage, angles, symmetric: false, omnidirectional: false })
+    }
+
+    // azimuth and elevation of the emission direction in the local frame of the source (degrees)
+    pub fn find_closest_filter(&self, azimuth: f32, elevation: f32) -> usize {
+        let key = if self.symmetric {
+            [emission_angle(azimuth.to_radians(), elevation.to_radians()).to_degrees(), 0.0]
+        } else {
+            [azimuth, elevation]
+        };
+        *self.angles.nearest(&key, 1, &kdtree::distance::squared_euclidean).unwrap()[0].1
+    }
+
+    pub fn get_mono_filter(&self, id: usize) -> &MonoFilter {
+        self.storage.get(&id).unwrap()
+    }
+
+    pub fn is_omnidirectional(&self) -> bool {
+        self.omnidirectional
+    }
+
+    pub fn get_n_segments(&self) -> usize {
+        self.storage.values().next().unwrap().get_n_segments()
+    }
+}
+
+#[test]
+fn test_pattern_gain_and_emission_angle() {
+    use std::f32::consts::{FRAC_PI_2, PI};
+    use nalgebra::{Point3, Vector3};
+    use crate::image_source_method::{CardinalDirection, ISMImageSource};
+
+    // on-axis (+z), sideways and behind the source
+    assert!(emission_angle(0.0, 0.0).abs() < 1e-6);
+    assert!((emission_angle(FRAC_PI_2, 0.0) - FRAC_PI_2).abs() < 1e-6);
+    assert!((emission_angle(0.0, -FRAC_PI_2) - FRAC_PI_2).abs() < 1e-6);
+    assert!((emission_angle(PI, 0.0) - PI).abs() < 1e-6);
+
+    let cardioid = DirectivityPattern::from_name("cardioid").unwrap();
+    assert!((cardioid.gain(0.0) - 1.0).abs() < 1e-6);
+    assert!((cardioid.gain(FRAC_PI_2) - 0.5).abs() < 1e-6);
+    assert!(cardioid.gain(PI).abs() < 1e-6);
+    // the rear lobe of the figure-8 has the same magnitude
+    assert!((DirectivityPattern::Figure8.gain(PI) - 1.0).abs() < 1e-6);
+    assert_eq!(DirectivityPattern::Omnidirectional.gain(2.0), 1.0);
+
+    // source at x = 1 in front of the wall at x = 0, the listener at x = 2 hears the reflection
+    // from the image source at x = -1, the path left the source towards the wall
+    let image_source = ISMImageSource::new(1, Point3::new(-1.0, 0.0, 0.0), CardinalDirection::NORTH);
+    let emission = image_source.get_emission_direction(&Point3::new(2.0, 0.0, 0.0));
+    assert!((emission - Vector3::new(-3.0, 0.0, 0.0)).norm() < 1e-6);
+}
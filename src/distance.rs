@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+// Distance attenuation models, similar to the ones of game engines. All models are
+// clamped to [reference_distance, max_distance], the gain is 1 at the reference distance.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum DistanceModel {
+    #[default]
+    Inverse,
+    InverseSquareClamped,
+    Linear,
+    // (distance, gain) points, linearly interpolated, sorted by distance. Shared by the
+    // virtual sources of a source, cloning does not allocate.
+    Custom(Arc<[(f32, f32)]>),
+}
+
+impl DistanceModel {
+    pub fn from_name(name: &str, curve: Vec<(f32, f32)>) -> Option<Self> {
+        match name {
+            "inverse" => Some(DistanceModel::Inverse),
+            "inverse_square" | "inverse_square_clamped" => Some(DistanceModel::InverseSquareClamped),
+            "linear" => Some(DistanceModel::Linear),
+            "custom" => Some(DistanceModel::Custom(curve.into())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceAttenuation {
+    model: DistanceModel,
+    reference_distance: f32,
+    max_distance: f32,
+    rolloff: f32,
+}
+
+impl Default for DistanceAttenuation {
+    fn default() -> Self {
+        DistanceAttenuation::new(DistanceModel::Inverse, 1.0, 100.0, 1.0)
+    }
+}
+
+impl DistanceAttenuation {
+    pub fn new(model: DistanceModel, reference_distance: f32, max_distance: f32, rolloff: f32) -> Self {
+        let reference_distance = reference_distance.max(f32::EPSILON);
+        let mut model = model;
+        if let DistanceModel::Custom(curve) = &mut model {
+            if curve.windows(2).any(|p| p[0].0 > p[1].0) {
+                let mut sorted = curve.to_vec();
+                sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+                *curve = sorted.into();
+            }
+        }
+        Self {
+            model,
+            reference_distance,
+            max_distance: max_distance.max(reference_distance),
+            rolloff,
+        }
+    }
+
+    pub fn get_model(&self) -> &DistanceModel {
+        &self.model
+    }
+
+    pub fn gain(&self, distance: f32) -> f32 {
+        let d = distance.clamp(self.reference_distance, self.max_distance);
+        let d_ref = self.reference_distance;
+        match &self.model {
+            DistanceModel::Inverse => d_ref / (d_ref + self.rolloff * (d - d_ref)),
+            DistanceModel::InverseSquareClamped => {
+                (d_ref / (d_ref + self.rolloff * (d - d_ref))).powi(2)
+            }
+            DistanceModel::Linear => {
+                if self.max_distance <= d_ref {
+                    return 1.0;
+                }
+                (1.0 - self.rolloff * (d - d_ref) / (self.max_distance - d_ref)).clamp(0.0, 1.0)
+            }
+            DistanceModel::Custom(curve) => interpolate_curve(curve, d),
+        }
+    }
+}
+
+fn interpolate_curve(curve: &[(f32, f32)], distance: f32) -> f32 {
+    let (first, last) = match (curve.first(), curve.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return 1.0,
+    };
+    if distance <= first.0 {
+        return first.1;
+    }
+    if distance >= last.0 {
+        return last.1;
+    }
+    let idx = curve.partition_point(|p| p.0 <= distance);
+    let (d0, g0) = curve[idx - 1];
+    let (d1, g1) = curve[idx];
+    g0 + (g1 - g0) * (distance - d0) / (d1 - d0)
+}
+
+#[test]
+fn test_distance_models() {
+    let inverse = DistanceAttenuation::new(DistanceModel::Inverse, 1.0, 100.0, 1.0);
+    assert_eq!(inverse.gain(0.5), 1.0);
+    assert!((inverse.gain(2.0) - 0.5).abs() < 1e-6);
+    assert!((inverse.gain(1000.0) - 0.01).abs() < 1e-6);
+
+    let inverse_square = DistanceAttenuation::new(DistanceModel::InverseSquareClamped, 1.0, 100.0, 1.0);
+    assert!((inverse_square.gain(2.0) - 0.25).abs() < 1e-6);
+
+    let linear = DistanceAttenuation::new(DistanceModel::Linear, 1.0, 11.0, 1.0);
+    assert!((linear.gain(6.0) - 0.5).abs() < 1e-6);
+    assert_eq!(linear.gain(20.0), 0.0);
+
+    let custom = DistanceAttenuation::new(DistanceModel::Custom(Arc::from([(10.0, 0.0), (2.0, 1.0)])), 1.0, 100.0, 1.0);
+    assert_eq!(custom.gain(1.0), 1.0);
+    assert!((custom.gain(6.0) - 0.5).abs() < 1e-6);
+    assert_eq!(custom.gain(50.0), 0.0);
+}
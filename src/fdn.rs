@@ -1 +1,142 @@
-
+use crate::{multi_room::CoupledReverb, ray_tracer::LateReverb};
+
+pub const N_LINES: usize = 4;
+
+// mutually prime line lengths at 48 kHz, scaled to the sample rate
+pub(crate) const LINE_LENGTHS: [usize; N_LINES] = [1031, 1327, 1523, 1783];
+
+// Feedback delay network for the late tail of a room: N_LINES delay lines mixed by a
+// Hadamard matrix. A one-pole lowpass in every line matches the decay time below 1 kHz at DC
+// and the one of the highest band at Nyquist. The left ear takes the even lines, the right
+// ear the odd ones, so both ears get decorrelated tails.
+#[derive(Debug, Clone)]
+pub struct FeedbackDelayNetwork {
+    reverb: LateReverb,
+    lines: [Vec<f32>; N_LINES],
+    positions: [usize; N_LINES],
+    // gain at DC and pole of the loop filter, per line
+    gains: [f32; N_LINES],
+    poles: [f32; N_LINES],
+    filter_states: [f32; N_LINES],
+    input_gain: f32,
+}
+
+impl FeedbackDelayNetwork {
+    // late tail of a coupled room
+    pub fn new(reverb: CoupledReverb, sample_rate: f32) -> Self {
+        // the impulse response carries the coupled share of the energy
+        FeedbackDelayNetwork::from_late_reverb(LateReverb::broadband(reverb.rt60, reverb.coupling), sample_rate)
+    }
+
+    pub fn from_late_reverb(reverb: LateReverb, sample_rate: f32) -> Self {
+        let lengths = LINE_LENGTHS.map(|length| ((length as f32 * sample_rate / 48000.0) as usize).max(1));
+        // -60 dB after the decay time
+        let (low, high) = reverb.get_decay_range();
+        let line_gain = |length: usize, decay_time: f32| 10f32.powf(-3.0 * length as f32 / (decay_time.max(f32::EPSILON) * sample_rate));
+        let gains = lengths.map(|length| line_gain(length, low));
+        let poles = std::array::from_fn(|i| {
+            let high_gain = line_gain(lengths[i], high);
+            (gains[i] - high_gain) / (gains[i] + high_gain).max(f32::EPSILON)
+        });
+        // the impulse response carries the energy of the tail (at DC)
+        let mean_loop_energy = gains.iter().map(|g| g * g).sum::<f32>() / N_LINES as f32;
+        let input_gain = (reverb.energy * (1.0 - mean_loop_energy) / N_LINES as f32).sqrt();
+        Self {
+            reverb,
+            lines: lengths.map(|length| vec![0.0; length]),
+            positions: [0; N_LINES],
+            gains,
+            poles,
+            filter_states: [0.0; N_LINES],
+            input_gain,
+        }
+    }
+
+    pub fn get_late_reverb(&self) -> LateReverb {
+        self.reverb
+    }
+
+    // continues the tail of the previous network (built for the same sample rate) with the
+    // decay of this one, the previous network gets the empty lines
+    pub fn take_tail(&mut self, previous: &mut FeedbackDelayNetwork) {
+        if self.lines.iter().zip(previous.lines.iter()).all(|(a, b)| a.len() == b.len()) {
+            std::mem::swap(&mut self.lines, &mut previous.lines);
+            std::mem::swap(&mut self.positions, &mut previous.positions);
+            std::mem::swap(&mut self.filter_states, &mut previous.filter_states);
+        }
+    }
+
+    // renders one block of the mono input and adds it to the interleaved stereo output
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        for (x, frame) in input.iter().zip(output.chunks_exact_mut(2)) {
+            let y: [f32; N_LINES] = std::array::from_fn(|i| self.lines[i][self.positions[i]]);
+            frame[0] += (y[0] + y[2]) * std::f32::consts::SQRT_2;
+            frame[1] += (y[1] + y[3]) * std::f32::consts::SQRT_2;
+            // normalized 4 x 4 Hadamard matrix
+            let feedback = [
+                y[0] + y[1] + y[2] + y[3],
+                y[0] - y[1] + y[2] - y[3],
+                y[0] + y[1] - y[2] - y[3],
+                y[0] - y[1] - y[2] + y[3],
+            ];
+            for i in 0..N_LINES {
+                let pole = self.poles[i];
+                self.filter_states[i] = (1.0 - pole) * self.gains[i] * 0.5 * feedback[i] + pole * self.filter_states[i];
+                let line = &mut self.lines[i];
+                line[self.positions[i]] = x * self.input_gain + self.filter_states[i];
+                self.positions[i] = (self.positions[i] + 1) % line.len();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fdn_decay_and_energy() {
+    let sample_rate = 16000.0;
+    let reverb = CoupledReverb {
+        room_idx: 0,
+        rt60: 0.5,
+        coupling: 0.2,
+    };
+    let mut fdn = FeedbackDelayNetwork::new(reverb, sample_rate);
+    let n_samples = sample_rate as usize;
+    let mut input = vec![0.0; n_samples];
+    input[0] = 1.0;
+    let mut output = vec![0.0; 2 * n_samples];
+    fdn.process(&input, &mut output);
+
+    let energy = |frames: &[f32]| frames.iter().map(|s| s * s).sum::<f32>() / 2.0;
+    assert!((energy(&output) - reverb.coupling).abs() < 0.5 * reverb.coupling);
+    // 60 dB per rt60, the second quarter second is about 30 dB weaker than the first
+    let quarter = 2 * n_samples / 4;
+    let decay = 10.0 * (energy(&output[..quarter]) / energy(&output[quarter..2 * quarter])).log10();
+    assert!((decay - 30.0).abs() < 6.0, "{decay}");
+}
+
+#[test]
+fn test_fdn_frequency_dependent_decay() {
+    let sample_rate = 16000.0;
+    let mut decay_times = [1.0; crate::biquad::OCTAVE_BAND_CENTRES.len()];
+    *decay_times.last_mut().unwrap() = 0.2;
+    let mut fdn = FeedbackDelayNetwork::from_late_reverb(LateReverb { decay_times, energy: 0.5 }, sample_rate);
+    let n_samples = sample_rate as usize;
+    let mut input = vec![0.0; n_samples];
+    input[0] = 1.0;
+    let mut output = vec![0.0; 2 * n_samples];
+    fdn.process(&input, &mut output);
+
+    // the tail loses its high frequencies: the share of the first difference (a highpass)
+    // in the energy falls over time
+    let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+    let high_share = |signal: &[f32]| {
+        let difference: f32 = signal.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum();
+        difference / signal.iter().map(|s| s * s).sum::<f32>()
+    };
+    let quarter = n_samples / 4;
+    assert!(high_share(&left[quarter..2 * quarter]) < 0.5 * high_share(&left[..quarter]));
+    // once the high frequencies are gone, the tail decays by 60 dB per second (15 dB per
+    // quarter second)
+    let energy = |frames: &[f32]| frames.iter().map(|s| s * s).sum::<f32>();
+    let decay = 10.0 * (energy(&left[quarter..2 * quarter]) / energy(&left[2 * quarter..3 * quarter])).log10();
+    assert!((decay - 15.0).abs() < 3.0, "{decay}");
+}
@@ -0,0 +1,317 @@
+use std::f32::consts::PI;
+
+use nalgebra::Point3;
+
+use crate::{biquad::LinkwitzRileyFilterbank, image_source_method::ISMRoom};
+
+// Courant number of the 7-point scheme at the stability limit, 1 / sqrt(3)
+const COURANT: f32 = 0.577_350_27;
+
+// grid points per wavelength below which the dispersion error stays acceptable
+const POINTS_PER_WAVELENGTH: f32 = 10.0;
+
+// Room discretized into cubic cells. Cells are air (None) or solid with an absorption
+// coefficient, the faces of the grid are walls with the absorption of the room boundaries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelGrid {
+    origin: Point3<f32>,
+    spacing: f32,
+    size: [usize; 3],
+    cells: Vec<Option<f32>>,
+    // absorption of the grid faces, [axis][lower, upper]
+    walls: [[f32; 2]; 3],
+}
+
+impl VoxelGrid {
+    // empty grid of the given size, all cells air
+    pub fn new(origin: Point3<f32>, spacing: f32, size: [usize; 3], wall_absorption: f32) -> Self {
+        Self {
+            origin,
+            spacing,
+            size,
+            cells: vec![None; size.iter().product()],
+            walls: [[wall_absorption; 2]; 3],
+        }
+    }
+
+    // shoebox of the room, the boundary materials are taken as absorption coefficients
+    pub fn from_room(room: &ISMRoom, spacing: f32) -> Self {
+        let (min, max) = room.get_bounds();
+        let size = [0, 1, 2].map(|axis| (((max[axis] - min[axis]) / spacing).round() as usize).max(1));
+        let mut grid = VoxelGrid::new(min, spacing, size, 0.0);
+        for boundary in room.get_boundaries() {
+            let axis = boundary.get_axis();
+            let side = (boundary.get_location() >= max[axis]) as usize;
+            grid.walls[axis][side] = boundary.get_material().clamp(0.0, 1.0);
+        }
+        grid
+    }
+
+    // grid with solid cells where solid(cell centre) returns an absorption coefficient
+    pub fn from_fn(
+        origin: Point3<f32>,
+        spacing: f32,
+        size: [usize; 3],
+        wall_absorption: f32,
+        solid: impl Fn(&Point3<f32>) -> Option<f32>,
+    ) -> Self {
+        let mut grid = VoxelGrid::new(origin, spacing, size, wall_absorption);
+        for z in 0..size[2] {
+            for y in 0..size[1] {
+                for x in 0..size[0] {
+                    let idx = grid.index([x, y, z]);
+                    grid.cells[idx] = solid(&grid.position([x, y, z]));
+                }
+            }
+        }
+        grid
+    }
+
+    pub fn get_spacing(&self) -> f32 {
+        self.spacing
+    }
+
+    pub fn get_size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    pub fn set_solid(&mut self, cell: [usize; 3], absorption: Option<f32>) {
+        let idx = self.index(cell);
+        self.cells[idx] = absorption;
+    }
+
+    pub fn is_air(&self, cell: [usize; 3]) -> bool {
+        self.cells[self.index(cell)].is_none()
+    }
+
+    fn index(&self, cell: [usize; 3]) -> usize {
+        cell[0] + self.size[0] * (cell[1] + self.size[1] * cell[2])
+    }
+
+    // centre of a cell
+    pub fn position(&self, cell: [usize; 3]) -> Point3<f32> {
+        self.origin + nalgebra::Vector3::from(cell.map(|c| (c as f32 + 0.5) * self.spacing))
+    }
+
+    // cell containing the position, clamped to the grid
+    pub fn cell(&self, position: &Point3<f32>) -> [usize; 3] {
+        [0, 1, 2].map(|axis| {
+            let c = ((position[axis] - self.origin[axis]) / self.spacing).floor().max(0.0) as usize;
+            c.min(self.size[axis] - 1)
+        })
+    }
+}
+
+// Finite-difference time-domain solver of the wave equation on a VoxelGrid (7-point stencil,
+// frequency independent locally reacting boundaries). Only valid up to get_max_frequency,
+// meant for the low-frequency part of room impulse responses.
+pub struct FdtdSolver {
+    grid: VoxelGrid,
+    speed_of_sound: f32,
+    time_step: f32,
+    // number of air neighbours of every cell
+    neighbours: Vec<u8>,
+    // boundary loss term of every cell, courant / 2 * sum of the face admittances
+    losses: Vec<f32>,
+    pressure: Vec<f32>,
+    previous: Vec<f32>,
+}
+
+impl FdtdSolver {
+    pub fn new(grid: VoxelGrid, speed_of_sound: f32) -> Self {
+        let n_cells = grid.cells.len();
+        let mut neighbours = vec![0; n_cells];
+        let mut losses = vec![0.0; n_cells];
+        let size = grid.size;
+        for z in 0..size[2] {
+            for y in 0..size[1] {
+                for x in 0..size[0] {
+                    let cell = [x, y, z];
+                    let idx = grid.index(cell);
+                    if grid.cells[idx].is_some() {
+                        continue;
+                    }
+                    for axis in 0..3 {
+                        for side in 0..2 {
+                            let absorption = match neighbour(&cell, axis, side, &size) {
+                                Some(n) => match grid.cells[grid.index(n)] {
+                                    Some(absorption) => absorption,
+                                    None => {
+                                        neighbours[idx] += 1;
+                                        continue;
+                                    }
+                                },
+                                None => grid.walls[axis][side],
+                            };
+                            losses[idx] += COURANT / 2.0 * admittance(absorption);
+                        }
+                    }
+                }
+            }
+        }
+        Self {
+            time_step: COURANT * grid.spacing / speed_of_sound,
+            grid,
+            speed_of_sound,
+            neighbours,
+            losses,
+            pressure: vec![0.0; n_cells],
+            previous: vec![0.0; n_cells],
+        }
+    }
+
+    pub fn get_grid(&self) -> &VoxelGrid {
+        &self.grid
+    }
+
+    // sample rate of the simulation (Hz)
+    pub fn get_sample_rate(&self) -> f32 {
+        1.0 / self.time_step
+    }
+
+    // highest frequency resolved with POINTS_PER_WAVELENGTH (Hz)
+    pub fn get_max_frequency(&self) -> f32 {
+        self.speed_of_sound / (POINTS_PER_WAVELENGTH * self.grid.spacing)
+    }
+
+    pub fn clear(&mut self) {
+        self.pressure.iter_mut().chain(self.previous.iter_mut()).for_each(|p| *p = 0.0);
+    }
+
+    // impulse response at the simulation rate, scaled so that the direct sound has the area
+    // 1 / r. The source is a Hann pulse that band limits the response to about
+    // 2 * get_max_frequency, the response is shifted by its delay.
+    pub fn simulate(&mut self, source: &Point3<f32>, receiver: &Point3<f32>, duration: f32) -> Vec<f32> {
+        self.clear();
+        let source_idx = self.grid.index(self.grid.cell(source));
+        let receiver_idx = self.grid.index(self.grid.cell(receiver));
+        let n_steps = (duration / self.time_step).ceil() as usize;
+        let pulse = hann_pulse((self.get_sample_rate() / self.get_max_frequency()).ceil() as usize | 1);
+        let pulse_delay = pulse.len() / 2;
+        // a point source of unit strength spreads over the cell volume
+        let strength = 4.0 * PI * COURANT * COURANT / self.grid.spacing;
+
+        let mut response = Vec::with_capacity(n_steps);
+        for n in 0..n_steps + pulse_delay {
+            if let Some(g) = pulse.get(n) {
+                self.pressure[source_idx] += strength * g;
+            }
+            if n >= pulse_delay {
+                response.push(self.pressure[receiver_idx]);
+            }
+            self.step();
+        }
+        response
+    }
+
+    // simulate, resampled to the given rate. Above get_max_frequency the response is not
+    // valid and has to be removed, e.g. by crossover_responses.
+    pub fn impulse_response(&mut self, source: &Point3<f32>, receiver: &Point3<f32>, duration: f32, sample_rate: f32) -> Vec<f32> {
+        let response = self.simulate(source, receiver, duration);
+        let ratio = self.get_sample_rate() / sample_rate;
+        let length = (duration * sample_rate) as usize;
+        (0..length)
+            .map(|i| {
+                let t = i as f32 * ratio;
+                let (idx, frac) = (t.floor() as usize, t.fract());
+                let a = response.get(idx).copied().unwrap_or(0.0);
+                let b = response.get(idx + 1).copied().unwrap_or(0.0);
+                // keeps the spectral level when going to the higher rate
+                (a * (1.0 - frac) + b * frac) * ratio
+            })
+            .collect()
+    }
+
+    fn step(&mut self) {
+        let [nx, ny, nz] = self.grid.size;
+        let lambda_2 = COURANT * COURANT;
+        // the new pressure overwrites the previous one, the neighbours only read the current
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let idx = x + nx * (y + ny * z);
+                    if self.grid.cells[idx].is_some() {
+                        continue;
+                    }
+                    let p = &self.pressure;
+                    let mut sum = 0.0;
+                    if x > 0 { sum += p[idx - 1]; }
+                    if x + 1 < nx { sum += p[idx + 1]; }
+                    if y > 0 { sum += p[idx - nx]; }
+                    if y + 1 < ny { sum += p[idx + nx]; }
+                    if z > 0 { sum += p[idx - nx * ny]; }
+                    if z + 1 < nz { sum += p[idx + nx * ny]; }
+                    let k = self.neighbours[idx] as f32;
+                    let loss = self.losses[idx];
+                    self.previous[idx] = ((2.0 - k * lambda_2) * p[idx] + lambda_2 * sum
+                        - (1.0 - loss) * self.previous[idx])
+                        / (1.0 + loss);
+                }
+            }
+        }
+        std::mem::swap(&mut self.pressure, &mut self.previous);
+    }
+}
+
+fn neighbour(cell: &[usize; 3], axis: usize, side: usize, size: &[usize; 3]) -> Option<[usize; 3]> {
+    let mut n = *cell;
+    match side {
+        0 => n[axis] = n[axis].checked_sub(1)?,
+        _ if n[axis] + 1 < size[axis] => n[axis] += 1,
+        _ => return None,
+    }
+    Some(n)
+}
+
+// unit area Hann window of odd length
+fn hann_pulse(length: usize) -> Vec<f32> {
+    let window: Vec<f32> = (0..length)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * (i + 1) as f32 / (length + 1) as f32).cos())
+        .collect();
+    let area: f32 = window.iter().sum();
+    window.iter().map(|w| w / area).collect()
+}
+
+// specific admittance of a wall with the given (normal incidence) absorption coefficient
+fn admittance(absorption: f32) -> f32 {
+    let reflection = (1.0 - absorption.clamp(0.0, 1.0)).sqrt();
+    (1.0 - reflection) / (1.0 + reflection)
+}
+
+// full-band response: low passed wave-based part plus high passed geometric part
+// (Linkwitz-Riley, so the crossover sums flat)
+pub fn crossover_responses(low: &[f32], high: &[f32], crossover: f32, sample_rate: f32) -> Vec<f32> {
+    let mut low_filter = LinkwitzRileyFilterbank::new(&[crossover], sample_rate);
+    let mut high_filter = LinkwitzRileyFilterbank::new(&[crossover], sample_rate);
+    let mut bands = [0.0; 2];
+    (0..low.len().max(high.len()))
+        .map(|i| {
+            low_filter.process_sample(low.get(i).copied().unwrap_or(0.0), &mut bands);
+            let low_band = bands[0];
+            high_filter.process_sample(high.get(i).copied().unwrap_or(0.0), &mut bands);
+            low_band + bands[1]
+        })
+        .collect()
+}
+
+#[test]
+fn test_free_field_level() {
+    use nalgebra::Vector3;
+
+    let room = ISMRoom::new(Vector3::new(4.0, 4.0, 4.0), [0.2; 6], 343.0);
+    let grid = VoxelGrid::from_room(&room, 0.1);
+    assert_eq!(grid.get_size(), [40, 40, 40]);
+    let mut solver = FdtdSolver::new(grid, 343.0);
+    assert!((solver.get_max_frequency() - 343.0).abs() < 1.0);
+
+    let (source, receiver) = (Point3::new(2.0, 2.0, 2.0), Point3::new(3.0, 2.0, 2.0));
+    let response = solver.simulate(&source, &receiver, 0.01);
+
+    // the direct sound ends before the first reflection (2 m further) arrives
+    let direct_end = (1.5 / 343.0 * solver.get_sample_rate()) as usize;
+    let peak = (0..direct_end).max_by(|a, b| response[*a].abs().total_cmp(&response[*b].abs())).unwrap();
+    assert!((peak as f32 / solver.get_sample_rate() - 1.0 / 343.0).abs() < 5e-4);
+    let direct: f32 = response[..direct_end].iter().sum();
+    assert!((direct - 1.0).abs() < 0.05, "{direct}");
+    assert!(response.iter().all(|p| p.is_finite()));
+}
@@ -9,6 +9,7 @@ use kdtree;
 use nohash_hasher::NoHashHasher;
 
 use crate::readwav;
+use crate::simd::SplitComplex;
 
 #[allow(unused)]
 #[derive(Clone)]
@@ -71,8 +72,8 @@ pub enum MonoFilterType {
 #[allow(unused)]
 #[derive(Debug, Clone)]
 pub struct BinauralFilter {
-    pub data_f_l: Vec<Vec<Complex<f32>>>,
-    pub data_f_r: Vec<Vec<Complex<f32>>>,
+    pub data_f_l: Vec<SplitComplex>,
+    pub data_f_r: Vec<SplitComplex>,
     filter_type: BinauralFilterType,
     n_segments: usize,
 } 
@@ -100,8 +101,8 @@ impl BinauralFilter {
         }
 
         // check for small filters
-        let mut data_t_l = pad_zeros(& data_t[0], r);
-        let mut data_t_r= pad_zeros(& data_t[1], r);
+        let mut data_t_l = pad_zeros(& data_t[0], (buffer_size - r) % buffer_size);
+        let mut data_t_r= pad_zeros(& data_t[1], (buffer_size - r) % buffer_size);
     
         if data_t_length < buffer_size {
             data_t_l = pad_zeros(&data_t_l, buffer_size-data_t_length);
@@ -133,8 +134,8 @@ impl BinauralFilter {
         // scaling of fft coefficients
         
         Self { 
-            data_f_l,
-            data_f_r,
+            data_f_l: data_f_l.iter().map(|d| SplitComplex::from_interleaved(d)).collect(),
+            data_f_r: data_f_r.iter().map(|d| SplitComplex::from_interleaved(d)).collect(),
             filter_type,
             n_segments
         }
@@ -155,6 +156,10 @@ impl BinauralFilter {
         self.n_segments
     }
 
+    pub fn get_filter_type(&self) -> BinauralFilterType {
+        self.filter_type
+    }
+
 }
 
 
@@ -170,7 +175,7 @@ impl MonoFilter {
                 n_segments+=1;
             }
             // check for small filters
-            let mut data_t = pad_zeros(& data_t, r);
+            let mut data_t = pad_zeros(& data_t, (buffer_size - r) % buffer_size);
             
             if data_t_length < buffer_size {
                 data_t = pad_zeros(&data_t, buffer_size-data_t_length);
@@ -280,6 +285,7 @@ impl FilterStorage {
             
             angles.add(azel, id);
             storage.insert(id, binaural_filter);
+            id += 1;
         }
         
         let available: bool = !storage.is_empty();
@@ -287,6 +293,7 @@ impl FilterStorage {
 
         // insert default filters
         angles.add([666.0, 420.0], 0);
+        storage.insert(0, BinauralFilter::from_vec(vec![0.0; 384], vec![0.0; 384], fft, BinauralFilterType::DirectSound, blocksize));
     
   
         (Self {
@@ -296,6 +303,64 @@ impl FilterStorage {
             angles: angles
         })
     }
+
+    // Filter set of arbitrary size and filter length, e.g. measured BRIRs. Stereo impulse responses
+    // (filter_length samples left, then right, f32 little endian) are stored back to back in filterpath,
+    // the matching angle pairs (degrees) in anglepath. Ids start at 0 in file order.
+    pub fn from_files(filterpath: &str, anglepath: &str, filter_length: usize, filter_type: BinauralFilterType, fft: &mut FFTManager, blocksize: usize) -> io::Result<(Self, FilterTree)> {
+        let mut angles: kdtree::KdTree<f32, usize, [f32; 2]> = kdtree::KdTree::new(2);
+        let mut storage: HashMap<usize, BinauralFilter, BuildHasherDefault<NoHashHasher<usize>>> = HashMap::with_hasher(BuildHasherDefault::default());
+
+        let mut filter_buf_reader = BufReader::new(File::open(filterpath)?);
+        let mut angles_buf_reader = BufReader::new(File::open(anglepath)?);
+        let mut id: usize = 0;
+        loop {
+            let mut azel: [f32; 2] = [0.0f32, 0.0f32];
+            azel[0] = match angles_buf_reader.read_f32::<LittleEndian>() {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            azel[1] = angles_buf_reader.read_f32::<LittleEndian>()?;
+            let mut left_channel: Vec<f32> = Vec::with_capacity(filter_length);
+            let mut right_channel: Vec<f32> = Vec::with_capacity(filter_length);
+            for _ in 0..filter_length {
+                left_channel.push(filter_buf_reader.read_f32::<LittleEndian>()?);
+            }
+            for _ in 0..filter_length {
+                right_channel.push(filter_buf_reader.read_f32::<LittleEndian>()?);
+            }
+            let binaural_filter: BinauralFilter = BinauralFilter::from_vec(left_channel, right_channel, fft, filter_type, blocksize);
+
+            angles.add(azel, id).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}")))?;
+            storage.insert(id, binaural_filter);
+            id += 1;
+        }
+        if storage.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no filters found in {filterpath}")));
+        }
+
+        Ok((Self {
+             storage, available: true
+            },
+        FilterTree {
+            angles
+        }))
+    }
+
+    // filters that are already in memory, with their angle pairs (degrees). Ids start at 0 in
+    // the given order.
+    pub fn from_filters(filters: Vec<([f32; 2], BinauralFilter)>) -> (Self, FilterTree) {
+        let mut angles: kdtree::KdTree<f32, usize, [f32; 2]> = kdtree::KdTree::new(2);
+        let mut storage: HashMap<usize, BinauralFilter, BuildHasherDefault<NoHashHasher<usize>>> = HashMap::with_hasher(BuildHasherDefault::default());
+        for (id, (azel, filter)) in filters.into_iter().enumerate() {
+            angles.add(azel, id).unwrap();
+            storage.insert(id, filter);
+        }
+        let available = !storage.is_empty();
+        (Self { storage, available }, FilterTree { angles })
+    }
+
     fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
     where P: AsRef<Path>, {
         let file = File::open(filename)?;
@@ -315,6 +380,10 @@ impl FilterStorage {
     pub fn get_n_stereo_segments(&self, filter_type: BinauralFilterType) -> usize {    
                 self.storage.values().next().unwrap().get_n_segments()
     }
+
+    pub fn get_max_n_stereo_segments(&self) -> usize {
+        self.storage.values().map(|f| f.get_n_segments()).max().unwrap_or(0)
+    }
 }
 
 
@@ -6,10 +6,22 @@ use protobuf::reflect;
 use strum_macros::EnumIter;
 
 use crate::{
+    air_absorption::Atmosphere,
     audioSceneHandlerData::{Listener, Scene_data, Transform},
+    diffraction::{find_diffraction_paths, DiffractionPath},
+    obstacle::{Obstacle, Occlusion},
+    ray_tracer::{EnergyHistogram, RayTracer, RayTracerSettings},
+    reflection_lod::{image_source_paths, ReflectionPath},
+    transmission::{TransmissionMaterial, WallTransmission},
     scene::{get_position, get_quaternion},
 };
 
+// scattering coefficient of the boundaries unless set otherwise
+const DEFAULT_SCATTERING: f32 = 0.1;
+
+// smaller movements of sources and listener (m) keep the image sources and the cached paths
+pub const MOVEMENT_THRESHOLD: f32 = 0.01;
+
 static N_IS_INDEX_RANGES: [(usize, usize); 7] = [
     (0, 0),
     (0, 6),
@@ -37,6 +49,9 @@ pub struct Boundary {
     direction: CardinalDirection,
     location: f32,
     material: f32, // needs an implementation
+    transmission: TransmissionMaterial,
+    // share of the reflected energy that is scattered diffusely (ray tracer only)
+    scattering: f32,
 }
 
 impl Boundary {
@@ -45,6 +60,8 @@ impl Boundary {
             direction,
             material,
             location,
+            transmission: TransmissionMaterial::default(),
+            scattering: DEFAULT_SCATTERING,
         }
     }
     pub fn get_direction(&self) -> CardinalDirection {
@@ -53,12 +70,27 @@ impl Boundary {
     pub fn get_material(&self) -> f32 {
         self.material
     }
+    pub fn get_location(&self) -> f32 {
+        self.location
+    }
+    pub fn get_transmission(&self) -> TransmissionMaterial {
+        self.transmission
+    }
+    pub fn get_scattering(&self) -> f32 {
+        self.scattering
+    }
+    // coordinate axis the boundary is perpendicular to
+    pub fn get_axis(&self) -> usize {
+        reflection_axis(self.direction)
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct ISMRoom {
     dimensions: Vector3<f32>,
     boundaries: [Boundary; 6],
+    speed_of_sound: f32,
+    atmosphere: Atmosphere,
 }
 
 impl ISMRoom {
@@ -74,8 +106,27 @@ impl ISMRoom {
         Self {
             boundaries,
             dimensions,
+            speed_of_sound,
+            atmosphere: Atmosphere::default(),
         }
     }
+    // speed of sound derived from the temperature of the atmosphere
+    pub fn with_atmosphere(dimensions: Vector3<f32>, materials: [f32; 6], atmosphere: Atmosphere) -> Self {
+        let mut room = ISMRoom::new(dimensions, materials, atmosphere.speed_of_sound());
+        room.atmosphere = atmosphere;
+        room
+    }
+    // room spanning the box from min to max (coordinates used by reflect), one material per
+    // boundary in the order of get_boundaries
+    pub fn from_bounds(min: Point3<f32>, max: Point3<f32>, materials: [f32; 6], speed_of_sound: f32) -> Self {
+        let extent = max - min;
+        let mut room = ISMRoom::new(Vector3::new(extent[1], extent[2], extent[0]), materials, speed_of_sound);
+        for (boundary, material) in room.boundaries.iter_mut().zip(materials) {
+            boundary.location += min[reflection_axis(boundary.direction)];
+            boundary.material = material;
+        }
+        room
+    }
     pub fn from_scene_data(scene_data: &Scene_data) -> Self {
         let dimensions = Vector3::from_vec(vec![
             scene_data.room.width,
@@ -83,12 +134,116 @@ impl ISMRoom {
             scene_data.room.height,
         ]);
         let materials = [0.0f32; 6];
-        let speed_of_sound = 343.0f32; // CHANGE THIS
-        ISMRoom::new(dimensions, materials, speed_of_sound)
+        // Scene_data carries no atmospheric conditions, assume the default atmosphere
+        ISMRoom::with_atmosphere(dimensions, materials, Atmosphere::default())
     }
     pub fn get_boundaries(&self) -> [Boundary; 6] {
         self.boundaries
     }
+    pub fn get_speed_of_sound(&self) -> f32 {
+        self.speed_of_sound
+    }
+    pub fn get_atmosphere(&self) -> Atmosphere {
+        self.atmosphere
+    }
+    pub fn set_atmosphere(&mut self, atmosphere: Atmosphere) {
+        self.atmosphere = atmosphere;
+        self.speed_of_sound = atmosphere.speed_of_sound();
+    }
+    pub fn set_transmission_material(&mut self, direction: CardinalDirection, material: TransmissionMaterial) {
+        for boundary in self.boundaries.iter_mut().filter(|b| b.direction == direction) {
+            boundary.transmission = material;
+        }
+    }
+    pub fn set_scattering(&mut self, direction: CardinalDirection, scattering: f32) {
+        for boundary in self.boundaries.iter_mut().filter(|b| b.direction == direction) {
+            boundary.scattering = scattering.clamp(0.0, 1.0);
+        }
+    }
+    // corners of the room in the coordinates used by reflect
+    pub fn get_bounds(&self) -> (Point3<f32>, Point3<f32>) {
+        let mut min = Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut max = Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for boundary in self.boundaries.iter() {
+            let axis = reflection_axis(boundary.direction);
+            min[axis] = min[axis].min(boundary.location);
+            max[axis] = max[axis].max(boundary.location);
+        }
+        (min, max)
+    }
+    pub fn get_volume(&self) -> f32 {
+        let (min, max) = self.get_bounds();
+        (max - min).product()
+    }
+    // equivalent absorption area (m²), the boundary materials are taken as absorption coefficients
+    pub fn get_absorption_area(&self) -> f32 {
+        let (min, max) = self.get_bounds();
+        let extent = max - min;
+        self.boundaries
+            .iter()
+            .map(|boundary| {
+                let axis = reflection_axis(boundary.direction);
+                extent.product() / extent[axis].max(f32::EPSILON) * boundary.material
+            })
+            .sum()
+    }
+    // Pressure reflection gain sqrt(1 - absorption) of all walls between the room and an image
+    // source. Along every axis the image lies |k| rooms away, the path hits the wall on the
+    // side of the image first and then alternates.
+    pub fn get_reflection_gain(&self, image_source: &Point3<f32>) -> f32 {
+        let (min, max) = self.get_bounds();
+        let reflection = |axis: usize, location: f32| {
+            self.boundaries
+                .iter()
+                .find(|b| reflection_axis(b.direction) == axis && b.location == location)
+                .map_or(1.0, |b| (1.0 - b.material.clamp(0.0, 1.0)).sqrt())
+        };
+        let mut gain = 1.0;
+        for axis in 0..3 {
+            let extent = max[axis] - min[axis];
+            if extent <= 0.0 {
+                continue;
+            }
+            let room_idx = ((image_source[axis] - min[axis]) / extent).floor() as i32;
+            let (first, second) = match room_idx > 0 {
+                true => (reflection(axis, max[axis]), reflection(axis, min[axis])),
+                false => (reflection(axis, min[axis]), reflection(axis, max[axis])),
+            };
+            let hits = room_idx.unsigned_abs() as i32;
+            gain *= first.powi((hits + 1) / 2) * second.powi(hits / 2);
+        }
+        gain
+    }
+    pub fn contains(&self, position: &Point3<f32>) -> bool {
+        let (min, max) = self.get_bounds();
+        (0..3).all(|axis| position[axis] >= min[axis] && position[axis] <= max[axis])
+    }
+    // closest point of the room to the position, the position itself if it is inside
+    pub fn virtual_source_position(&self, position: &Point3<f32>) -> Point3<f32> {
+        let (min, max) = self.get_bounds();
+        Point3::from(position.coords.zip_zip_map(&min.coords, &max.coords, |p, lo, hi| p.clamp(lo, hi)))
+    }
+    // transmission of a source outside the room through its nearest wall, None if the
+    // source is inside
+    pub fn get_wall_transmission(&self, position: &Point3<f32>) -> Option<WallTransmission> {
+        if self.contains(position) {
+            return None;
+        }
+        let point = self.virtual_source_position(position);
+        // the wall the source is furthest outside of
+        let excess = position - point;
+        let axis = excess.iamax();
+        let walls = self
+            .boundaries
+            .iter()
+            .filter(|b| reflection_axis(b.direction) == axis);
+        let nearest_wall = match excess[axis] > 0.0 {
+            true => walls.max_by(|a, b| a.location.total_cmp(&b.location)),
+            false => walls.min_by(|a, b| a.location.total_cmp(&b.location)),
+        }
+        .unwrap();
+        Some(WallTransmission::new(point, &nearest_wall.transmission))
+    }
 }
 
 #[derive(Debug, Default)]
@@ -133,6 +288,12 @@ impl ISMSoundSource {
             reflector: CardinalDirection::NONE,
         }
     }
+    pub fn get_orientation(&self) -> Quaternion<f32> {
+        self.orientation
+    }
+    pub fn update_orientation(&mut self, new_orientation: Quaternion<f32>) {
+        self.orientation = new_orientation;
+    }
 }
 impl Source for ISMSoundSource {
     fn get_position(&self) -> Point3<f32> {
@@ -142,25 +303,70 @@ impl Source for ISMSoundSource {
         self.position = new_position;
     }
 }
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct ISMImageSource {
     position: Point3<f32>,
     reflector: CardinalDirection,
     order: usize,
+    // -1 for every axis that has been mirrored an odd number of times
+    mirror: Vector3<f32>,
+}
+impl Default for ISMImageSource {
+    fn default() -> Self {
+        Self {
+            position: Point3::origin(),
+            reflector: CardinalDirection::NONE,
+            order: 0,
+            mirror: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
 }
 impl ISMImageSource {
     pub fn new(order: usize, position: Point3<f32>, reflector: CardinalDirection) -> Self {
+        let mut mirror = Vector3::new(1.0, 1.0, 1.0);
+        if reflector != CardinalDirection::NONE {
+            mirror[reflection_axis(reflector)] = -1.0;
+        }
         Self {
             position,
             reflector,
             order,
+            mirror,
         }
     }
 
     pub fn init(&mut self, new_position: Point3<f32>, reflector: CardinalDirection, order: usize) {
+        self.init_with_mirror(new_position, reflector, order, Vector3::new(1.0, 1.0, 1.0));
+    }
+
+    // parent_mirror: mirror signs of the (image) source this one is reflected from
+    pub fn init_with_mirror(
+        &mut self,
+        new_position: Point3<f32>,
+        reflector: CardinalDirection,
+        order: usize,
+        parent_mirror: Vector3<f32>,
+    ) {
         self.position = new_position;
         self.reflector = reflector;
         self.order = order;
+        self.mirror = parent_mirror;
+        self.mirror[reflection_axis(reflector)] *= -1.0;
+    }
+
+    pub fn get_mirror(&self) -> Vector3<f32> {
+        self.mirror
+    }
+
+    // direction (world frame) in which the path to the listener left the real source: the path
+    // leaves the image source towards the listener, undoing the reflections gives the direction
+    // at the real source
+    pub fn get_emission_direction(&self, listener_position: &Point3<f32>) -> Vector3<f32> {
+        (listener_position - self.position).component_mul(&self.mirror)
+    }
+
+    pub fn get_order(&self) -> usize {
+        self.order
     }
 
     pub fn get_reflector(&self) -> CardinalDirection {
@@ -188,6 +394,12 @@ pub struct ISMAcousticScene {
     image_sources: Vec<Vec<ISMImageSource>>,
     listener: ISMListener,
     max_order: usize,
+    obstacles: Vec<Obstacle>,
+    // source position the image sources were computed for
+    image_source_origins: Vec<Option<Point3<f32>>>,
+    // validated reflection paths and the (source, listener) positions they belong to, None if
+    // outdated
+    path_cache: Vec<Option<(Point3<f32>, Point3<f32>, Vec<ReflectionPath>)>>,
 }
 
 impl ISMAcousticScene {
@@ -206,8 +418,10 @@ impl ISMAcousticScene {
         for (i, snd_src) in sound_sources.iter().enumerate() {
             // first order
             for _ in 0..ism_max_order - (ism_max_order - 1) {
+                // sources outside the room excite it from their transmission point
+                let position = room.virtual_source_position(&snd_src.get_position());
                 for (n, boundary) in room.get_boundaries().iter().enumerate() {
-                    let new_pos = reflect(snd_src, boundary);
+                    let new_pos = reflect_position(position, boundary);
                     image_sources[i][n].init(new_pos, boundary.get_direction(), 1);
                 }
             }
@@ -217,10 +431,12 @@ impl ISMAcousticScene {
                     for boundary in room.get_boundaries().iter() {
                         if boundary.get_direction() != image_sources[i][is_idx].get_reflector() {
                             let new_position = reflect(&image_sources[i][is_idx], boundary);
-                            image_sources[i][n].init(
+                            let parent_mirror = image_sources[i][is_idx].get_mirror();
+                            image_sources[i][n].init_with_mirror(
                                 new_position,
                                 boundary.get_direction(),
                                 order + 1,
+                                parent_mirror,
                             );
                             n += 1;
                         }
@@ -229,17 +445,24 @@ impl ISMAcousticScene {
             }
         }
         Self {
+            image_source_origins: sound_sources.iter().map(|s| Some(s.get_position())).collect(),
+            path_cache: vec![None; n_sources],
             sound_sources,
             image_sources,
             room,
             listener,
             max_order: ism_max_order,
+            obstacles: Vec::new(),
         }
     }
     pub fn default() -> Self {
         let listener: ISMListener =
             ISMListener::new(Point3::from_slice(&[0.0, 0.0, 0.0]), Quaternion::zero());
-        let room: ISMRoom = ISMRoom::new(Vector3::from_vec(vec![0.0, 0.0, 0.0]), [0.0; 6], 343.0);
+        let room: ISMRoom = ISMRoom::with_atmosphere(
+            Vector3::from_vec(vec![0.0, 0.0, 0.0]),
+            [0.0; 6],
+            Atmosphere::default(),
+        );
         let mut sound_sources: Vec<ISMSoundSource> = Vec::new();
         sound_sources.push(ISMSoundSource::new(
             Point3::from_slice(&[0.0, 0.0, 0.0]),
@@ -252,6 +475,9 @@ impl ISMAcousticScene {
             sound_sources,
             image_sources,
             max_order: 2,
+            obstacles: Vec::new(),
+            image_source_origins: vec![None],
+            path_cache: vec![None],
         }
     }
 
@@ -259,20 +485,27 @@ impl ISMAcousticScene {
         let room = ISMRoom::from_scene_data(scene_data);
         let listener: ISMListener = ISMListener::from_scene_data(scene_data);
         let mut sound_sources = Vec::new();
-        for source_transform in scene_data.sources.transforms {
+        for source_transform in scene_data.sources.transforms.iter() {
             sound_sources.push(ISMSoundSource::from_transform(&source_transform));
         }
 
         ISMAcousticScene::new(room, listener, sound_sources, 2)
     }
 
+    // image sources are only recomputed for sources that moved further than MOVEMENT_THRESHOLD
     pub fn update(&mut self, new_source_positions: Vec<Point3<f32>>) {
         for (i, source) in self.sound_sources.iter_mut().enumerate() {
             source.update_position(new_source_positions[i]);
+            let origin = &mut self.image_source_origins[i];
+            if origin.is_some_and(|o| (o - new_source_positions[i]).norm() < MOVEMENT_THRESHOLD) {
+                continue;
+            }
+            *origin = Some(new_source_positions[i]);
             // source -> first order
             for _ in 0..self.max_order - (self.max_order - 1) {
+                let position = self.room.virtual_source_position(&source.get_position());
                 for (n, boundary) in self.room.get_boundaries().iter().enumerate() {
-                    let new_position = reflect(source, boundary);
+                    let new_position = reflect_position(position, boundary);
                     self.image_sources[i][n].update_position(new_position);
                 }
             }
@@ -294,40 +527,208 @@ impl ISMAcousticScene {
     }
 
     pub fn from_protobuf_scene(&mut self, scene_data: &Scene_data) {
+        self.listener = ISMListener::from_scene_data(scene_data);
         let mut new_positions: Vec<Point3<f32>> = Vec::new();
-        for s in scene_data.sources.transforms.iter() {
+        for (i, s) in scene_data.sources.transforms.iter().enumerate() {
             new_positions.push(Point3::new(s.position.x, s.position.y, s.position.z));
+            if let Some(source) = self.sound_sources.get_mut(i) {
+                source.update_orientation(get_quaternion(s));
+            }
         }
         self.update(new_positions);
     }
-}
 
-fn reflect(source: &impl Source, boundary: &Boundary) -> Point3<f32> {
-    let mut new_position = source.get_position();
-    match boundary.get_direction() {
-        CardinalDirection::EAST => {
-            new_position[1] = 2.0 * boundary.location - new_position[1];
-        }
-        CardinalDirection::NORTH => {
-            new_position[0] = 2.0 * boundary.location - new_position[0];
-        }
-        CardinalDirection::SOUTH => {
-            new_position[0] = -new_position[0];
-        }
-        CardinalDirection::WEST => {
-            new_position[1] = -new_position[1];
-        }
-        CardinalDirection::FLOOR => {
-            new_position[2] = -new_position[2];
+    // emission directions (world frame) of the image source paths of a source, see
+    // ISMImageSource::get_emission_direction
+    pub fn get_emission_directions(&self, source_idx: usize) -> Vec<Vector3<f32>> {
+        self.image_sources[source_idx]
+            .iter()
+            .map(|is| is.get_emission_direction(&self.listener.position))
+            .collect()
+    }
+
+    pub fn set_atmosphere(&mut self, atmosphere: Atmosphere) {
+        self.room.set_atmosphere(atmosphere);
+        self.invalidate_paths();
+    }
+
+    pub fn set_listener(&mut self, listener: ISMListener) {
+        self.listener = listener;
+    }
+
+    pub fn get_room(&self) -> &ISMRoom {
+        &self.room
+    }
+
+    pub fn get_source_position(&self, source_idx: usize) -> Point3<f32> {
+        self.sound_sources[source_idx].get_position()
+    }
+
+    pub fn get_image_source_positions(&self, source_idx: usize) -> Vec<Point3<f32>> {
+        self.image_sources[source_idx].iter().map(|is| is.get_position()).collect()
+    }
+
+    pub fn get_n_sources(&self) -> usize {
+        self.sound_sources.len()
+    }
+
+    // true if the source or the listener moved further than MOVEMENT_THRESHOLD since the
+    // paths of the source were validated, or the geometry changed
+    pub fn needs_update(&self, source_idx: usize) -> bool {
+        match &self.path_cache[source_idx] {
+            Some((source, listener, _)) => {
+                (source - self.sound_sources[source_idx].get_position()).norm() >= MOVEMENT_THRESHOLD
+                    || (listener - self.listener.position).norm() >= MOVEMENT_THRESHOLD
+            }
+            None => true,
         }
-        CardinalDirection::CEILING => {
-            new_position[2] = 2.0 * boundary.location - new_position[2];
+    }
+
+    // image source paths of a source, recomputed only if needs_update
+    pub fn get_reflection_paths(&mut self, source_idx: usize) -> &[ReflectionPath] {
+        if self.needs_update(source_idx) {
+            let paths = image_source_paths(self, source_idx, &self.listener.position);
+            self.set_reflection_paths(source_idx, paths);
         }
+        &self.path_cache[source_idx].as_ref().unwrap().2
+    }
+
+    // paths from another source (baked probes) validated for the current positions
+    pub fn set_reflection_paths(&mut self, source_idx: usize, paths: Vec<ReflectionPath>) {
+        self.path_cache[source_idx] = Some((self.sound_sources[source_idx].get_position(), self.listener.position, paths));
+    }
+
+    // e.g. after changes outside the scene (portals) that affect the paths
+    pub fn invalidate_paths(&mut self) {
+        self.path_cache.iter_mut().for_each(|paths| *paths = None);
+    }
+
+    // adds an obstacle or replaces the one with the same id
+    pub fn add_obstacle(&mut self, obstacle: Obstacle) {
+        self.remove_obstacle(obstacle.get_id());
+        self.obstacles.push(obstacle);
+        self.invalidate_paths();
+    }
+
+    pub fn remove_obstacle(&mut self, id: u32) -> bool {
+        let n_obstacles = self.obstacles.len();
+        self.obstacles.retain(|o| o.get_id() != id);
+        self.invalidate_paths();
+        self.obstacles.len() != n_obstacles
+    }
+
+    pub fn get_obstacles(&self) -> &[Obstacle] {
+        &self.obstacles
+    }
+
+    pub fn take_obstacles(&mut self) -> Vec<Obstacle> {
+        std::mem::take(&mut self.obstacles)
+    }
+
+    pub fn set_obstacles(&mut self, obstacles: Vec<Obstacle>) {
+        self.obstacles = obstacles;
+        self.invalidate_paths();
+    }
+
+    // wall transmission of a source outside the room, None if the source is inside
+    pub fn get_transmission(&self, source_idx: usize) -> Option<WallTransmission> {
+        self.room
+            .get_wall_transmission(&self.sound_sources[source_idx].get_position())
+    }
+
+    // occlusion of the direct path between a source and the listener
+    pub fn get_direct_occlusion(&self, source_idx: usize) -> Occlusion {
+        Occlusion::along_segment(
+            &self.obstacles,
+            &self.sound_sources[source_idx].get_position(),
+            &self.listener.position,
+        )
+    }
+
+    // diffuse energy of a source arriving at the listener, complements the specular image sources
+    pub fn trace_rays(&self, source_idx: usize, settings: &RayTracerSettings) -> EnergyHistogram {
+        RayTracer::new(&self.room, settings.clone())
+            .trace(&self.sound_sources[source_idx].get_position(), &self.listener.position)
+    }
+
+    // paths around the obstacle edges if the direct path of a source is blocked
+    pub fn get_diffraction_paths(&self, source_idx: usize) -> Vec<DiffractionPath> {
+        find_diffraction_paths(
+            &self.obstacles,
+            &self.sound_sources[source_idx].get_position(),
+            &self.listener.position,
+            self.room.get_speed_of_sound(),
+        )
+    }
+
+    // occlusion of the direct path and of every image source path of a source. Both legs
+    // of first order paths are tested, for higher orders only the last leg (last reflection
+    // point to listener) is.
+    pub fn get_path_occlusion(&self, source_idx: usize) -> (Occlusion, Vec<Occlusion>) {
+        let listener_position = self.listener.position;
+        let source_position = self.sound_sources[source_idx].get_position();
+        let boundaries = self.room.get_boundaries();
+        let image_sources = self.image_sources[source_idx]
+            .iter()
+            .map(|is| {
+                let boundary = match boundaries.iter().find(|b| b.get_direction() == is.get_reflector()) {
+                    Some(boundary) => boundary,
+                    None => return Occlusion::default(),
+                };
+                let reflection_point = reflection_point(is, boundary, &listener_position);
+                let last_leg = Occlusion::along_segment(&self.obstacles, &reflection_point, &listener_position);
+                match is.get_order() {
+                    1 => last_leg.combine(&Occlusion::along_segment(
+                        &self.obstacles,
+                        &source_position,
+                        &reflection_point,
+                    )),
+                    _ => last_leg,
+                }
+            })
+            .collect();
+        (self.get_direct_occlusion(source_idx), image_sources)
+    }
+}
+
+fn reflect(source: &impl Source, boundary: &Boundary) -> Point3<f32> {
+    reflect_position(source.get_position(), boundary)
+}
+
+fn reflect_position(position: Point3<f32>, boundary: &Boundary) -> Point3<f32> {
+    // mirrored at the plane of the boundary, which need not pass through the origin
+    let mut new_position = position;
+    let axis = reflection_axis(boundary.get_direction());
+    new_position[axis] = 2.0 * boundary.location - new_position[axis];
+    new_position
+}
+
+// point where the path from an image source to the listener hits the boundary of its
+// last reflection
+fn reflection_point(
+    image_source: &ISMImageSource,
+    boundary: &Boundary,
+    listener_position: &Point3<f32>,
+) -> Point3<f32> {
+    let axis = reflection_axis(boundary.get_direction());
+    let direction = image_source.get_position() - listener_position;
+    if direction[axis].abs() < f32::EPSILON {
+        return *listener_position;
+    }
+    let t = ((boundary.get_location() - listener_position[axis]) / direction[axis]).clamp(0.0, 1.0);
+    listener_position + direction * t
+}
+
+// coordinate that is mirrored by a reflection at the given boundary (see reflect)
+fn reflection_axis(direction: CardinalDirection) -> usize {
+    match direction {
+        CardinalDirection::EAST | CardinalDirection::WEST => 1,
+        CardinalDirection::NORTH | CardinalDirection::SOUTH => 0,
+        CardinalDirection::FLOOR | CardinalDirection::CEILING => 2,
         CardinalDirection::NONE => {
             panic!("(Image) Source has no reflector. That doesn't make any sense.")
         }
     }
-    new_position
 }
 
 fn is_per_model(maxorder: usize, n_surfaces: usize) -> usize {
@@ -341,3 +742,50 @@ fn is_per_model(maxorder: usize, n_surfaces: usize) -> usize {
 fn is_per_order(order: f64, n_surfaces: f64) -> usize {
     ((n_surfaces) * (n_surfaces - 1f64).powf(order - 1f64)).floor() as usize
 }
+
+#[test]
+fn test_wall_transmission_of_outside_source() {
+    let mut room = ISMRoom::new(Vector3::new(4.0, 5.0, 3.0), [0.0; 6], 343.0);
+    room.set_transmission_material(CardinalDirection::EAST, TransmissionMaterial::CONCRETE_WALL);
+    assert!(room.get_wall_transmission(&Point3::new(1.0, 2.0, 2.0)).is_none());
+
+    // outside behind the EAST wall (see reflect for the axes of the boundaries)
+    let transmission = room.get_wall_transmission(&Point3::new(1.0, 6.0, 2.0)).unwrap();
+    assert_eq!(transmission.get_point(), Point3::new(1.0, 4.0, 2.0));
+    assert_eq!(
+        transmission.get_band_gains(),
+        &TransmissionMaterial::CONCRETE_WALL.band_gains()[..]
+    );
+    // no image source is mirrored from outside the room
+    assert_eq!(
+        room.virtual_source_position(&Point3::new(1.0, 6.0, 2.0)),
+        transmission.get_point()
+    );
+}
+
+#[test]
+fn test_cached_paths_of_static_scene() {
+    let room = ISMRoom::new(Vector3::new(4.0, 5.0, 3.0), [0.2; 6], 343.0);
+    let listener = ISMListener::new(Point3::new(1.0, 1.0, 1.5), Quaternion::identity());
+    let source = ISMSoundSource::new(Point3::new(2.0, 3.0, 1.5), Quaternion::identity());
+    let mut scene = ISMAcousticScene::new(room, listener, vec![source], 2);
+    assert!(scene.needs_update(0));
+    let paths = scene.get_reflection_paths(0).to_vec();
+    assert!(!paths.is_empty());
+    assert!(!scene.needs_update(0));
+
+    // jitter below the threshold keeps image sources and paths
+    let image_sources = scene.get_image_source_positions(0);
+    scene.update(vec![Point3::new(2.002, 3.0, 1.5)]);
+    assert!(!scene.needs_update(0));
+    assert_eq!(scene.get_image_source_positions(0), image_sources);
+    assert_eq!(scene.get_reflection_paths(0), &paths[..]);
+
+    // real movement of the source or the listener
+    scene.update(vec![Point3::new(2.5, 3.0, 1.5)]);
+    assert!(scene.needs_update(0));
+    assert_ne!(scene.get_image_source_positions(0), image_sources);
+    assert_ne!(scene.get_reflection_paths(0), &paths[..]);
+    scene.set_listener(ISMListener::new(Point3::new(1.0, 2.0, 1.5), Quaternion::identity()));
+    assert!(scene.needs_update(0));
+}
@@ -1,16 +1,38 @@
 pub mod bind;
+pub mod biquad;
+pub mod brir;
+pub mod air_absorption;
 pub mod audio_module;
+pub mod baked_acoustics;
+pub mod obstacle;
 pub mod osc;
+pub mod ray_tracer;
 pub mod server;
 pub mod audioSceneHandlerData;
 pub mod filter;
 pub mod convolver;
+pub mod crossfade;
+pub mod delay_line;
+pub mod diffraction;
+pub mod directivity;
+pub mod distance;
+pub mod fdn;
+pub mod fdtd;
 pub mod readwav;
+pub mod reflection_lod;
+pub mod room_mesh;
+pub mod room_modes;
+pub mod sdn;
+pub mod simd;
+pub mod transmission;
+pub mod worker_pool;
 use std::{sync::mpsc};
 mod scene;
 mod image_source_method;
-use audioSceneHandlerData::Scene_data;
-use audio_module::start_audio_thread;
+mod multi_room;
+use scene::SceneUpdate;
+use osc::Source_parameter;
+use audio_module::{start_audio_thread, Garbage, GARBAGE_CAPACITY};
 use interoptopus::ffi_function;
 
 // use protobuf::ext;
@@ -28,14 +50,17 @@ pub extern "C" fn add_one(x: u32) -> u32 {
 pub extern "C" fn StartAudioSceneHandler(port: u32) {
 
     // create channel btw. audio thread and scene_handler thread
-    let (tx, rx) = mpsc::channel::<Scene_data>();
+    let (tx, rx) = mpsc::channel::<SceneUpdate>();
+    let (parameter_tx, parameter_rx) = mpsc::channel::<Source_parameter>();
+    // objects the audio thread is done with go back to the scene handler to be dropped there
+    let (garbage_tx, garbage_rx) = mpsc::sync_channel::<Garbage>(GARBAGE_CAPACITY);
     
     // start audio thread
-    let _thread_audio: () = start_audio_thread(rx);
+    let channel_factory = start_audio_thread(rx, parameter_rx, garbage_tx);
 
 
     // start scene handler thread
-    let _server: () = start_server(port, tx);
+    let _server: () = start_server(port, tx, parameter_tx, garbage_rx, channel_factory);
     
     println!("Server terminated.")
 }
\ No newline at end of file
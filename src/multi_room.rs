@@ -0,0 +1,372 @@
+use nalgebra::{Point3, Vector3};
+
+use crate::{
+    air_absorption::Atmosphere,
+    biquad::OCTAVE_BAND_CENTRES,
+    image_source_method::ISMRoom,
+    transmission::{TransmissionMaterial, WallTransmission},
+};
+
+// longest chain of portals between a source and the listener
+const MAX_PORTAL_DEPTH: usize = 3;
+// upper limit of the diffraction loss at an opening (dB)
+const MAX_DIFFRACTION_LOSS: f32 = 24.0;
+
+type BandGains = [f32; OCTAVE_BAND_CENTRES.len()];
+
+// Rectangular opening (door, window) between two rooms. A closed portal transmits sound
+// through its closed material.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Portal {
+    id: u32,
+    rooms: (usize, usize),
+    center: Point3<f32>,
+    normal: Vector3<f32>,
+    width: f32,
+    height: f32,
+    open: bool,
+    closed_material: TransmissionMaterial,
+}
+
+impl Portal {
+    pub fn new(
+        id: u32,
+        rooms: (usize, usize),
+        center: Point3<f32>,
+        normal: Vector3<f32>,
+        width: f32,
+        height: f32,
+        closed_material: TransmissionMaterial,
+    ) -> Self {
+        Self {
+            id,
+            rooms,
+            center,
+            normal: normal.try_normalize(f32::EPSILON).unwrap_or(Vector3::x()),
+            width,
+            height,
+            open: true,
+            closed_material,
+        }
+    }
+
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn get_rooms(&self) -> (usize, usize) {
+        self.rooms
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn set_open(&mut self, open: bool) {
+        self.open = open;
+    }
+
+    pub fn get_area(&self) -> f32 {
+        self.width * self.height
+    }
+
+    // horizontal and vertical axis of the opening
+    fn axes(&self) -> (Vector3<f32>, Vector3<f32>) {
+        let horizontal = self
+            .normal
+            .cross(&Vector3::y())
+            .try_normalize(f32::EPSILON)
+            .unwrap_or(Vector3::x());
+        (horizontal, horizontal.cross(&self.normal))
+    }
+
+    // point of the opening the path from a to b passes, clamped to the opening
+    fn crossing_point(&self, a: &Point3<f32>, b: &Point3<f32>) -> Point3<f32> {
+        let direction = b - a;
+        let denominator = direction.dot(&self.normal);
+        let on_plane = if denominator.abs() > f32::EPSILON {
+            let t = ((self.center - a).dot(&self.normal) / denominator).clamp(0.0, 1.0);
+            a + direction * t
+        } else {
+            *a
+        };
+        let (horizontal, vertical) = self.axes();
+        let offset = on_plane - self.center;
+        let u = offset.dot(&horizontal).clamp(-self.width / 2.0, self.width / 2.0);
+        let v = offset.dot(&vertical).clamp(-self.height / 2.0, self.height / 2.0);
+        self.center + horizontal * u + vertical * v
+    }
+
+    // band gains of a path bending at point (from a to b)
+    fn band_gains(&self, a: &Point3<f32>, point: &Point3<f32>, b: &Point3<f32>, speed_of_sound: f32) -> BandGains {
+        if !self.open {
+            return self.closed_material.band_gains();
+        }
+        // Maekawa: the detour around the edge of the opening sets the Fresnel number
+        let detour = (point - a).norm() + (b - point).norm() - (b - a).norm();
+        if detour < 1e-3 {
+            return [1.0; OCTAVE_BAND_CENTRES.len()];
+        }
+        OCTAVE_BAND_CENTRES.map(|frequency| {
+            let fresnel_number = 2.0 * detour * frequency / speed_of_sound;
+            let loss = (10.0 * (3.0 + 20.0 * fresnel_number).log10()).min(MAX_DIFFRACTION_LOSS);
+            10f32.powf(-loss / 20.0)
+        })
+    }
+}
+
+// Path from a source through one or more portals to the listener.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortalPath {
+    portal_ids: Vec<u32>,
+    // crossing points in the order source -> listener
+    points: Vec<Point3<f32>>,
+    length: f32,
+    band_gains: BandGains,
+}
+
+impl PortalPath {
+    pub fn get_portal_ids(&self) -> &[u32] {
+        &self.portal_ids
+    }
+
+    pub fn get_points(&self) -> &[Point3<f32>] {
+        &self.points
+    }
+
+    pub fn get_length(&self) -> f32 {
+        self.length
+    }
+
+    pub fn get_band_gains(&self) -> &BandGains {
+        &self.band_gains
+    }
+
+    // the listener hears the path from the last portal
+    pub fn get_apparent_position(&self) -> Point3<f32> {
+        *self.points.last().unwrap()
+    }
+
+    // mean band energy, used to pick the dominant path
+    pub fn get_energy(&self) -> f32 {
+        self.band_gains.iter().map(|g| g * g).sum::<f32>() / self.band_gains.len() as f32
+            / self.length.max(1.0).powi(2)
+    }
+
+    pub fn to_transmission(&self) -> WallTransmission {
+        WallTransmission::from_band_gains(self.get_apparent_position(), self.band_gains)
+    }
+}
+
+// Late tail of the source room leaking into a neighbouring room through its portals.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoupledReverb {
+    pub room_idx: usize,
+    // Sabine reverberation time of the coupled room (s)
+    pub rt60: f32,
+    // share of the reverberant energy that leaves the room through the portals
+    pub coupling: f32,
+}
+
+// Several ISMRooms connected by portals. Every room is placed at its origin in world
+// coordinates.
+#[derive(Debug, Default)]
+pub struct MultiRoomScene {
+    rooms: Vec<(Point3<f32>, ISMRoom)>,
+    portals: Vec<Portal>,
+}
+
+impl MultiRoomScene {
+    pub fn new() -> Self {
+        MultiRoomScene::default()
+    }
+
+    // returns the index of the room
+    pub fn add_room(&mut self, origin: Point3<f32>, room: ISMRoom) -> usize {
+        self.rooms.push((origin, room));
+        self.rooms.len() - 1
+    }
+
+    // atmospheric conditions of all rooms
+    pub fn set_atmosphere(&mut self, atmosphere: Atmosphere) {
+        for (_, room) in self.rooms.iter_mut() {
+            room.set_atmosphere(atmosphere);
+        }
+    }
+
+    pub fn get_n_rooms(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn get_room(&self, room_idx: usize) -> &ISMRoom {
+        &self.rooms[room_idx].1
+    }
+
+    // adds a portal or replaces the one with the same id
+    pub fn add_portal(&mut self, portal: Portal) {
+        self.portals.retain(|p| p.id != portal.id);
+        self.portals.push(portal);
+    }
+
+    pub fn set_portal_open(&mut self, id: u32, open: bool) {
+        if let Some(portal) = self.portals.iter_mut().find(|p| p.id == id) {
+            portal.open = open;
+        }
+    }
+
+    pub fn get_portals(&self) -> &[Portal] {
+        &self.portals
+    }
+
+    // room containing the (world) position
+    pub fn find_room(&self, position: &Point3<f32>) -> Option<usize> {
+        self.rooms.iter().position(|(origin, room)| {
+            room.contains(&Point3::from(position - origin))
+        })
+    }
+
+    // all portal paths from the room of the source to the room of the listener, empty if
+    // both are in the same room (or outside all rooms)
+    pub fn find_portal_paths(&self, source: &Point3<f32>, listener: &Point3<f32>) -> Vec<PortalPath> {
+        let (source_room, listener_room) = match (self.find_room(source), self.find_room(listener)) {
+            (Some(source_room), Some(listener_room)) if source_room != listener_room => (source_room, listener_room),
+            _ => return Vec::new(),
+        };
+        let mut paths = Vec::new();
+        let mut visited = vec![source_room];
+        let mut portal_chain = Vec::new();
+        self.search_paths(source_room, listener_room, &mut visited, &mut portal_chain, source, listener, &mut paths);
+        paths
+    }
+
+    // dominant portal path (most energy at the listener)
+    pub fn find_best_portal_path(&self, source: &Point3<f32>, listener: &Point3<f32>) -> Option<PortalPath> {
+        self.find_portal_paths(source, listener)
+            .into_iter()
+            .max_by(|a, b| a.get_energy().total_cmp(&b.get_energy()))
+    }
+
+    // late tail of the source room as heard in the listener room
+    pub fn get_coupled_reverb(&self, source: &Point3<f32>, listener: &Point3<f32>) -> Option<CoupledReverb> {
+        let source_room = self.find_room(source)?;
+        if Some(source_room) == self.find_room(listener) {
+            return None;
+        }
+        let room = &self.rooms[source_room].1;
+        let open_portal_area: f32 = self
+            .portals
+            .iter()
+            .filter(|p| p.open && (p.rooms.0 == source_room || p.rooms.1 == source_room))
+            .map(|p| p.get_area())
+            .sum();
+        // open portals act as perfect absorbers of the source room
+        let absorption_area = (room.get_absorption_area() + open_portal_area).max(f32::EPSILON);
+        Some(CoupledReverb {
+            room_idx: source_room,
+            rt60: 0.161 * room.get_volume() / absorption_area,
+            coupling: open_portal_area / absorption_area,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn search_paths(
+        &self,
+        room: usize,
+        listener_room: usize,
+        visited: &mut Vec<usize>,
+        portal_chain: &mut Vec<usize>,
+        source: &Point3<f32>,
+        listener: &Point3<f32>,
+        paths: &mut Vec<PortalPath>,
+    ) {
+        if room == listener_room {
+            paths.push(self.build_path(portal_chain, source, listener));
+            return;
+        }
+        if portal_chain.len() == MAX_PORTAL_DEPTH {
+            return;
+        }
+        for (portal_idx, portal) in self.portals.iter().enumerate() {
+            let next_room = match portal.rooms {
+                (a, b) if a == room => b,
+                (a, b) if b == room => a,
+                _ => continue,
+            };
+            if visited.contains(&next_room) {
+                continue;
+            }
+            visited.push(next_room);
+            portal_chain.push(portal_idx);
+            self.search_paths(next_room, listener_room, visited, portal_chain, source, listener, paths);
+            portal_chain.pop();
+            visited.pop();
+        }
+    }
+
+    fn build_path(&self, portal_chain: &[usize], source: &Point3<f32>, listener: &Point3<f32>) -> PortalPath {
+        let portals: Vec<&Portal> = portal_chain.iter().map(|idx| &self.portals[*idx]).collect();
+        let mut points = Vec::with_capacity(portals.len());
+        let mut previous = *source;
+        for portal in portals.iter() {
+            let point = portal.crossing_point(&previous, listener);
+            points.push(point);
+            previous = point;
+        }
+
+        let speed_of_sound = self.rooms[0].1.get_speed_of_sound();
+        let mut band_gains = [1.0; OCTAVE_BAND_CENTRES.len()];
+        let mut length = 0.0;
+        for (i, portal) in portals.iter().enumerate() {
+            let a = if i == 0 { *source } else { points[i - 1] };
+            let b = points.get(i + 1).copied().unwrap_or(*listener);
+            let gains = portal.band_gains(&a, &points[i], &b, speed_of_sound);
+            band_gains.iter_mut().zip(gains.iter()).for_each(|(g, p)| *g *= p);
+            length += (points[i] - a).norm();
+        }
+        length += (listener - points.last().unwrap_or(source)).norm();
+
+        PortalPath {
+            portal_ids: portals.iter().map(|p| p.id).collect(),
+            points,
+            length,
+            band_gains,
+        }
+    }
+}
+
+#[test]
+fn test_portal_paths() {
+    // two 4 x 3 x 4 rooms next to each other along x, connected by a door at x = 4
+    let mut scene = MultiRoomScene::new();
+    let room_a = scene.add_room(Point3::origin(), ISMRoom::new(Vector3::new(4.0, 3.0, 4.0), [0.1; 6], 343.0));
+    let room_b = scene.add_room(Point3::new(4.0, 0.0, 0.0), ISMRoom::new(Vector3::new(4.0, 3.0, 4.0), [0.1; 6], 343.0));
+    scene.add_portal(Portal::new(
+        7,
+        (room_a, room_b),
+        Point3::new(4.0, 1.0, 2.0),
+        Vector3::x(),
+        1.0,
+        2.0,
+        TransmissionMaterial::WOODEN_DOOR,
+    ));
+
+    // line of sight through the open door
+    let source = Point3::new(1.0, 1.0, 2.0);
+    let listener = Point3::new(7.0, 1.0, 2.0);
+    let path = scene.find_best_portal_path(&source, &listener).unwrap();
+    assert_eq!(path.get_portal_ids(), &[7]);
+    assert!((path.get_length() - 6.0).abs() < 1e-4);
+    assert!(path.get_band_gains().iter().all(|g| *g == 1.0));
+
+    // around the door jamb, high frequencies are attenuated more
+    let listener = Point3::new(7.0, 1.0, 0.5);
+    let path = scene.find_best_portal_path(&source, &listener).unwrap();
+    let gains = path.get_band_gains();
+    assert!(gains[0] < 1.0 && gains[7] < gains[0]);
+
+    // closed door
+    scene.set_portal_open(7, false);
+    let path = scene.find_best_portal_path(&source, &listener).unwrap();
+    assert_eq!(path.get_band_gains(), &TransmissionMaterial::WOODEN_DOOR.band_gains());
+    assert!(scene.find_portal_paths(&source, &Point3::new(2.0, 1.0, 1.0)).is_empty());
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use nalgebra::{Point3, UnitQuaternion, Vector3};
+
+use crate::diffraction::Edge;
+
+// tolerance for hits at the ends of a segment (sources/listeners touching an obstacle)
+const SEGMENT_EPSILON: f32 = 1e-4;
+
+// Broadband transmission loss (dB) and low-pass cutoff (Hz) of sound passing an obstacle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObstacleMaterial {
+    transmission_loss: f32,
+    cutoff: f32,
+}
+
+impl Default for ObstacleMaterial {
+    fn default() -> Self {
+        ObstacleMaterial::WOOD
+    }
+}
+
+impl ObstacleMaterial {
+    pub const CURTAIN: ObstacleMaterial = ObstacleMaterial::new(3.0, 8000.0);
+    pub const GLASS: ObstacleMaterial = ObstacleMaterial::new(10.0, 4000.0);
+    pub const WOOD: ObstacleMaterial = ObstacleMaterial::new(15.0, 1500.0);
+    pub const CONCRETE: ObstacleMaterial = ObstacleMaterial::new(30.0, 500.0);
+
+    pub const fn new(transmission_loss: f32, cutoff: f32) -> Self {
+        Self {
+            transmission_loss,
+            cutoff,
+        }
+    }
+
+    pub fn get_transmission_loss(&self) -> f32 {
+        self.transmission_loss
+    }
+
+    pub fn get_cutoff(&self) -> f32 {
+        self.cutoff
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObstacleGeometry {
+    // oriented box, half_extents in the local frame of the box
+    Box {
+        center: Point3<f32>,
+        half_extents: Vector3<f32>,
+        orientation: UnitQuaternion<f32>,
+    },
+    Mesh {
+        triangles: Vec<[Point3<f32>; 3]>,
+    },
+}
+
+impl ObstacleGeometry {
+    // true if the segment from a to b passes through the geometry
+    pub fn intersects_segment(&self, a: &Point3<f32>, b: &Point3<f32>) -> bool {
+        match self {
+            ObstacleGeometry::Box {
+                center,
+                half_extents,
+                orientation,
+            } => {
+                let a_local = orientation.inverse_transform_vector(&(a - center));
+                let b_local = orientation.inverse_transform_vector(&(b - center));
+                segment_intersects_aabb(&a_local, &b_local, half_extents)
+            }
+            ObstacleGeometry::Mesh { triangles } => triangles
+                .iter()
+                .any(|triangle| segment_intersects_triangle(a, b, triangle)),
+        }
+    }
+
+    // diffracting edges: the 12 edges of a box, the creases of a mesh
+    pub fn get_edges(&self) -> Vec<Edge> {
+        match self {
+            ObstacleGeometry::Box {
+                center,
+                half_extents,
+                orientation,
+            } => {
+                let mut edges = Vec::with_capacity(12);
+                for axis in 0..3 {
+                    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+                    for (sign_a, sign_b) in [(1.0, 1.0), (1.0, -1.0), (-1.0, 1.0), (-1.0, -1.0)] {
+                        let normal_a = orientation * (Vector3::ith(a, 1.0) * sign_a);
+                        let normal_b = orientation * (Vector3::ith(b, 1.0) * sign_b);
+                        let offset = normal_a * half_extents[a] + normal_b * half_extents[b];
+                        let along = orientation * Vector3::ith(axis, half_extents[axis]);
+                        // the face with normal a runs along -b and vice versa
+                        edges.push(Edge::new(
+                            center + offset - along,
+                            center + offset + along,
+                            -normal_b,
+                            -normal_a,
+                        ));
+                    }
+                }
+                edges
+            }
+            ObstacleGeometry::Mesh { triangles } => mesh_edges(triangles),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Obstacle {
+    id: u32,
+    geometry: ObstacleGeometry,
+    material: ObstacleMaterial,
+}
+
+impl Obstacle {
+    pub fn new(id: u32, geometry: ObstacleGeometry, material: ObstacleMaterial) -> Self {
+        Self {
+            id,
+            geometry,
+            material,
+        }
+    }
+
+    // size: edge lengths of the box
+    pub fn from_box(
+        id: u32,
+        center: Point3<f32>,
+        size: Vector3<f32>,
+        orientation: UnitQuaternion<f32>,
+        material: ObstacleMaterial,
+    ) -> Self {
+        let geometry = ObstacleGeometry::Box {
+            center,
+            half_extents: size.abs() / 2.0,
+            orientation,
+        };
+        Obstacle::new(id, geometry, material)
+    }
+
+    pub fn from_mesh(id: u32, triangles: Vec<[Point3<f32>; 3]>, material: ObstacleMaterial) -> Self {
+        Obstacle::new(id, ObstacleGeometry::Mesh { triangles }, material)
+    }
+
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn get_geometry(&self) -> &ObstacleGeometry {
+        &self.geometry
+    }
+
+    pub fn get_material(&self) -> ObstacleMaterial {
+        self.material
+    }
+
+    pub fn get_edges(&self) -> Vec<Edge> {
+        self.geometry.get_edges()
+    }
+}
+
+// Attenuation of a propagation path by all obstacles it passes. Transmission losses add
+// up, the lowest cutoff wins.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Occlusion {
+    attenuation: f32,
+    cutoff: f32,
+}
+
+impl Default for Occlusion {
+    // free line of sight
+    fn default() -> Self {
+        Self {
+            attenuation: 0.0,
+            cutoff: f32::INFINITY,
+        }
+    }
+}
+
+impl Occlusion {
+    pub fn new(attenuation: f32, cutoff: f32) -> Self {
+        Self {
+            attenuation,
+            cutoff,
+        }
+    }
+
+    // path from a to b through the given obstacles
+    pub fn along_segment(obstacles: &[Obstacle], a: &Point3<f32>, b: &Point3<f32>) -> Self {
+        obstacles
+            .iter()
+            .filter(|obstacle| obstacle.geometry.intersects_segment(a, b))
+            .fold(Occlusion::default(), |occlusion, obstacle| {
+                occlusion.combine(&Occlusion::new(
+                    obstacle.material.transmission_loss,
+                    obstacle.material.cutoff,
+                ))
+            })
+    }
+
+    pub fn combine(&self, other: &Occlusion) -> Self {
+        Self {
+            attenuation: self.attenuation + other.attenuation,
+            cutoff: self.cutoff.min(other.cutoff),
+        }
+    }
+
+    pub fn is_occluded(&self) -> bool {
+        self.attenuation > 0.0 || self.cutoff.is_finite()
+    }
+
+    // attenuation in dB
+    pub fn get_attenuation(&self) -> f32 {
+        self.attenuation
+    }
+
+    pub fn get_cutoff(&self) -> f32 {
+        self.cutoff
+    }
+
+    pub fn gain(&self) -> f32 {
+        10f32.powf(-self.attenuation / 20.0)
+    }
+}
+
+// slab test against the box [-half_extents, half_extents]
+fn segment_intersects_aabb(a: &Vector3<f32>, b: &Vector3<f32>, half_extents: &Vector3<f32>) -> bool {
+    let direction = b - a;
+    let (mut t_min, mut t_max) = (0.0f32, 1.0f32);
+    for axis in 0..3 {
+        if direction[axis].abs() < f32::EPSILON {
+            if a[axis].abs() > half_extents[axis] {
+                return false;
+            }
+            continue;
+        }
+        let t0 = (-half_extents[axis] - a[axis]) / direction[axis];
+        let t1 = (half_extents[axis] - a[axis]) / direction[axis];
+        t_min = t_min.max(t0.min(t1));
+        t_max = t_max.min(t0.max(t1));
+        if t_min > t_max {
+            return false;
+        }
+    }
+    true
+}
+
+// edges shared by two triangles that are not coplanar, vertices have to match exactly
+fn mesh_edges(triangles: &[[Point3<f32>; 3]]) -> Vec<Edge> {
+    let key = |p: &Point3<f32>| p.coords.map(f32::to_bits);
+    let mut shared: HashMap<_, Vec<Vector3<f32>>> = HashMap::new();
+    let mut vertices = HashMap::new();
+    for triangle in triangles {
+        for i in 0..3 {
+            let (a, b, c) = (triangle[i], triangle[(i + 1) % 3], triangle[(i + 2) % 3]);
+            let (a, b) = if key(&a).as_slice() < key(&b).as_slice() { (a, b) } else { (b, a) };
+            // direction from the edge towards the opposite vertex, within the triangle
+            let direction = (b - a).normalize();
+            let face = (c - a) - direction * (c - a).dot(&direction);
+            shared.entry((key(&a), key(&b))).or_default().push(face);
+            vertices.insert((key(&a), key(&b)), (a, b));
+        }
+    }
+    let mut edges = Vec::new();
+    for (k, faces) in shared {
+        if faces.len() != 2 || faces[0].norm() < f32::EPSILON || faces[1].norm() < f32::EPSILON {
+            continue;
+        }
+        // coplanar triangles do not diffract
+        if faces[0].normalize().dot(&faces[1].normalize()) < -0.999 {
+            continue;
+        }
+        let (a, b) = vertices[&k];
+        edges.push(Edge::new(a, b, faces[0], faces[1]));
+    }
+    edges
+}
+
+// Möller-Trumbore
+fn segment_intersects_triangle(a: &Point3<f32>, b: &Point3<f32>, triangle: &[Point3<f32>; 3]) -> bool {
+    let direction = b - a;
+    let edge_1 = triangle[1] - triangle[0];
+    let edge_2 = triangle[2] - triangle[0];
+    let p = direction.cross(&edge_2);
+    let det = edge_1.dot(&p);
+    if det.abs() < f32::EPSILON {
+        return false;
+    }
+    let s = a - triangle[0];
+    let u = s.dot(&p) / det;
+    if !(0.0..=1.0).contains(&u) {
+        return false;
+    }
+    let q = s.cross(&edge_1);
+    let v = direction.dot(&q) / det;
+    if v < 0.0 || u + v > 1.0 {
+        return false;
+    }
+    let t = edge_2.dot(&q) / det;
+    t > SEGMENT_EPSILON && t < 1.0 - SEGMENT_EPSILON
+}
+
+#[test]
+fn test_occlusion_by_box_and_mesh() {
+    let source = Point3::new(0.0, 1.0, 0.0);
+    let listener = Point3::new(4.0, 1.0, 0.0);
+    let rotated_box = Obstacle::from_box(
+        0,
+        Point3::new(2.0, 1.0, 0.0),
+        Vector3::new(0.2, 2.0, 2.0),
+        UnitQuaternion::from_euler_angles(0.0, 0.3, 0.0),
+        ObstacleMaterial::CONCRETE,
+    );
+    // a wall segment in the plane x = 3
+    let wall = Obstacle::from_mesh(
+        1,
+        vec![[Point3::new(3.0, 0.0, -2.0), Point3::new(3.0, 4.0, -2.0), Point3::new(3.0, 0.0, 2.0)]],
+        ObstacleMaterial::WOOD,
+    );
+    let obstacles = vec![rotated_box, wall];
+
+    let occlusion = Occlusion::along_segment(&obstacles, &source, &listener);
+    assert!(occlusion.is_occluded());
+    assert_eq!(occlusion.get_attenuation(), 45.0);
+    assert_eq!(occlusion.get_cutoff(), 500.0);
+
+    // passing above both obstacles
+    let above = Point3::new(4.0, 5.0, 0.0);
+    assert!(!Occlusion::along_segment(&obstacles, &Point3::new(0.0, 5.0, 0.0), &above).is_occluded());
+}
@@ -1,6 +1,24 @@
-use std::{net::{UdpSocket, SocketAddrV4}, str::FromStr};
+use std::{collections::VecDeque, net::{UdpSocket, SocketAddrV4}, str::FromStr, sync::Arc};
 
-use rosc::OscPacket;
+use rosc::{OscMessage, OscPacket, OscType};
+
+use nalgebra::{Point3, Quaternion, UnitQuaternion, Vector3};
+
+use crate::air_absorption::Atmosphere;
+use crate::audio_module::RoomModel;
+use crate::convolver::{SpatializerChannel, MAX_SOURCE_ID};
+use crate::diffraction::DiffractionPath;
+use crate::directivity::{DirectivityPattern, DirectivityStorage};
+use crate::distance::{DistanceAttenuation, DistanceModel};
+use crate::fdn::FeedbackDelayNetwork;
+use crate::image_source_method::ISMRoom;
+use crate::multi_room::Portal;
+use crate::obstacle::{Obstacle, ObstacleMaterial, Occlusion};
+use crate::reflection_lod::ReflectionPath;
+use crate::room_mesh::MESH_OBSTACLE_ID;
+use crate::room_modes::RoomModeBank;
+use crate::sdn::ScatteringDelayNetwork;
+use crate::transmission::{TransmissionMaterial, WallTransmission};
 
 
 
@@ -18,10 +36,96 @@ pub enum Transport_Cmd_Type {
 }
 
 
+// Messages received by the scene handler. Scene data arrives as protobuf blob followed by
+// one Source_transform blob per source, per-source parameters as plain OSC messages.
+pub enum OSC_message {
+    SceneData(Vec<u8>, Vec<Vec<u8>>),
+    SourceParameter(Source_parameter),
+    ObstacleCommand(ObstacleCommand),
+    RoomCommand(RoomCommand),
+    DirectivityCommand(DirectivityCommand),
+    // /source/signal <id> [<wav file>], loaded by the scene handler, without file the source is silenced
+    SourceSignal(u32, Option<String>),
+    Unknown(String),
+}
+
+pub enum Source_parameter {
+    // stable ids of the sources of the scene and the channels of the new ones, sent by the scene
+    // handler before the parameters of new sources so that their channels exist
+    Sources(Vec<u32>, Vec<Box<SpatializerChannel>>),
+    // /source/distance <id> <model> <reference distance> <max distance> <rolloff> [<distance> <gain>]...
+    DistanceAttenuation(u32, DistanceAttenuation),
+    // /source/doppler <id> <enabled>
+    Doppler(u32, bool),
+    // computed by the scene handler from the obstacles of the scene
+    Occlusion(u32, Occlusion),
+    // computed by the scene handler for sources outside the room, None if inside
+    Transmission(u32, Option<WallTransmission>),
+    // computed by the scene handler if obstacles block the direct path, rendered as virtual
+    // sources. The scene handler builds the channels of the virtual sources that are new.
+    Diffraction(u32, Vec<DiffractionPath>, Vec<Box<SpatializerChannel>>),
+    // image source paths (or the baked probes of a static source) after the level of detail
+    // selection of the scene handler, for the listener position. Channels as for Diffraction.
+    Reflections(u32, Point3<f32>, Vec<ReflectionPath>, Vec<Box<SpatializerChannel>>),
+    // loaded by the scene handler from a DirectivityCommand
+    Directivity(u32, Option<Arc<DirectivityStorage>>),
+    // loaded by the scene handler from a SourceSignal message, played in a loop
+    Signal(u32, Option<Arc<[f32]>>),
+    // applies to all sources, sent by the scene handler on /room/atmosphere
+    Atmosphere(Atmosphere),
+    // late reverberation of the neighbouring room of the source, None if source and listener share a room
+    CoupledReverb(u32, Option<Box<FeedbackDelayNetwork>>),
+    // late reverberation of the room of the listener, traced by the scene handler once per room.
+    // None with the Scattering Delay Network (it renders the tail) or for sources in another room.
+    RoomReverb(u32, Option<Box<FeedbackDelayNetwork>>),
+    // built by the scene handler for the room of the scene if the room model is the Scattering
+    // Delay Network, None renders the image source reflections. Channels of the node virtual
+    // sources as for Diffraction.
+    RoomModel(u32, Option<Box<ScatteringDelayNetwork>>, Vec<Box<SpatializerChannel>>),
+    // low-frequency modes of the room of the scene, built by the scene handler
+    RoomModes(u32, Option<Box<RoomModeBank>>),
+}
+
+// directivity filters are built by the scene handler, not on the audio thread
+pub enum DirectivityCommand {
+    // /source/directivity <id> <pattern name>
+    Pattern(u32, DirectivityPattern),
+    // /source/directivity <id> <filter file> <angle file> <filter length>
+    Measured(u32, String, String, usize),
+}
+
+pub enum ObstacleCommand {
+    // /obstacle/box <id> <center x y z> <size x y z> <transmission loss> <cutoff> [<orientation x y z w>]
+    Add(Obstacle),
+    // /obstacle/remove <id>
+    Remove(u32),
+}
+
+pub enum RoomCommand {
+    // /room/add <origin x y z> <width> <length> <height> [<absorption>]
+    AddRoom(Point3<f32>, Box<ISMRoom>),
+    // /portal/add <id> <room a> <room b> <center x y z> <normal x y z> <width> <height> [<material>]
+    AddPortal(Portal),
+    // /portal/open <id> <open>
+    SetPortalOpen(u32, bool),
+    // /room/probes <path of the baked acoustics file>
+    LoadProbes(String),
+    // /room/lod <max reflection virtual sources over all sources> <clusters per source>
+    SetLod(usize, usize),
+    // /room/atmosphere <temperature (°C)> <relative humidity (%)> [<pressure (kPa)>]
+    SetAtmosphere(Atmosphere),
+    // /room/model <ism or sdn>
+    SetRoomModel(RoomModel),
+    // /room/mesh <path of an OBJ or glTF file>
+    LoadMesh(String),
+}
+
 pub struct OSCHandler {   
    address: SocketAddrV4,
    sock: UdpSocket,
    buf: [u8; 2048],
+   // messages of a received bundle that were not returned yet
+   pending: VecDeque<OSC_message>,
 }
 
 impl OSCHandler {
@@ -35,7 +139,8 @@ impl OSCHandler {
         OSCHandler {
            address,
            sock, 
-           buf: [0; 2048]
+           buf: [0; 2048],
+           pending: VecDeque::new(),
         }
     }
 
@@ -51,12 +156,262 @@ impl OSCHandler {
 
     }
 
+    // packets that cannot be received or decoded are returned as Unknown, the messages of
+    // a bundle are returned one by one in the order of the bundle
+    pub fn try_recv_message(&mut self) -> OSC_message {
+        if let Some(message) = self.pending.pop_front() {
+            return message;
+        }
+        match self.sock.recv_from(&mut self.buf) {
+            Ok((size, _addr)) => match rosc::decoder::decode_udp(&self.buf[..size]) {
+                Ok((_, osc_packet)) => {
+                    OSCHandler::parse_osc_packet(osc_packet, &mut self.pending);
+                    self.pending.pop_front().unwrap_or_else(|| OSC_message::Unknown("(empty bundle)".to_string()))
+                }
+                Err(error) => OSC_message::Unknown(format!("(undecodable packet: {error:?})")),
+            },
+            Err(error) => OSC_message::Unknown(format!("(receive error: {error})")),
+        }
+    }
+
+    fn parse_osc_packet(packet: OscPacket, messages: &mut VecDeque<OSC_message>) {
+        match packet {
+            OscPacket::Bundle(bundle) => {
+                for packet in bundle.content {
+                    OSCHandler::parse_osc_packet(packet, messages);
+                }
+            }
+            OscPacket::Message(message) => messages.push_back(OSCHandler::parse_osc_message(message)),
+        }
+    }
+
+    fn parse_osc_message(message: OscMessage) -> OSC_message {
+        match message.addr.as_str() {
+            "/source/distance" => match parse_distance_attenuation(&message) {
+                Some((id, distance_attenuation)) => OSC_message::SourceParameter(Source_parameter::DistanceAttenuation(id, distance_attenuation)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/obstacle/box" => match parse_box_obstacle(&message) {
+                Some(obstacle) => OSC_message::ObstacleCommand(ObstacleCommand::Add(obstacle)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/obstacle/remove" => match message.args.first().and_then(osc_id) {
+                Some(id) => OSC_message::ObstacleCommand(ObstacleCommand::Remove(id)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/room/add" => match parse_room(&message) {
+                Some((origin, room)) => OSC_message::RoomCommand(RoomCommand::AddRoom(origin, Box::new(room))),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/room/probes" => match message.args.first().and_then(|arg| arg.clone().string()) {
+                Some(path) => OSC_message::RoomCommand(RoomCommand::LoadProbes(path)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/room/mesh" => match message.args.first().and_then(|arg| arg.clone().string()) {
+                Some(path) => OSC_message::RoomCommand(RoomCommand::LoadMesh(path)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/room/lod" => match (message.args.first().and_then(|a| a.clone().int()), message.args.get(1).and_then(|a| a.clone().int())) {
+                (Some(max_paths), Some(max_clusters)) if max_paths >= 0 && max_clusters >= 0 => {
+                    OSC_message::RoomCommand(RoomCommand::SetLod(max_paths as usize, max_clusters as usize))
+                }
+                _ => OSC_message::Unknown(message.addr),
+            },
+            "/room/atmosphere" => match parse_atmosphere(&message) {
+                Some(atmosphere) => OSC_message::RoomCommand(RoomCommand::SetAtmosphere(atmosphere)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/room/model" => match message.args.first().and_then(|arg| arg.clone().string()).and_then(|name| RoomModel::from_name(&name)) {
+                Some(room_model) => OSC_message::RoomCommand(RoomCommand::SetRoomModel(room_model)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/portal/add" => match parse_portal(&message) {
+                Some(portal) => OSC_message::RoomCommand(RoomCommand::AddPortal(portal)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/portal/open" => match parse_id_flag(&message) {
+                Some((id, open)) => OSC_message::RoomCommand(RoomCommand::SetPortalOpen(id, open)),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/source/directivity" => match parse_directivity(&message) {
+                Some(command) => OSC_message::DirectivityCommand(command),
+                None => OSC_message::Unknown(message.addr),
+            },
+            "/source/signal" => match (message.args.first().and_then(osc_source_id), message.args.get(1).map(|arg| arg.clone().string())) {
+                (Some(id), None) => OSC_message::SourceSignal(id, None),
+                (Some(id), Some(Some(path))) => OSC_message::SourceSignal(id, Some(path)),
+                _ => OSC_message::Unknown(message.addr),
+            },
+            "/source/doppler" => match parse_id_flag(&message) {
+                Some((id, enabled)) if id < MAX_SOURCE_ID => OSC_message::SourceParameter(Source_parameter::Doppler(id, enabled)),
+                _ => OSC_message::Unknown(message.addr),
+            },
+            _ => {
+                let mut blobs = message.args.into_iter().map(OscType::blob);
+                match (blobs.next().flatten(), blobs.collect::<Option<Vec<_>>>()) {
+                    (Some(byte_string), Some(source_transforms)) => OSC_message::SceneData(byte_string, source_transforms),
+                    _ => OSC_message::Unknown(message.addr),
+                }
+            }
+        }
+    }
+
     fn handle_osc_packet(packet: OscPacket) -> Vec<u8> {
         match packet {
-            OscPacket::Bundle(bundle) => panic!("OSC Pcket of type Bundle not (yet) supported!"),
+            OscPacket::Bundle(bundle) => bundle.content.into_iter().next().map(OSCHandler::handle_osc_packet).unwrap_or_default(),
             OscPacket::Message(message) => {
                 message.args[0].clone().blob().expect("Expected OscMessage found None")               
             },
         }
     }
-}
\ No newline at end of file
+}
+
+fn parse_distance_attenuation(message: &OscMessage) -> Option<(u32, DistanceAttenuation)> {
+    let id = osc_source_id(message.args.first()?)?;
+    let model_name = message.args.get(1)?.clone().string()?;
+    let params: Vec<f32> = osc_floats(&message.args[2..])?;
+    if params.len() < 3 {
+        return None;
+    }
+    let curve: Vec<(f32, f32)> = params[3..].chunks_exact(2).map(|p| (p[0], p[1])).collect();
+    let model = DistanceModel::from_name(&model_name, curve)?;
+    Some((id, DistanceAttenuation::new(model, params[0], params[1], params[2])))
+}
+
+fn parse_directivity(message: &OscMessage) -> Option<DirectivityCommand> {
+    let id = osc_source_id(message.args.first()?)?;
+    let name = message.args.get(1)?.clone().string()?;
+    if message.args.len() == 2 {
+        return Some(DirectivityCommand::Pattern(id, DirectivityPattern::from_name(&name)?));
+    }
+    let anglepath = message.args.get(2)?.clone().string()?;
+    let filter_length = message.args.get(3)?.clone().int()?;
+    if filter_length <= 0 {
+        return None;
+    }
+    Some(DirectivityCommand::Measured(id, name, anglepath, filter_length as usize))
+}
+
+fn parse_room(message: &OscMessage) -> Option<(Point3<f32>, ISMRoom)> {
+    let params: Vec<f32> = osc_floats(&message.args)?;
+    if params.len() < 6 {
+        return None;
+    }
+    let absorption = params.get(6).copied().unwrap_or(0.0);
+    // the scene handler applies the atmosphere of the scene
+    let room = ISMRoom::with_atmosphere(Vector3::new(params[3], params[4], params[5]), [absorption; 6], Atmosphere::default());
+    Some((Point3::new(params[0], params[1], params[2]), room))
+}
+
+fn parse_atmosphere(message: &OscMessage) -> Option<Atmosphere> {
+    let params: Vec<f32> = osc_floats(&message.args)?;
+    if params.len() < 2 {
+        return None;
+    }
+    let pressure = params.get(2).copied().unwrap_or(Atmosphere::default().get_pressure());
+    Some(Atmosphere::new(params[0], params[1], pressure))
+}
+
+fn parse_portal(message: &OscMessage) -> Option<Portal> {
+    let id = osc_id(message.args.first()?)?;
+    let room_a = osc_id(message.args.get(1)?)? as usize;
+    let room_b = osc_id(message.args.get(2)?)? as usize;
+    let params: Vec<f32> = osc_floats(message.args.get(3..11)?)?;
+    // the material of the closed portal, a wooden door if not given
+    let closed_material = match message.args.get(11) {
+        Some(name) => TransmissionMaterial::from_name(&name.clone().string()?)?,
+        None => TransmissionMaterial::WOODEN_DOOR,
+    };
+    Some(Portal::new(
+        id,
+        (room_a, room_b),
+        Point3::new(params[0], params[1], params[2]),
+        Vector3::new(params[3], params[4], params[5]),
+        params[6],
+        params[7],
+        closed_material,
+    ))
+}
+
+// <id> <bool or number>
+fn parse_id_flag(message: &OscMessage) -> Option<(u32, bool)> {
+    let id = osc_id(message.args.first()?)?;
+    let enabled = match message.args.get(1)? {
+        OscType::Bool(v) => *v,
+        arg => osc_float(arg)? != 0.0,
+    };
+    Some((id, enabled))
+}
+
+fn parse_box_obstacle(message: &OscMessage) -> Option<Obstacle> {
+    let id = osc_id(message.args.first()?)?;
+    if id >= MESH_OBSTACLE_ID {
+        return None;
+    }
+    let params: Vec<f32> = osc_floats(&message.args[1..])?;
+    if params.len() < 8 {
+        return None;
+    }
+    let orientation = match params.get(8..12) {
+        Some(q) => UnitQuaternion::from_quaternion(Quaternion::new(q[3], q[0], q[1], q[2])),
+        None => UnitQuaternion::identity(),
+    };
+    Some(Obstacle::from_box(
+        id,
+        Point3::new(params[0], params[1], params[2]),
+        Vector3::new(params[3], params[4], params[5]),
+        orientation,
+        ObstacleMaterial::new(params[6], params[7]),
+    ))
+}
+
+// ids and indices are sent as OSC int32, negative values are invalid
+fn osc_id(arg: &OscType) -> Option<u32> {
+    u32::try_from(arg.clone().int()?).ok()
+}
+
+// the ids from MAX_SOURCE_ID on belong to virtual sources
+fn osc_source_id(arg: &OscType) -> Option<u32> {
+    osc_id(arg).filter(|id| *id < MAX_SOURCE_ID)
+}
+
+// None if one of the arguments is not a number
+fn osc_floats(args: &[OscType]) -> Option<Vec<f32>> {
+    args.iter().map(osc_float).collect()
+}
+
+fn osc_float(arg: &OscType) -> Option<f32> {
+    match arg {
+        OscType::Float(v) => Some(*v),
+        OscType::Double(v) => Some(*v as f32),
+        OscType::Int(v) => Some(*v as f32),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_bundle_and_negative_ids() {
+    use rosc::{OscBundle, OscTime};
+
+    let message = |addr: &str, args: Vec<OscType>| OscPacket::Message(OscMessage { addr: addr.to_string(), args });
+    let inner = OscPacket::Bundle(OscBundle {
+        timetag: OscTime { seconds: 0, fractional: 1 },
+        content: vec![message("/source/doppler", vec![OscType::Int(2), OscType::Bool(true)])],
+    });
+    let bundle = OscPacket::Bundle(OscBundle {
+        timetag: OscTime { seconds: 0, fractional: 1 },
+        content: vec![
+            message("/obstacle/remove", vec![OscType::Int(-1)]),
+            inner,
+            message("/source/doppler", vec![OscType::Int(MAX_SOURCE_ID as i32), OscType::Bool(true)]),
+        ],
+    });
+
+    let mut messages = VecDeque::new();
+    OSCHandler::parse_osc_packet(bundle, &mut messages);
+    assert_eq!(messages.len(), 3);
+    assert!(matches!(messages[0], OSC_message::Unknown(ref addr) if addr == "/obstacle/remove"));
+    assert!(matches!(messages[1], OSC_message::SourceParameter(Source_parameter::Doppler(2, true))));
+    // ids of virtual sources
+    assert!(matches!(messages[2], OSC_message::Unknown(ref addr) if addr == "/source/doppler"));
+}
@@ -0,0 +1,455 @@
+use std::f32::consts::PI;
+
+use nalgebra::{Point3, Vector3};
+
+use crate::{
+    biquad::{LinkwitzRileyFilterbank, OCTAVE_BAND_CENTRES},
+    image_source_method::ISMRoom,
+};
+
+const N_BANDS: usize = OCTAVE_BAND_CENTRES.len();
+
+// arrival directions: azimuth sectors times elevation ranges (below -30°, level, above 30°)
+const N_AZIMUTH_BINS: usize = 8;
+const N_ELEVATION_BINS: usize = 3;
+pub const N_DIRECTION_BINS: usize = N_AZIMUTH_BINS * N_ELEVATION_BINS;
+const ELEVATION_LIMIT: f32 = PI / 6.0;
+
+type BandEnergies = [f32; N_BANDS];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RayTracerSettings {
+    pub n_rays: usize,
+    // length of the histograms (s)
+    pub max_time: f32,
+    // width of a histogram bin (s)
+    pub time_resolution: f32,
+    // radius of the detection sphere around the listener (m)
+    pub receiver_radius: f32,
+    // rays are dropped once all bands fell by this factor
+    pub energy_threshold: f32,
+    // only rays that were scattered at least once are detected, the specular part is left to
+    // the image sources
+    pub diffuse_only: bool,
+    pub seed: u64,
+}
+
+impl Default for RayTracerSettings {
+    fn default() -> Self {
+        Self {
+            n_rays: 10000,
+            max_time: 2.0,
+            time_resolution: 0.001,
+            receiver_radius: 0.5,
+            energy_threshold: 1e-6,
+            diffuse_only: true,
+            seed: 1,
+        }
+    }
+}
+
+// bands up to 1 kHz, their mean decay time sets the low-frequency decay of the late reverb
+const N_LOW_BANDS: usize = 5;
+
+// Late reverberation of a room, rendered by a FeedbackDelayNetwork.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LateReverb {
+    // per octave band (s), 0 if unknown
+    pub decay_times: [f32; N_BANDS],
+    // energy of the tail below 1 kHz, relative to the direct sound at 1 m
+    pub energy: f32,
+}
+
+impl LateReverb {
+    // same decay time in all bands
+    pub fn broadband(decay_time: f32, energy: f32) -> Self {
+        Self {
+            decay_times: [decay_time; N_BANDS],
+            energy,
+        }
+    }
+
+    // (mean decay time of the known bands up to 1 kHz, decay time of the highest known band),
+    // 0 if no band is known
+    pub fn get_decay_range(&self) -> (f32, f32) {
+        let known = |decay_time: &&f32| **decay_time > 0.0;
+        let (sum, n) = self.decay_times[..N_LOW_BANDS].iter().filter(known).fold((0.0, 0), |(sum, n), t| (sum + t, n + 1));
+        let high = self.decay_times.iter().rev().find(known).copied().unwrap_or(0.0);
+        match n {
+            0 => (high, high),
+            n => (sum / n as f32, high),
+        }
+    }
+}
+
+// Energy arriving at the listener per time bin, arrival direction and octave band. Energies
+// are relative to the direct sound at 1 m distance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnergyHistogram {
+    time_resolution: f32,
+    // [time bin * N_DIRECTION_BINS + direction bin]
+    bins: Vec<BandEnergies>,
+}
+
+impl EnergyHistogram {
+    pub fn new(n_time_bins: usize, time_resolution: f32) -> Self {
+        Self {
+            time_resolution,
+            bins: vec![[0.0; N_BANDS]; n_time_bins * N_DIRECTION_BINS],
+        }
+    }
+
+    pub fn get_time_resolution(&self) -> f32 {
+        self.time_resolution
+    }
+
+    pub fn get_n_time_bins(&self) -> usize {
+        self.bins.len() / N_DIRECTION_BINS
+    }
+
+    pub fn get(&self, time_bin: usize, direction_bin: usize) -> &BandEnergies {
+        &self.bins[time_bin * N_DIRECTION_BINS + direction_bin]
+    }
+
+    fn add(&mut self, time: f32, direction_bin: usize, energies: &BandEnergies) {
+        let time_bin = (time / self.time_resolution) as usize;
+        if time_bin >= self.get_n_time_bins() {
+            return;
+        }
+        let bin = &mut self.bins[time_bin * N_DIRECTION_BINS + direction_bin];
+        bin.iter_mut().zip(energies.iter()).for_each(|(b, e)| *b += e);
+    }
+
+    // energy of a band over time, all directions
+    pub fn get_envelope(&self, band: usize) -> Vec<f32> {
+        self.bins
+            .chunks(N_DIRECTION_BINS)
+            .map(|directions| directions.iter().map(|d| d[band]).sum())
+            .collect()
+    }
+
+    // energy per band from one direction, whole histogram
+    pub fn get_direction_energy(&self, direction_bin: usize) -> BandEnergies {
+        let mut energies = [0.0; N_BANDS];
+        for bin in self.bins.iter().skip(direction_bin).step_by(N_DIRECTION_BINS) {
+            energies.iter_mut().zip(bin.iter()).for_each(|(e, b)| *e += b);
+        }
+        energies
+    }
+
+    pub fn get_total_energy(&self) -> BandEnergies {
+        let mut energies = [0.0; N_BANDS];
+        for bin in self.bins.iter() {
+            energies.iter_mut().zip(bin.iter()).for_each(|(e, b)| *e += b);
+        }
+        energies
+    }
+
+    // reverberation time of a band (s) from the -5 to -25 dB range of the Schroeder
+    // integral (T20), None if the decay is too short
+    pub fn get_decay_time(&self, band: usize) -> Option<f32> {
+        let envelope = self.get_envelope(band);
+        let mut schroeder: Vec<f32> = envelope
+            .iter()
+            .rev()
+            .scan(0.0, |sum, e| {
+                *sum += e;
+                Some(*sum)
+            })
+            .collect();
+        schroeder.reverse();
+        let total = *schroeder.first()?;
+        if total <= 0.0 {
+            return None;
+        }
+        let points: Vec<(f32, f32)> = schroeder
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (i as f32 * self.time_resolution, 10.0 * (e / total).log10()))
+            .skip_while(|(_, level)| *level > -5.0)
+            .take_while(|(_, level)| *level >= -25.0)
+            .collect();
+        if points.len() < 2 || !schroeder.last().is_some_and(|e| 10.0 * (e / total).log10() <= -25.0) {
+            return None;
+        }
+        // least squares slope in dB/s
+        let n = points.len() as f32;
+        let mean_t = points.iter().map(|p| p.0).sum::<f32>() / n;
+        let mean_l = points.iter().map(|p| p.1).sum::<f32>() / n;
+        let covariance: f32 = points.iter().map(|p| (p.0 - mean_t) * (p.1 - mean_l)).sum();
+        let variance: f32 = points.iter().map(|p| (p.0 - mean_t).powi(2)).sum();
+        let slope = covariance / variance;
+        match slope < 0.0 {
+            true => Some(-60.0 / slope),
+            false => None,
+        }
+    }
+
+    // decay times and low-frequency energy of the diffuse tail, None if no decay time up to
+    // 1 kHz could be estimated
+    pub fn get_late_reverb(&self) -> Option<LateReverb> {
+        let decay_times: [f32; N_BANDS] = std::array::from_fn(|band| self.get_decay_time(band).unwrap_or(0.0));
+        if decay_times[..N_LOW_BANDS].iter().all(|decay_time| *decay_time <= 0.0) {
+            return None;
+        }
+        let energy = self.get_total_energy()[..N_LOW_BANDS].iter().sum::<f32>() / N_LOW_BANDS as f32;
+        Some(LateReverb { decay_times, energy })
+    }
+
+    // impulse response of the diffuse part: octave band noise following the band envelopes
+    pub fn synthesize(&self, sample_rate: f32, seed: u64) -> Vec<f32> {
+        let samples_per_bin = (self.time_resolution * sample_rate).max(1.0);
+        let length = (self.get_n_time_bins() as f32 * samples_per_bin) as usize;
+        let mut random = Random::new(seed);
+        let noise: Vec<f32> = (0..length).map(|_| 2.0 * random.next() - 1.0).collect();
+        let mut filterbank = LinkwitzRileyFilterbank::octave_bands(sample_rate);
+        let mut bands = vec![vec![0.0; length]; filterbank.get_n_bands()];
+        filterbank.process(&noise, &mut bands);
+
+        let mut response = vec![0.0; length];
+        for (band, band_noise) in bands.iter().enumerate() {
+            // unit power band noise, scaled to the energy of every bin
+            let power = band_noise.iter().map(|x| x * x).sum::<f32>() / length.max(1) as f32;
+            if power <= 0.0 {
+                continue;
+            }
+            let envelope = self.get_envelope(band);
+            for (i, (r, x)) in response.iter_mut().zip(band_noise.iter()).enumerate() {
+                let energy = envelope[((i as f32 / samples_per_bin) as usize).min(envelope.len() - 1)];
+                *r += x * (energy / (samples_per_bin * power)).sqrt();
+            }
+        }
+        response
+    }
+}
+
+// direction bin of a unit vector (z up)
+pub fn direction_bin(direction: &Vector3<f32>) -> usize {
+    let azimuth = direction[1].atan2(direction[0]).rem_euclid(2.0 * PI);
+    let azimuth_bin = ((azimuth / (2.0 * PI) * N_AZIMUTH_BINS as f32) as usize).min(N_AZIMUTH_BINS - 1);
+    let elevation = direction[2].clamp(-1.0, 1.0).asin();
+    let elevation_bin = match elevation {
+        e if e < -ELEVATION_LIMIT => 0,
+        e if e > ELEVATION_LIMIT => 2,
+        _ => 1,
+    };
+    elevation_bin * N_AZIMUTH_BINS + azimuth_bin
+}
+
+// centre direction of a bin
+pub fn direction_of_bin(direction_bin: usize) -> Vector3<f32> {
+    let azimuth = ((direction_bin % N_AZIMUTH_BINS) as f32 + 0.5) * 2.0 * PI / N_AZIMUTH_BINS as f32;
+    let elevation = match direction_bin / N_AZIMUTH_BINS {
+        0 => -PI / 3.0,
+        2 => PI / 3.0,
+        _ => 0.0,
+    };
+    Vector3::new(azimuth.cos() * elevation.cos(), azimuth.sin() * elevation.cos(), elevation.sin())
+}
+
+// Stochastic ray tracer for the shoebox of an ISMRoom. Reflections are specular or, with the
+// scattering coefficient of the boundary, diffuse (Lambert). Rays passing the sphere around
+// the listener add their energy to the histogram. Obstacles are not traced.
+pub struct RayTracer {
+    bounds: (Point3<f32>, Point3<f32>),
+    // (absorption, scattering) per axis, for the lower and upper boundary
+    faces: [[(f32, f32); 2]; 3],
+    speed_of_sound: f32,
+    // air attenuation per band (energy factor per m)
+    air_attenuation: BandEnergies,
+    settings: RayTracerSettings,
+}
+
+impl RayTracer {
+    pub fn new(room: &ISMRoom, settings: RayTracerSettings) -> Self {
+        let bounds = room.get_bounds();
+        let mut faces = [[(0.0, 0.0); 2]; 3];
+        for boundary in room.get_boundaries() {
+            let axis = boundary.get_axis();
+            let side = (boundary.get_location() >= bounds.1[axis]) as usize;
+            faces[axis][side] = (boundary.get_material().clamp(0.0, 1.0), boundary.get_scattering());
+        }
+        let atmosphere = room.get_atmosphere();
+        Self {
+            bounds,
+            faces,
+            speed_of_sound: room.get_speed_of_sound(),
+            air_attenuation: OCTAVE_BAND_CENTRES.map(|f| 10f32.powf(-atmosphere.attenuation_coefficient(f) / 10.0)),
+            settings,
+        }
+    }
+
+    pub fn get_settings(&self) -> &RayTracerSettings {
+        &self.settings
+    }
+
+    pub fn trace(&self, source: &Point3<f32>, listener: &Point3<f32>) -> EnergyHistogram {
+        let settings = &self.settings;
+        let n_time_bins = (settings.max_time / settings.time_resolution).ceil() as usize;
+        let mut histogram = EnergyHistogram::new(n_time_bins, settings.time_resolution);
+        let (min, max) = self.bounds;
+        let clamp = |p: Point3<f32>| Point3::from(p.coords.zip_zip_map(&min.coords, &max.coords, |p, lo, hi| p.clamp(lo, hi)));
+        let source = clamp(*source);
+        let radius = settings.receiver_radius;
+        // detected energy is weighted with the chord length over the sphere volume, which
+        // makes the direct sound at distance d come out as 1 / d²
+        let sphere_volume = 4.0 / 3.0 * PI * radius.powi(3);
+        let initial_energy = 4.0 * PI / settings.n_rays.max(1) as f32;
+        let max_distance = settings.max_time * self.speed_of_sound;
+        let mut random = Random::new(settings.seed);
+
+        for _ in 0..settings.n_rays {
+            let mut position = source;
+            let mut direction = random.sphere_direction();
+            let mut energy = [initial_energy; N_BANDS];
+            let mut travelled = 0.0;
+            let mut detect = !settings.diffuse_only;
+
+            while travelled < max_distance {
+                // next boundary along the ray
+                let (mut distance, mut hit_axis, mut hit_side) = (f32::INFINITY, 0, 0);
+                for axis in 0..3 {
+                    let (t, side) = match direction[axis] {
+                        d if d > f32::EPSILON => ((max[axis] - position[axis]) / d, 1),
+                        d if d < -f32::EPSILON => ((min[axis] - position[axis]) / d, 0),
+                        _ => continue,
+                    };
+                    if t < distance {
+                        (distance, hit_axis, hit_side) = (t.max(0.0), axis, side);
+                    }
+                }
+                if !distance.is_finite() {
+                    break;
+                }
+
+                if detect {
+                    if let Some((t, chord)) = sphere_chord(&position, &direction, distance, listener, radius) {
+                        let weight = chord / sphere_volume;
+                        let detected = std::array::from_fn(|b| energy[b] * self.air_attenuation[b].powf(t) * weight);
+                        let time = (travelled + t) / self.speed_of_sound;
+                        histogram.add(time, direction_bin(&-direction), &detected);
+                    }
+                }
+
+                position += direction * distance;
+                position[hit_axis] = if hit_side == 1 { max[hit_axis] } else { min[hit_axis] };
+                travelled += distance;
+                let (absorption, scattering) = self.faces[hit_axis][hit_side];
+                for (e, air) in energy.iter_mut().zip(self.air_attenuation.iter()) {
+                    *e *= air.powf(distance) * (1.0 - absorption);
+                }
+                if energy.iter().all(|e| *e < initial_energy * settings.energy_threshold) {
+                    break;
+                }
+
+                if random.next() < scattering {
+                    let mut normal = Vector3::zeros();
+                    normal[hit_axis] = if hit_side == 1 { -1.0 } else { 1.0 };
+                    direction = random.lambert_direction(hit_axis, &normal);
+                    detect = true;
+                } else {
+                    direction[hit_axis] = -direction[hit_axis];
+                }
+            }
+        }
+        histogram
+    }
+}
+
+// (distance to the middle of the chord, chord length) of a ray segment through a sphere
+fn sphere_chord(
+    origin: &Point3<f32>,
+    direction: &Vector3<f32>,
+    length: f32,
+    center: &Point3<f32>,
+    radius: f32,
+) -> Option<(f32, f32)> {
+    let offset = origin - center;
+    let b = offset.dot(direction);
+    let discriminant = b * b - (offset.norm_squared() - radius * radius);
+    if discriminant <= 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let (t_0, t_1) = ((-b - root).max(0.0), (-b + root).min(length));
+    match t_1 > t_0 {
+        true => Some(((t_0 + t_1) / 2.0, t_1 - t_0)),
+        false => None,
+    }
+}
+
+// xorshift64*, keeps the traced responses reproducible
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        Random(seed.max(1))
+    }
+
+    // uniform in [0, 1)
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn sphere_direction(&mut self) -> Vector3<f32> {
+        let z = 2.0 * self.next() - 1.0;
+        let phi = 2.0 * PI * self.next();
+        let r = (1.0 - z * z).sqrt();
+        Vector3::new(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    // cosine distributed around the normal of an axis aligned boundary
+    fn lambert_direction(&mut self, axis: usize, normal: &Vector3<f32>) -> Vector3<f32> {
+        let u = self.next();
+        let phi = 2.0 * PI * self.next();
+        let r = u.sqrt();
+        let mut direction = normal * (1.0 - u).sqrt();
+        direction[(axis + 1) % 3] = r * phi.cos();
+        direction[(axis + 2) % 3] = r * phi.sin();
+        direction
+    }
+}
+
+#[test]
+fn test_diffuse_decay() {
+    let mut room = ISMRoom::new(Vector3::new(10.0, 4.0, 8.0), [0.2; 6], 343.0);
+    for boundary in room.get_boundaries() {
+        room.set_scattering(boundary.get_direction(), 1.0);
+    }
+    let settings = RayTracerSettings {
+        n_rays: 4000,
+        max_time: 1.0,
+        ..RayTracerSettings::default()
+    };
+    let tracer = RayTracer::new(&room, settings);
+    let histogram = tracer.trace(&Point3::new(2.0, 3.0, 1.5), &Point3::new(6.0, 5.0, 2.0));
+
+    // a fully diffuse room decays close to Eyring
+    let (volume, surface) = (320.0, 304.0);
+    let eyring = 0.161 * volume / (-surface * (1.0f32 - 0.2).ln());
+    let decay_time = histogram.get_decay_time(4).unwrap();
+    assert!((decay_time - eyring).abs() / eyring < 0.2, "{decay_time} {eyring}");
+
+    // air absorption shortens the decay at high frequencies
+    assert!(histogram.get_decay_time(7).unwrap() < decay_time);
+
+    let total = histogram.get_total_energy();
+    let by_direction: f32 = (0..N_DIRECTION_BINS).map(|d| histogram.get_direction_energy(d)[4]).sum();
+    assert!((by_direction - total[4]).abs() / total[4] < 1e-3);
+
+    // the late reverb decays like the low bands and ends with the highest band
+    let late_reverb = histogram.get_late_reverb().unwrap();
+    let (low, high) = late_reverb.get_decay_range();
+    assert!((low - eyring).abs() / eyring < 0.2, "{low} {eyring}");
+    assert_eq!(high, histogram.get_decay_time(7).unwrap());
+    let low_energy = (0..5).map(|band| total[band]).sum::<f32>() / 5.0;
+    assert!((late_reverb.energy - low_energy).abs() <= 1e-6 * low_energy);
+
+    let response = histogram.synthesize(48000.0, 7);
+    assert_eq!(response.len(), 48000);
+    let energy: f32 = response.iter().map(|x| x * x).sum();
+    assert!(energy > 0.0 && energy.is_finite());
+}
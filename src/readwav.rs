@@ -75,6 +75,38 @@ pub fn readwav_mono(path: &str) -> Vec<f32> {
     wavfile    
 }
 
+// signal of a sound source and its sample rate, all channels are mixed down. Unlike the
+// functions above, a missing or broken file is an error.
+pub fn read_source_signal(path: &str) -> hound::Result<(Vec<f32>, u32)> {
+    let mut reader = WavReader::open(Path::new(path))?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Int => {
+            let max_val = (2.0f32).powf(spec.bits_per_sample as f32 - 1.0);
+            reader.samples::<i32>().map(|sample| sample.map(|s| s as f32 / max_val)).collect::<hound::Result<_>>()?
+        }
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<hound::Result<_>>()?,
+    };
+    let channels = spec.channels as usize;
+    let signal = samples.chunks(channels).map(|frame| frame.iter().sum::<f32>() / channels as f32).collect();
+    Ok((signal, spec.sample_rate))
+}
+
+#[test]
+fn test_read_source_signal() {
+    let path = std::env::temp_dir().join("test_read_source_signal.wav");
+    let spec = hound::WavSpec { channels: 2, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for sample in [16384i16, 0, -16384, -16384] {
+        writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    let (signal, sample_rate) = read_source_signal(path.to_str().unwrap()).unwrap();
+    assert_eq!(sample_rate, 44100);
+    assert_eq!(signal, vec![0.25, -0.5]);
+    assert!(read_source_signal("./no/such/file.wav").is_err());
+}
 
 #[cfg(test)]
 #[test]
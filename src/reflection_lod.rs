@@ -0,0 +1,325 @@
+use nalgebra::{Point3, Vector3};
+
+use crate::{
+    baked_acoustics::BakedReflection,
+    biquad::OCTAVE_BAND_CENTRES,
+    image_source_method::ISMAcousticScene,
+    ray_tracer::{direction_bin, N_DIRECTION_BINS},
+    transmission::WallTransmission,
+};
+
+// virtual sources per source for reflections, full paths and clusters together
+pub const MAX_REFLECTION_PATHS: usize = 16;
+
+// Reflection as rendered by a virtual source: a single image source path or a cluster of
+// weaker paths from similar directions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReflectionPath {
+    length: f32,
+    // wall reflections and occlusion, without the spreading loss
+    gain: f32,
+    // direction of arrival (world frame)
+    direction: Vector3<f32>,
+    // direction in which the path leaves the source (world frame), None if unknown
+    emission: Option<Vector3<f32>>,
+}
+
+impl ReflectionPath {
+    pub fn new(length: f32, gain: f32, direction: Vector3<f32>) -> Self {
+        Self {
+            length,
+            gain,
+            direction,
+            emission: None,
+        }
+    }
+
+    pub fn with_emission(mut self, emission: Vector3<f32>) -> Self {
+        self.emission = Some(emission);
+        self
+    }
+
+    pub fn get_length(&self) -> f32 {
+        self.length
+    }
+
+    pub fn get_gain(&self) -> f32 {
+        self.gain
+    }
+
+    pub fn get_direction(&self) -> Vector3<f32> {
+        self.direction
+    }
+
+    pub fn get_emission(&self) -> Option<Vector3<f32>> {
+        self.emission
+    }
+
+    pub fn get_level(&self) -> f32 {
+        self.gain / self.length.max(f32::EPSILON)
+    }
+
+    // heard from the (apparent) image source position
+    pub fn to_transmission(&self, listener: &Point3<f32>) -> WallTransmission {
+        WallTransmission::from_band_gains(
+            listener + self.direction * self.length,
+            [self.gain; OCTAVE_BAND_CENTRES.len()],
+        )
+    }
+}
+
+impl From<&BakedReflection> for ReflectionPath {
+    fn from(reflection: &BakedReflection) -> Self {
+        ReflectionPath::new(reflection.get_length(), reflection.get_gain(), reflection.get_direction())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodSettings {
+    // global budget: virtual sources for reflections over all sources, full paths and clusters
+    pub max_paths: usize,
+    // clusters per source for the paths that get no virtual source of their own
+    pub max_clusters: usize,
+    // paths below this level (re 1 m) are dropped
+    pub min_level: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        Self {
+            max_paths: 32,
+            max_clusters: 4,
+            min_level: 1e-3,
+        }
+    }
+}
+
+// Paths of one source after the level of detail selection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourcePaths {
+    full: Vec<ReflectionPath>,
+    clusters: Vec<ReflectionPath>,
+}
+
+impl SourcePaths {
+    pub fn get_full_paths(&self) -> &[ReflectionPath] {
+        &self.full
+    }
+
+    pub fn get_clusters(&self) -> &[ReflectionPath] {
+        &self.clusters
+    }
+
+    // full paths first, at most MAX_REFLECTION_PATHS
+    pub fn get_paths(&self) -> Vec<ReflectionPath> {
+        self.full.iter().chain(self.clusters.iter()).copied().collect()
+    }
+}
+
+// image source paths of a source to the listener of the scene
+pub fn image_source_paths(scene: &ISMAcousticScene, source_idx: usize, listener: &Point3<f32>) -> Vec<ReflectionPath> {
+    let (_, occlusion) = scene.get_path_occlusion(source_idx);
+    let emissions = scene.get_emission_directions(source_idx);
+    scene
+        .get_image_source_positions(source_idx)
+        .iter()
+        .zip(occlusion.iter())
+        .zip(emissions.iter())
+        .filter_map(|((image_source, occlusion), emission)| {
+            let offset = image_source - listener;
+            let direction = offset.try_normalize(f32::EPSILON)?;
+            let gain = scene.get_room().get_reflection_gain(image_source) * occlusion.gain();
+            Some(ReflectionPath::new(offset.norm(), gain, direction).with_emission(*emission))
+        })
+        .collect()
+}
+
+// Perceptual level of detail over the paths of all sources within a global budget of virtual
+// sources. The clusters are reserved first, one per source and round, the louder sources
+// first, so that every source keeps its reflected energy, and never more clusters than a
+// source has occupied direction bins. The rest of the budget goes to the
+// loudest paths, which get their own virtual source (HRTF). The remaining paths of a source are
+// merged by direction into its clusters, which keep their energy, the energy weighted direction
+// and the energy weighted length. Reserved clusters that are left without paths go back to the
+// full paths.
+pub fn select_paths(sources: &[Vec<ReflectionPath>], settings: &LodSettings) -> Vec<SourcePaths> {
+    let max_clusters = settings.max_clusters.min(MAX_REFLECTION_PATHS);
+
+    let mut ranking: Vec<(usize, &ReflectionPath)> = sources
+        .iter()
+        .enumerate()
+        .flat_map(|(source_idx, paths)| paths.iter().map(move |path| (source_idx, path)))
+        .filter(|(_, path)| path.get_level() >= settings.min_level)
+        .collect();
+    ranking.sort_by(|a, b| b.1.get_level().total_cmp(&a.1.get_level()));
+
+    let mut energies = vec![0.0; sources.len()];
+    for (source_idx, path) in ranking.iter() {
+        energies[*source_idx] += path.get_level().powi(2);
+    }
+    // a source gets no more clusters than directions its paths arrive from
+    let n_bins: Vec<usize> = (0..sources.len())
+        .map(|source_idx| n_occupied_bins(ranking.iter().filter(|(idx, _)| *idx == source_idx).map(|(_, path)| *path)))
+        .collect();
+    let mut by_energy: Vec<usize> = (0..sources.len()).collect();
+    by_energy.sort_by(|a, b| energies[*b].total_cmp(&energies[*a]));
+
+    let mut budget = settings.max_paths;
+    let mut cluster_budgets = vec![0; sources.len()];
+    for _ in 0..max_clusters {
+        for source_idx in by_energy.iter().copied() {
+            if budget > 0 && cluster_budgets[source_idx] < n_bins[source_idx].min(max_clusters) {
+                cluster_budgets[source_idx] += 1;
+                budget -= 1;
+            }
+        }
+    }
+
+    let mut selected = vec![SourcePaths::default(); sources.len()];
+    let mut remaining = ranking;
+    loop {
+        let mut rest = Vec::with_capacity(remaining.len());
+        for (source_idx, path) in remaining {
+            let full = &mut selected[source_idx].full;
+            if budget > 0 && full.len() < MAX_REFLECTION_PATHS - cluster_budgets[source_idx] {
+                full.push(*path);
+                budget -= 1;
+            } else {
+                rest.push((source_idx, path));
+            }
+        }
+        remaining = rest;
+        // clusters without paths left in their directions go back to the full paths
+        let mut unused = 0;
+        for (source_idx, cluster_budget) in cluster_budgets.iter_mut().enumerate() {
+            let n_bins = n_occupied_bins(remaining.iter().filter(|(idx, _)| *idx == source_idx).map(|(_, path)| *path));
+            if *cluster_budget > n_bins {
+                unused += *cluster_budget - n_bins;
+                *cluster_budget = n_bins;
+            }
+        }
+        if unused == 0 {
+            break;
+        }
+        budget += unused;
+    }
+    for (source_idx, source_paths) in selected.iter_mut().enumerate() {
+        let paths: Vec<ReflectionPath> = remaining.iter().filter(|(idx, _)| *idx == source_idx).map(|(_, path)| **path).collect();
+        source_paths.clusters = cluster_paths(&paths, cluster_budgets[source_idx]);
+    }
+    selected
+}
+
+fn n_occupied_bins<'a>(paths: impl Iterator<Item = &'a ReflectionPath>) -> usize {
+    let mut occupied = [false; N_DIRECTION_BINS];
+    for path in paths {
+        occupied[direction_bin(&path.direction)] = true;
+    }
+    occupied.iter().filter(|occupied| **occupied).count()
+}
+
+// (energy, energy weighted direction, energy weighted length, energy weighted emission) per
+// cluster, the emission is None if one of the paths has none
+type Cluster = (f32, Vector3<f32>, f32, Option<Vector3<f32>>);
+
+fn cluster_paths(paths: &[ReflectionPath], max_clusters: usize) -> Vec<ReflectionPath> {
+    if max_clusters == 0 {
+        return Vec::new();
+    }
+    let add = |cluster: &mut Cluster, path: &ReflectionPath| {
+        let energy = path.get_level().powi(2);
+        cluster.0 += energy;
+        cluster.1 += energy * path.direction;
+        cluster.2 += energy * path.length;
+        cluster.3 = cluster.3.zip(path.emission).map(|(sum, emission)| sum + energy * emission.normalize());
+    };
+    let mut bins: Vec<Cluster> = vec![(0.0, Vector3::zeros(), 0.0, Some(Vector3::zeros())); N_DIRECTION_BINS];
+    for path in paths.iter() {
+        add(&mut bins[direction_bin(&path.direction)], path);
+    }
+    // the strongest bins are kept, the others join the closest kept bin
+    let mut order: Vec<usize> = (0..N_DIRECTION_BINS).filter(|bin| bins[*bin].0 > 0.0).collect();
+    order.sort_by(|a, b| bins[*b].0.total_cmp(&bins[*a].0));
+    let (kept, merged) = order.split_at(order.len().min(max_clusters));
+    let mut clusters: Vec<Cluster> = kept.iter().map(|bin| bins[*bin]).collect();
+    for bin in merged {
+        let direction = bins[*bin].1;
+        let closest = (0..clusters.len())
+            .max_by(|a, b| clusters[*a].1.normalize().dot(&direction).total_cmp(&clusters[*b].1.normalize().dot(&direction)))
+            .unwrap();
+        clusters[closest].0 += bins[*bin].0;
+        clusters[closest].1 += bins[*bin].1;
+        clusters[closest].2 += bins[*bin].2;
+        clusters[closest].3 = clusters[closest].3.zip(bins[*bin].3).map(|(a, b)| a + b);
+    }
+    clusters
+        .into_iter()
+        .map(|(energy, direction, length, emission)| {
+            let length = length / energy;
+            let direction = direction.try_normalize(f32::EPSILON).unwrap_or_else(Vector3::x);
+            // level sqrt(energy) at the mean length
+            let path = ReflectionPath::new(length, energy.sqrt() * length, direction);
+            match emission.and_then(|emission| emission.try_normalize(f32::EPSILON)) {
+                Some(emission) => path.with_emission(emission),
+                None => path,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_budget_and_clusters() {
+    let path = |length: f32, gain: f32, azimuth: f32| {
+        ReflectionPath::new(length, gain, Vector3::new(azimuth.cos(), azimuth.sin(), 0.0))
+    };
+    // a near source with strong paths and a far one, all paths from eight directions
+    let near: Vec<ReflectionPath> = (0..40).map(|i| path(2.0 + i as f32 * 0.5, 0.8, i as f32 * 0.785)).collect();
+    let far: Vec<ReflectionPath> = (0..40).map(|i| path(10.0 + i as f32 * 0.5, 0.8, i as f32 * 0.785)).collect();
+    let settings = LodSettings {
+        max_paths: 20,
+        max_clusters: 3,
+        min_level: 1e-3,
+    };
+    let selected = select_paths(&[near.clone(), far.clone()], &settings);
+
+    // clusters count against the budget, the full paths go to the near source first, limited
+    // by its virtual sources
+    let n_rendered: usize = selected.iter().map(|source_paths| source_paths.get_paths().len()).sum();
+    assert_eq!(n_rendered, 20);
+    assert_eq!(selected[0].get_full_paths().len(), MAX_REFLECTION_PATHS - 3);
+    assert_eq!(selected[1].get_full_paths().len(), 20 - 2 * 3 - (MAX_REFLECTION_PATHS - 3));
+    assert_eq!(selected[0].get_full_paths()[0], near[0]);
+    for (source_paths, paths) in selected.iter().zip([&near, &far]) {
+        assert_eq!(source_paths.get_clusters().len(), 3);
+        assert!(source_paths.get_paths().len() <= MAX_REFLECTION_PATHS);
+        // no energy is lost
+        let energy = |paths: &[ReflectionPath]| paths.iter().map(|p| p.get_level().powi(2)).sum::<f32>();
+        let rendered = energy(&source_paths.get_paths());
+        assert!((rendered - energy(paths)).abs() < 1e-4 * energy(paths));
+    }
+
+    // more sources than the budget: clusters go to the louder sources, no full paths are left
+    let tight = LodSettings {
+        max_paths: 4,
+        ..settings
+    };
+    let selected = select_paths(&[far.clone(), near.clone(), far.clone()], &tight);
+    let n_clusters: Vec<usize> = selected.iter().map(|source_paths| source_paths.get_clusters().len()).collect();
+    assert_eq!(n_clusters, vec![1, 2, 1]);
+    assert!(selected.iter().all(|source_paths| source_paths.get_full_paths().is_empty()));
+
+    // paths from a single direction need a single cluster, the other reserved clusters become
+    // full paths
+    let same: Vec<ReflectionPath> = (0..40).map(|i| path(2.0 + i as f32 * 0.5, 0.8, 0.0)).collect();
+    let selected = select_paths(&[same], &settings);
+    assert_eq!(selected[0].get_clusters().len(), 1);
+    assert_eq!(selected[0].get_full_paths().len(), MAX_REFLECTION_PATHS - 1);
+    let few = vec![path(2.0, 0.8, 0.0), path(3.0, 0.8, 1.57)];
+    let selected = select_paths(std::slice::from_ref(&few), &settings);
+    assert_eq!(selected[0].get_full_paths(), &few[..]);
+    assert!(selected[0].get_clusters().is_empty());
+
+    // weak paths are dropped
+    let weak = vec![path(100.0, 0.01, 0.0)];
+    assert_eq!(select_paths(&[weak], &settings)[0], SourcePaths::default());
+}
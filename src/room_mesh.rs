@@ -0,0 +1,622 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context};
+use nalgebra::{Matrix4, Point3, Vector3};
+
+use crate::{
+    image_source_method::{ISMAcousticScene, ISMListener, ISMRoom, ISMSoundSource},
+    obstacle::{Obstacle, ObstacleMaterial},
+};
+
+// vertices closer than this are welded, planes closer than this are merged (m)
+pub const DEFAULT_TOLERANCE: f32 = 0.01;
+// faces below this area are too small to matter acoustically (m²)
+pub const DEFAULT_MIN_AREA: f32 = 0.01;
+// ids of the obstacles of an imported mesh start here, the ids below are left to /obstacle/box
+pub const MESH_OBSTACLE_ID: u32 = 1 << 30;
+// largest angle between the normals of merged faces
+const COPLANAR_COS: f32 = 0.9995;
+
+// Absorption and scattering coefficient of a surface, assigned by material name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceMaterial {
+    absorption: f32,
+    scattering: f32,
+}
+
+impl Default for SurfaceMaterial {
+    fn default() -> Self {
+        SurfaceMaterial::PLASTER
+    }
+}
+
+impl SurfaceMaterial {
+    pub const CONCRETE: SurfaceMaterial = SurfaceMaterial::new(0.02, 0.1);
+    pub const PLASTER: SurfaceMaterial = SurfaceMaterial::new(0.05, 0.1);
+    pub const GLASS: SurfaceMaterial = SurfaceMaterial::new(0.05, 0.05);
+    pub const WOOD: SurfaceMaterial = SurfaceMaterial::new(0.1, 0.1);
+    pub const CARPET: SurfaceMaterial = SurfaceMaterial::new(0.3, 0.2);
+    pub const CURTAIN: SurfaceMaterial = SurfaceMaterial::new(0.5, 0.3);
+
+    pub const fn new(absorption: f32, scattering: f32) -> Self {
+        Self {
+            absorption,
+            scattering,
+        }
+    }
+
+    pub fn get_absorption(&self) -> f32 {
+        self.absorption
+    }
+
+    pub fn get_scattering(&self) -> f32 {
+        self.scattering
+    }
+}
+
+// Surface materials by name. Names are matched exactly, then ignoring case; unknown names
+// get the default material.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialTable {
+    materials: HashMap<String, SurfaceMaterial>,
+    default: SurfaceMaterial,
+}
+
+impl MaterialTable {
+    pub fn new(default: SurfaceMaterial) -> Self {
+        Self {
+            materials: HashMap::new(),
+            default,
+        }
+    }
+
+    // the materials above by their lower case names, e.g. "concrete"
+    pub fn common() -> Self {
+        let mut table = MaterialTable::new(SurfaceMaterial::default());
+        for (name, material) in [
+            ("concrete", SurfaceMaterial::CONCRETE),
+            ("plaster", SurfaceMaterial::PLASTER),
+            ("glass", SurfaceMaterial::GLASS),
+            ("wood", SurfaceMaterial::WOOD),
+            ("carpet", SurfaceMaterial::CARPET),
+            ("curtain", SurfaceMaterial::CURTAIN),
+        ] {
+            table.insert(name, material);
+        }
+        table
+    }
+
+    pub fn insert(&mut self, name: &str, material: SurfaceMaterial) {
+        self.materials.insert(name.to_string(), material);
+    }
+
+    pub fn get(&self, name: &str) -> SurfaceMaterial {
+        self.materials
+            .get(name)
+            .or_else(|| self.materials.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, m)| m))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+// Planar part of the mesh with a single material.
+#[derive(Debug, Clone)]
+pub struct MeshFace {
+    triangles: Vec<[Point3<f32>; 3]>,
+    material_name: String,
+    material: SurfaceMaterial,
+}
+
+impl MeshFace {
+    pub fn get_triangles(&self) -> &[[Point3<f32>; 3]] {
+        &self.triangles
+    }
+
+    pub fn get_material_name(&self) -> &str {
+        &self.material_name
+    }
+
+    pub fn get_material(&self) -> SurfaceMaterial {
+        self.material
+    }
+
+    pub fn get_area(&self) -> f32 {
+        self.triangles.iter().map(triangle_area).sum()
+    }
+
+    // area weighted normal
+    pub fn get_normal(&self) -> Vector3<f32> {
+        self.triangles
+            .iter()
+            .map(|[a, b, c]| (b - a).cross(&(c - a)))
+            .sum::<Vector3<f32>>()
+            .try_normalize(f32::EPSILON)
+            .unwrap_or_else(Vector3::zeros)
+    }
+
+    // distance of the plane from the origin along the normal
+    pub fn get_offset(&self) -> f32 {
+        let normal = self.get_normal();
+        let area = self.get_area().max(f32::EPSILON);
+        self.triangles
+            .iter()
+            .map(|t| normal.dot(&(t[0].coords + t[1].coords + t[2].coords)) / 3.0 * triangle_area(t))
+            .sum::<f32>()
+            / area
+    }
+}
+
+// Room geometry imported from a mesh (OBJ, glTF). The enclosing box becomes the boundaries of
+// an ISMRoom with the area weighted materials of the faces on it, everything inside becomes
+// obstacles.
+#[derive(Debug, Clone, Default)]
+pub struct RoomMesh {
+    faces: Vec<MeshFace>,
+}
+
+impl RoomMesh {
+    // one face per triangle, with the name of its material
+    pub fn from_triangles(triangles: Vec<([Point3<f32>; 3], String)>, materials: &MaterialTable) -> Self {
+        let faces = triangles
+            .into_iter()
+            .map(|(triangle, material_name)| MeshFace {
+                triangles: vec![triangle],
+                material: materials.get(&material_name),
+                material_name,
+            })
+            .collect();
+        Self { faces }
+    }
+
+    pub fn from_obj(path: &str, materials: &MaterialTable) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        RoomMesh::parse_obj(&text, materials)
+    }
+
+    // vertices and faces (triangulated as fans) with the material of the last usemtl,
+    // everything else is ignored
+    pub fn parse_obj(text: &str, materials: &MaterialTable) -> anyhow::Result<Self> {
+        let mut vertices: Vec<Point3<f32>> = Vec::new();
+        let mut triangles = Vec::new();
+        let mut material_name = String::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            let error = || anyhow!("invalid OBJ line {}: {}", line_idx + 1, line);
+            match tokens.next() {
+                Some("v") => {
+                    let coordinates: Vec<f32> = tokens.take(3).map(|t| t.parse()).collect::<Result<_, _>>().map_err(|_| error())?;
+                    if coordinates.len() < 3 {
+                        return Err(error());
+                    }
+                    vertices.push(Point3::new(coordinates[0], coordinates[1], coordinates[2]));
+                }
+                Some("f") => {
+                    // v, v/vt, v//vn or v/vt/vn, negative indices count from the end
+                    let indices: Vec<usize> = tokens
+                        .map(|t| {
+                            let index: i64 = t.split('/').next().unwrap_or("").parse().map_err(|_| error())?;
+                            let index = if index < 0 { vertices.len() as i64 + index } else { index - 1 };
+                            match index >= 0 && (index as usize) < vertices.len() {
+                                true => Ok(index as usize),
+                                false => Err(error()),
+                            }
+                        })
+                        .collect::<anyhow::Result<_>>()?;
+                    for k in 2..indices.len() {
+                        let triangle = [vertices[indices[0]], vertices[indices[k - 1]], vertices[indices[k]]];
+                        triangles.push((triangle, material_name.clone()));
+                    }
+                }
+                Some("usemtl") => material_name = tokens.collect::<Vec<_>>().join(" "),
+                _ => {}
+            }
+        }
+        Ok(RoomMesh::from_triangles(triangles, materials))
+    }
+
+    // triangle primitives of all scenes (.gltf with external buffers, or .glb), in world
+    // coordinates. Buffers embedded as data URIs are not supported.
+    pub fn from_gltf(path: &str, materials: &MaterialTable) -> anyhow::Result<Self> {
+        let gltf = gltf::Gltf::open(path).with_context(|| format!("reading {path}"))?;
+        let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        let buffers = gltf
+            .buffers()
+            .map(|buffer| match buffer.source() {
+                gltf::buffer::Source::Bin => gltf.blob.clone().ok_or_else(|| anyhow!("missing binary chunk in {path}")),
+                gltf::buffer::Source::Uri(uri) if uri.starts_with("data:") => bail!("embedded buffers are not supported"),
+                gltf::buffer::Source::Uri(uri) => {
+                    std::fs::read(directory.join(uri)).with_context(|| format!("reading buffer {uri}"))
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut triangles = Vec::new();
+        let mut stack: Vec<(gltf::Node, Matrix4<f32>)> = gltf
+            .scenes()
+            .flat_map(|scene| scene.nodes())
+            .map(|node| (node, Matrix4::identity()))
+            .collect();
+        while let Some((node, parent_transform)) = stack.pop() {
+            let transform = parent_transform * Matrix4::from(node.transform().matrix());
+            stack.extend(node.children().map(|child| (child, transform)));
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+            for primitive in mesh.primitives().filter(|p| p.mode() == gltf::mesh::Mode::Triangles) {
+                let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|b| b.as_slice()));
+                let Some(positions) = reader.read_positions() else {
+                    continue;
+                };
+                let positions: Vec<Point3<f32>> = positions
+                    .map(|p| transform.transform_point(&Point3::from(p)))
+                    .collect();
+                let indices: Vec<usize> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+                    None => (0..positions.len()).collect(),
+                };
+                let material_name = primitive.material().name().unwrap_or_default().to_string();
+                for triangle in indices.chunks_exact(3) {
+                    if triangle.iter().any(|i| *i >= positions.len()) {
+                        bail!("vertex index out of range in {path}");
+                    }
+                    let triangle = [positions[triangle[0]], positions[triangle[1]], positions[triangle[2]]];
+                    triangles.push((triangle, material_name.clone()));
+                }
+            }
+        }
+        Ok(RoomMesh::from_triangles(triangles, materials))
+    }
+
+    // OBJ or glTF by the extension of the file, simplified with the default tolerances
+    pub fn load(path: &str, materials: &MaterialTable) -> anyhow::Result<Self> {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+        let mut mesh = match extension.as_str() {
+            "obj" => RoomMesh::from_obj(path, materials)?,
+            "gltf" | "glb" => RoomMesh::from_gltf(path, materials)?,
+            _ => bail!("unknown mesh format of {path}"),
+        };
+        mesh.simplify(DEFAULT_TOLERANCE, DEFAULT_MIN_AREA);
+        if mesh.faces.is_empty() {
+            bail!("no faces in {path}");
+        }
+        Ok(mesh)
+    }
+
+    pub fn get_faces(&self) -> &[MeshFace] {
+        &self.faces
+    }
+
+    pub fn get_n_triangles(&self) -> usize {
+        self.faces.iter().map(|f| f.triangles.len()).sum()
+    }
+
+    pub fn get_bounds(&self) -> (Point3<f32>, Point3<f32>) {
+        let mut min = Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut max = Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for point in self.faces.iter().flat_map(|f| f.triangles.iter().flatten()) {
+            min = min.inf(point);
+            max = max.sup(point);
+        }
+        (min, max)
+    }
+
+    // e.g. from the coordinates of the file to the coordinates of the scene
+    pub fn transform(&mut self, transform: &Matrix4<f32>) {
+        for point in self.faces.iter_mut().flat_map(|f| f.triangles.iter_mut().flatten()) {
+            *point = transform.transform_point(point);
+        }
+    }
+
+    // Welds vertices closer than tolerance, drops degenerate and duplicate triangles (also
+    // the back faces of two-sided walls), merges adjacent coplanar triangles of the same
+    // material into faces and drops faces smaller than min_area. Faces with a convex outline
+    // are triangulated again as a fan.
+    pub fn simplify(&mut self, tolerance: f32, min_area: f32) {
+        // welded vertices, the first vertex of a grid cell represents it
+        let mut vertices: Vec<Point3<f32>> = Vec::new();
+        let mut vertex_map: HashMap<[i64; 3], usize> = HashMap::new();
+        let mut weld = |p: &Point3<f32>| {
+            let key = [0, 1, 2].map(|axis| (p[axis] / tolerance).round() as i64);
+            *vertex_map.entry(key).or_insert_with(|| {
+                vertices.push(*p);
+                vertices.len() - 1
+            })
+        };
+        let mut material_names: Vec<(String, SurfaceMaterial)> = Vec::new();
+        let mut triangles: Vec<([usize; 3], usize)> = Vec::new();
+        let mut seen: HashSet<[usize; 3]> = HashSet::new();
+        for face in self.faces.iter() {
+            let material_idx = match material_names.iter().position(|(n, _)| *n == face.material_name) {
+                Some(idx) => idx,
+                None => {
+                    material_names.push((face.material_name.clone(), face.material));
+                    material_names.len() - 1
+                }
+            };
+            for triangle in face.triangles.iter() {
+                let indices = triangle.each_ref().map(&mut weld);
+                if indices[0] == indices[1] || indices[1] == indices[2] || indices[0] == indices[2] {
+                    continue;
+                }
+                let mut key = indices;
+                key.sort_unstable();
+                if seen.insert(key) {
+                    triangles.push((indices, material_idx));
+                }
+            }
+        }
+        let points = |t: &[usize; 3]| t.map(|i| vertices[i]);
+        triangles.retain(|(t, _)| triangle_area(&points(t)) > 0.5 * tolerance * tolerance);
+
+        // planes of the triangles
+        let planes: Vec<(Vector3<f32>, f32)> = triangles
+            .iter()
+            .map(|(t, _)| {
+                let [a, b, c] = points(t);
+                let normal = (b - a).cross(&(c - a)).normalize();
+                (normal, normal.dot(&a.coords))
+            })
+            .collect();
+
+        // union of triangles sharing an edge in the same plane
+        let mut parents: Vec<usize> = (0..triangles.len()).collect();
+        fn root(parents: &mut [usize], mut idx: usize) -> usize {
+            while parents[idx] != idx {
+                parents[idx] = parents[parents[idx]];
+                idx = parents[idx];
+            }
+            idx
+        }
+        let mut edges: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for (idx, (t, _)) in triangles.iter().enumerate() {
+            for k in 0..3 {
+                let (a, b) = (t[k], t[(k + 1) % 3]);
+                edges.entry((a.min(b), a.max(b))).or_default().push(idx);
+            }
+        }
+        for neighbours in edges.values() {
+            for (i, a) in neighbours.iter().enumerate() {
+                for b in neighbours[i + 1..].iter() {
+                    let coplanar = planes[*a].0.dot(&planes[*b].0) > COPLANAR_COS
+                        && (planes[*a].1 - planes[*b].1).abs() < tolerance;
+                    if coplanar && triangles[*a].1 == triangles[*b].1 {
+                        let (root_a, root_b) = (root(&mut parents, *a), root(&mut parents, *b));
+                        parents[root_a] = root_b;
+                    }
+                }
+            }
+        }
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for idx in 0..triangles.len() {
+            groups.entry(root(&mut parents, idx)).or_default().push(idx);
+        }
+        let mut groups: Vec<Vec<usize>> = groups.into_values().collect();
+        groups.sort_by_key(|g| g[0]);
+
+        self.faces = groups
+            .into_iter()
+            .filter_map(|group| {
+                let face_triangles: Vec<[usize; 3]> = group.iter().map(|idx| triangles[*idx].0).collect();
+                let (material_name, material) = material_names[triangles[group[0]].1].clone();
+                let normal = planes[group[0]].0;
+                let face_triangles = match convex_outline(&face_triangles, &vertices, &normal, tolerance) {
+                    Some(outline) => (2..outline.len()).map(|k| [outline[0], outline[k - 1], outline[k]]).collect(),
+                    None => face_triangles,
+                };
+                let face = MeshFace {
+                    triangles: face_triangles.iter().map(points).collect(),
+                    material_name,
+                    material,
+                };
+                (face.get_area() >= min_area).then_some(face)
+            })
+            .collect();
+    }
+
+    // enclosing box of the mesh as a room, the materials of the faces on a boundary are
+    // averaged by area, the uncovered rest of the boundary is open (fully absorbing)
+    pub fn to_room(&self, tolerance: f32, speed_of_sound: f32) -> ISMRoom {
+        let (min, max) = self.get_bounds();
+        let extent = max - min;
+        let boundaries = ISMRoom::from_bounds(min, max, [0.0; 6], speed_of_sound).get_boundaries();
+        let mut materials = [0.0; 6];
+        let mut scattering = [None; 6];
+        for (k, boundary) in boundaries.iter().enumerate() {
+            let axis = boundary.get_axis();
+            let boundary_area = extent.product() / extent[axis].max(f32::EPSILON);
+            let (mut covered_area, mut absorption_area, mut scattering_area) = (0.0, 0.0, 0.0);
+            for face in self.faces.iter().filter(|f| is_on_boundary(f, axis, boundary.get_location(), tolerance)) {
+                let area = face.get_area();
+                covered_area += area;
+                absorption_area += area * face.material.absorption;
+                scattering_area += area * face.material.scattering;
+            }
+            let open_area = (boundary_area - covered_area).max(0.0);
+            materials[k] = ((absorption_area + open_area) / boundary_area.max(f32::EPSILON)).min(1.0);
+            if covered_area > 0.0 {
+                scattering[k] = Some(scattering_area / covered_area);
+            }
+        }
+        let mut room = ISMRoom::from_bounds(min, max, materials, speed_of_sound);
+        for (boundary, scattering) in boundaries.iter().zip(scattering) {
+            if let Some(scattering) = scattering {
+                room.set_scattering(boundary.get_direction(), scattering);
+            }
+        }
+        room
+    }
+
+    // faces not on the enclosing box, one mesh obstacle per material with ids from first_id
+    pub fn to_obstacles(&self, tolerance: f32, first_id: u32) -> Vec<Obstacle> {
+        let (min, max) = self.get_bounds();
+        let mut groups: Vec<(&str, Vec<[Point3<f32>; 3]>)> = Vec::new();
+        for face in self.faces.iter() {
+            let on_boundary = (0..3).any(|axis| {
+                is_on_boundary(face, axis, min[axis], tolerance) || is_on_boundary(face, axis, max[axis], tolerance)
+            });
+            if on_boundary {
+                continue;
+            }
+            match groups.iter_mut().find(|(name, _)| *name == face.material_name) {
+                Some((_, triangles)) => triangles.extend_from_slice(&face.triangles),
+                None => groups.push((&face.material_name, face.triangles.clone())),
+            }
+        }
+        groups
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (_, triangles))| Obstacle::from_mesh(first_id + idx as u32, triangles, ObstacleMaterial::default()))
+            .collect()
+    }
+
+    // image source scene of the room with the interior geometry as obstacles
+    pub fn to_acoustic_scene(
+        &self,
+        listener: ISMListener,
+        sound_sources: Vec<ISMSoundSource>,
+        max_order: usize,
+        speed_of_sound: f32,
+    ) -> ISMAcousticScene {
+        let room = self.to_room(DEFAULT_TOLERANCE, speed_of_sound);
+        let mut scene = ISMAcousticScene::new(room, listener, sound_sources, max_order);
+        scene.set_obstacles(self.to_obstacles(DEFAULT_TOLERANCE, MESH_OBSTACLE_ID));
+        scene
+    }
+}
+
+fn triangle_area(triangle: &[Point3<f32>; 3]) -> f32 {
+    let [a, b, c] = triangle;
+    0.5 * (b - a).cross(&(c - a)).norm()
+}
+
+// face perpendicular to the axis, in the plane at location
+fn is_on_boundary(face: &MeshFace, axis: usize, location: f32, tolerance: f32) -> bool {
+    face.get_normal()[axis].abs() > COPLANAR_COS
+        && face.triangles.iter().flatten().all(|p| (p[axis] - location).abs() < tolerance)
+}
+
+// vertices of the outline of a face in winding order, None if the outline is not a single
+// convex loop. Vertices on straight parts of the outline are dropped.
+fn convex_outline(
+    triangles: &[[usize; 3]],
+    vertices: &[Point3<f32>],
+    normal: &Vector3<f32>,
+    tolerance: f32,
+) -> Option<Vec<usize>> {
+    // directed edges without their reverse are on the outline
+    let mut directed: HashMap<(usize, usize), usize> = HashMap::new();
+    for t in triangles.iter() {
+        for k in 0..3 {
+            *directed.entry((t[k], t[(k + 1) % 3])).or_default() += 1;
+        }
+    }
+    let mut next: HashMap<usize, usize> = HashMap::new();
+    for (a, b) in directed.keys().filter(|(a, b)| !directed.contains_key(&(*b, *a))) {
+        if next.insert(*a, *b).is_some() {
+            return None;
+        }
+    }
+    let start = *next.keys().min()?;
+    let mut outline = vec![start];
+    let mut vertex = next[&start];
+    while vertex != start {
+        outline.push(vertex);
+        vertex = *next.get(&vertex)?;
+        if outline.len() > next.len() {
+            return None;
+        }
+    }
+    if outline.len() != next.len() {
+        return None;
+    }
+    let turn = |outline: &[usize], k: usize| {
+        let n = outline.len();
+        let [a, b, c] = [outline[(k + n - 1) % n], outline[k], outline[(k + 1) % n]].map(|i| vertices[i]);
+        (b - a).cross(&(c - b)).dot(normal)
+    };
+    let mut k = 0;
+    while k < outline.len() && outline.len() > 3 {
+        match turn(&outline, k).abs() < tolerance * tolerance {
+            true => {
+                outline.remove(k);
+            }
+            false => k += 1,
+        }
+    }
+    (0..outline.len()).all(|k| turn(&outline, k) > 0.0).then_some(outline)
+}
+
+#[test]
+fn test_obj_room_import() {
+    // 4 x 2.5 x 3 room without the wall at x = 4, floor split into a grid, a two-sided
+    // panel and a degenerate triangle
+    let mut obj = String::new();
+    let (w, h, d) = (4.0, 2.5, 3.0);
+    let quad = |obj: &mut String, corners: [[f32; 3]; 4]| {
+        for c in corners {
+            obj.push_str(&format!("v {} {} {}\n", c[0], c[1], c[2]));
+        }
+        obj.push_str("f -4 -3 -2 -1\n");
+    };
+    obj.push_str("usemtl Carpet\n");
+    for i in 0..4 {
+        for j in 0..3 {
+            let (x, z) = (i as f32, j as f32);
+            quad(&mut obj, [[x, 0.0, z], [x, 0.0, z + 1.0], [x + 1.0, 0.0, z + 1.0], [x + 1.0, 0.0, z]]);
+        }
+    }
+    obj.push_str("usemtl concrete\n");
+    quad(&mut obj, [[0.0, h, 0.0], [w, h, 0.0], [w, h, d], [0.0, h, d]]);
+    quad(&mut obj, [[0.0, 0.0, 0.0], [w, 0.0, 0.0], [w, h, 0.0], [0.0, h, 0.0]]);
+    quad(&mut obj, [[0.0, 0.0, d], [0.0, h, d], [w, h, d], [w, 0.0, d]]);
+    quad(&mut obj, [[0.0, 0.0, 0.0], [0.0, h, 0.0], [0.0, h, d], [0.0, 0.0, d]]);
+    // both sides of the panel
+    obj.push_str("usemtl Wood\n");
+    quad(&mut obj, [[1.0, 0.0, 1.0], [2.0, 0.0, 1.0], [2.0, 2.0, 1.0], [1.0, 2.0, 1.0]]);
+    quad(&mut obj, [[1.0, 0.0, 1.0], [1.0, 2.0, 1.0], [2.0, 2.0, 1.0], [2.0, 0.0, 1.0]]);
+    // degenerate
+    obj.push_str("v 3 1 1\nv 3 1 1.001\nv 3 1.001 1\nf -3 -2 -1\n");
+
+    let mut materials = MaterialTable::new(SurfaceMaterial::PLASTER);
+    materials.insert("Carpet", SurfaceMaterial::CARPET);
+    materials.insert("Concrete", SurfaceMaterial::CONCRETE);
+    let mut mesh = RoomMesh::parse_obj(&obj, &materials).unwrap();
+    assert_eq!(mesh.get_n_triangles(), 24 + 8 + 4 + 1);
+
+    mesh.simplify(DEFAULT_TOLERANCE, DEFAULT_MIN_AREA);
+    // 4 concrete walls, the floor as one quad, one side of the panel
+    assert_eq!(mesh.get_faces().len(), 6);
+    assert_eq!(mesh.get_n_triangles(), 12);
+    let floor = mesh.get_faces().iter().find(|f| f.get_material_name() == "Carpet").unwrap();
+    assert!((floor.get_area() - w * d).abs() < 1e-3);
+    assert!(floor.get_normal()[1] > 0.99);
+
+    // the side without a wall is open
+    let room = mesh.to_room(DEFAULT_TOLERANCE, 343.0);
+    let (min, max) = room.get_bounds();
+    assert!((room.get_volume() - w * h * d).abs() < 1e-3);
+    let mut absorption: Vec<f32> = room.get_boundaries().iter().map(|b| b.get_material()).collect();
+    absorption.sort_by(f32::total_cmp);
+    let expected = [0.02, 0.02, 0.02, 0.02, 0.3, 1.0];
+    assert!(absorption.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-4), "{absorption:?}");
+    assert!(min.coords.iter().all(|c| c.abs() < 1e-6) && (max.coords.sum() - (w + h + d)).abs() < 1e-4);
+
+    let obstacles = mesh.to_obstacles(DEFAULT_TOLERANCE, 10);
+    assert_eq!(obstacles.len(), 1);
+    assert_eq!(obstacles[0].get_id(), 10);
+    assert!(obstacles[0]
+        .get_geometry()
+        .intersects_segment(&Point3::new(1.5, 1.0, 0.5), &Point3::new(1.5, 1.0, 1.5)));
+
+    // as loaded by /room/mesh, the material names are matched ignoring case
+    let path = std::env::temp_dir().join("test_obj_room_import.obj");
+    std::fs::write(&path, &obj).unwrap();
+    let loaded = RoomMesh::load(path.to_str().unwrap(), &MaterialTable::common()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.get_faces().len(), 6);
+    assert!(loaded.get_faces().iter().any(|f| f.get_material() == SurfaceMaterial::CARPET));
+    assert!(RoomMesh::load("room.stl", &materials).is_err());
+}
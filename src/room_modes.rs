@@ -0,0 +1,241 @@
+use std::f32::consts::PI;
+
+use nalgebra::{Point3, Vector3};
+
+use crate::{
+    biquad::{Biquad, BiquadCoefficients, BiquadType},
+    image_source_method::ISMRoom,
+};
+
+// modes above are left to the geometric models
+pub const MAX_MODE_FREQUENCY: f32 = 200.0;
+pub const MAX_MODES: usize = 128;
+
+// lower bound of the wall absorption, keeps the resonators of hard rooms finite
+const MIN_ABSORPTION: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeKind {
+    // one non-zero index
+    Axial,
+    // two non-zero indices
+    Tangential,
+    Oblique,
+}
+
+// Eigenmode of a rigid-walled shoebox, damped by the wall absorption (Morse-Bolt).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoomMode {
+    indices: [usize; 3],
+    frequency: f32,
+    // amplitude decay constant (1/s)
+    damping: f32,
+}
+
+impl RoomMode {
+    pub fn get_indices(&self) -> [usize; 3] {
+        self.indices
+    }
+
+    pub fn get_frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn get_damping(&self) -> f32 {
+        self.damping
+    }
+
+    pub fn get_decay_time(&self) -> f32 {
+        3.0 * 10f32.ln() / self.damping
+    }
+
+    pub fn get_kind(&self) -> ModeKind {
+        match self.indices.iter().filter(|n| **n > 0).count() {
+            1 => ModeKind::Axial,
+            2 => ModeKind::Tangential,
+            _ => ModeKind::Oblique,
+        }
+    }
+
+    // mode shape at a position relative to the room corner
+    pub fn shape(&self, position: &Vector3<f32>, dimensions: &Vector3<f32>) -> f32 {
+        (0..3)
+            .map(|axis| match self.indices[axis] {
+                0 => 1.0,
+                n => (n as f32 * PI * position[axis] / dimensions[axis]).cos(),
+            })
+            .product()
+    }
+
+    // norm of the mode shape over the room, relative to the volume
+    fn normalization(&self) -> f32 {
+        self.indices.iter().map(|n| if *n == 0 { 1.0 } else { 0.5 }).product()
+    }
+}
+
+// all modes of the room up to max_frequency, sorted by frequency
+pub fn compute_modes(room: &ISMRoom, max_frequency: f32) -> Vec<RoomMode> {
+    let (min, max) = room.get_bounds();
+    let dimensions = max - min;
+    let c = room.get_speed_of_sound();
+    let volume = dimensions.product();
+    if volume <= 0.0 {
+        return Vec::new();
+    }
+    // absorption area of the walls perpendicular to every axis
+    let mut absorption_area = [0.0; 3];
+    for boundary in room.get_boundaries() {
+        let axis = boundary.get_axis();
+        let area = volume / dimensions[axis];
+        absorption_area[axis] += area * boundary.get_material().clamp(MIN_ABSORPTION, 1.0);
+    }
+
+    let n_max = [0, 1, 2].map(|axis| (2.0 * dimensions[axis] * max_frequency / c) as usize);
+    let mut modes = Vec::new();
+    for nx in 0..=n_max[0] {
+        for ny in 0..=n_max[1] {
+            for nz in 0..=n_max[2] {
+                let indices = [nx, ny, nz];
+                if indices == [0, 0, 0] {
+                    continue;
+                }
+                let k: f32 = (0..3).map(|axis| (indices[axis] as f32 / dimensions[axis]).powi(2)).sum();
+                let frequency = c / 2.0 * k.sqrt();
+                if frequency > max_frequency {
+                    continue;
+                }
+                // walls perpendicular to an axis with a non-zero index count double
+                let weighted_area: f32 = (0..3)
+                    .map(|axis| absorption_area[axis] * if indices[axis] > 0 { 2.0 } else { 1.0 })
+                    .sum();
+                modes.push(RoomMode {
+                    indices,
+                    frequency,
+                    damping: c * weighted_area / (16.0 * volume),
+                });
+            }
+        }
+    }
+    modes.sort_by(|a, b| a.frequency.total_cmp(&b.frequency));
+    modes
+}
+
+// Resonator per room mode, excited by the source. The gains follow the mode shapes at the
+// source and listener positions, scaled like the modal sum of the Green's function so that
+// they match the direct sound at 1 m. Gain changes are ramped over one block.
+pub struct RoomModeBank {
+    bounds: (Point3<f32>, Point3<f32>),
+    speed_of_sound: f32,
+    modes: Vec<RoomMode>,
+    resonators: Vec<Biquad>,
+    gains: Vec<f32>,
+    target_gains: Vec<f32>,
+}
+
+impl RoomModeBank {
+    pub fn new(room: &ISMRoom, max_frequency: f32, sample_rate: f32) -> Self {
+        let mut modes = compute_modes(room, max_frequency.min(0.45 * sample_rate));
+        modes.truncate(MAX_MODES);
+        // bandwidth of a mode is damping / pi
+        let resonators = modes
+            .iter()
+            .map(|mode| {
+                let q = PI * mode.frequency / mode.damping;
+                Biquad::new(BiquadCoefficients::design(BiquadType::BandPass, mode.frequency, q, sample_rate))
+            })
+            .collect();
+        Self {
+            bounds: room.get_bounds(),
+            speed_of_sound: room.get_speed_of_sound(),
+            gains: vec![0.0; modes.len()],
+            target_gains: vec![0.0; modes.len()],
+            modes,
+            resonators,
+        }
+    }
+
+    pub fn get_modes(&self) -> &[RoomMode] {
+        &self.modes
+    }
+
+    pub fn get_bounds(&self) -> (Point3<f32>, Point3<f32>) {
+        self.bounds
+    }
+
+    pub fn get_gains(&self) -> &[f32] {
+        &self.target_gains
+    }
+
+    pub fn set_positions(&mut self, source: &Point3<f32>, listener: &Point3<f32>) {
+        let (min, max) = self.bounds;
+        let dimensions = max - min;
+        let volume = dimensions.product();
+        let (source, listener) = (source - min, listener - min);
+        let c = self.speed_of_sound;
+        for (gain, mode) in self.target_gains.iter_mut().zip(self.modes.iter()) {
+            let coupling = mode.shape(&source, &dimensions) * mode.shape(&listener, &dimensions);
+            let omega = 2.0 * PI * mode.frequency;
+            // peak of 4 pi c² psi(s) psi(r) / (V Lambda (omega_n² - omega² + 2 j delta omega))
+            *gain = 4.0 * PI * c * c * coupling / (volume * mode.normalization() * 2.0 * mode.damping * omega);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.resonators.iter_mut().for_each(|r| r.reset());
+    }
+
+    // renders one block of the mono input and adds it to both ears of the interleaved stereo output
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        let n_points = input.len() as f32;
+        for ((resonator, gain), target_gain) in self
+            .resonators
+            .iter_mut()
+            .zip(self.gains.iter_mut())
+            .zip(self.target_gains.iter())
+        {
+            let step = (target_gain - *gain) / n_points;
+            for (i, (x, frame)) in input.iter().zip(output.chunks_exact_mut(2)).enumerate() {
+                let y = (*gain + step * (i + 1) as f32) * resonator.process_sample(*x);
+                frame[0] += y;
+                frame[1] += y;
+            }
+            *gain = *target_gain;
+        }
+    }
+}
+
+#[test]
+fn test_shoebox_modes() {
+    let room = ISMRoom::new(Vector3::new(5.0, 3.0, 4.0), [0.1; 6], 343.0);
+    let (min, max) = room.get_bounds();
+    let dimensions = max - min;
+    let modes = compute_modes(&room, MAX_MODE_FREQUENCY);
+
+    // the lowest mode is the axial mode of the longest dimension
+    let longest = dimensions.imax();
+    assert_eq!(modes[0].get_kind(), ModeKind::Axial);
+    assert_eq!(modes[0].get_indices()[longest], 1);
+    assert!((modes[0].get_frequency() - 343.0 / 10.0).abs() < 1e-3);
+    assert!(modes.iter().any(|m| m.get_kind() == ModeKind::Tangential));
+    assert!(modes.iter().any(|m| m.get_kind() == ModeKind::Oblique));
+    assert!(modes.windows(2).all(|m| m[0].get_frequency() <= m[1].get_frequency()));
+
+    // axial modes ring longer than oblique ones, oblique modes decay like Sabine
+    let oblique = modes.iter().find(|m| m.get_kind() == ModeKind::Oblique).unwrap();
+    assert!(modes[0].get_decay_time() > oblique.get_decay_time());
+    let sabine = 0.161 * dimensions.product() / room.get_absorption_area();
+    assert!((oblique.get_decay_time() - sabine).abs() / sabine < 0.01);
+
+    // a listener on the nodal plane does not hear the first mode
+    let mut bank = RoomModeBank::new(&room, MAX_MODE_FREQUENCY, 48000.0);
+    let source = min + Vector3::new(0.5, 0.5, 0.5);
+    let listener = min + dimensions / 2.0;
+    bank.set_positions(&source, &listener);
+    assert!(bank.get_gains()[0].abs() < 1e-3);
+
+    let mut input = vec![0.0; 4800];
+    input[0] = 1.0;
+    let mut output = vec![0.0; 2 * 4800];
+    bank.process(&input, &mut output);
+    assert!(output.iter().all(|y| y.is_finite()) && output.iter().any(|y| y.abs() > 0.0));
+}
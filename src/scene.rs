@@ -1,6 +1,8 @@
+use anyhow::bail;
 use nalgebra::{Point3, Quaternion, UnitQuaternion, Vector3};
 
-use crate::audioSceneHandlerData::{Listener, Scene_data, Transform};
+use crate::audioSceneHandlerData::{Listener, Scene_data, Source_transform, Transform};
+use crate::convolver::MAX_SOURCE_ID;
 
 pub fn update_scene_parameters(scene_data: Scene_data) {
     let listener: Listener = scene_data.listener.unwrap();
@@ -24,6 +26,43 @@ pub fn update_scene_parameters(scene_data: Scene_data) {
     }
 }
 
+// scene data with the stable ids of its sources, in the order of Sources.transforms
+pub struct SceneUpdate {
+    pub source_ids: Vec<u32>,
+    pub scene_data: Scene_data,
+}
+
+// The sources of a scene arrive as Source_transform messages next to the scene data, their
+// transforms replace Sources.transforms. Scenes with sources without id (transforms of the
+// scene data only, or a Source_transform without transform), with an id used twice or an id
+// in the range of the virtual sources are rejected.
+pub fn apply_source_transforms(
+    scene_data: &mut Scene_data,
+    source_transforms: Vec<Source_transform>,
+) -> anyhow::Result<Vec<u32>> {
+    if source_transforms.is_empty() && !scene_data.sources.transforms.is_empty() {
+        bail!("{} sources without id", scene_data.sources.transforms.len());
+    }
+    let mut source_ids = Vec::with_capacity(source_transforms.len());
+    let mut transforms = Vec::with_capacity(source_transforms.len());
+    for source_transform in source_transforms {
+        let id = source_transform.id;
+        if id >= MAX_SOURCE_ID {
+            bail!("source id {id} is not below {MAX_SOURCE_ID}");
+        }
+        if source_ids.contains(&id) {
+            bail!("source id {id} is used twice");
+        }
+        match source_transform.transform.into_option() {
+            Some(transform) => transforms.push(transform),
+            None => bail!("source {id} has no transform"),
+        }
+        source_ids.push(id);
+    }
+    scene_data.sources.mut_or_insert_default().transforms = transforms;
+    Ok(source_ids)
+}
+
 pub fn get_position(t: &Transform) -> Point3<f32> {
     Point3::from_slice(&[t.position.x, t.position.y, t.position.z])
 }
@@ -36,7 +75,7 @@ pub fn get_quaternion(t: &Transform) -> Quaternion<f32> {
     Quaternion::new(w, i, j, k)
 }
 
-fn calculate_azimuth_and_elevation_with_rotation(a: &Transform, b: &Transform) -> (f32, f32, f32) {
+pub fn calculate_azimuth_and_elevation_with_rotation(a: &Transform, b: &Transform) -> (f32, f32, f32) {
     //Rotation<f32,3> {
     // Calculate relative position vector from A to B in world frame
 
@@ -57,10 +96,39 @@ fn calculate_azimuth_and_elevation_with_rotation(a: &Transform, b: &Transform) -
     cartesian_to_spherical(op)
 }
 
-fn cartesian_to_spherical(a: [f32; 3]) -> (f32, f32, f32) {
+// (r, azimuth, elevation) of a direction (world frame) in the local frame of a
+pub fn calculate_azimuth_and_elevation_of_direction(a: &Transform, direction: &Vector3<f32>) -> (f32, f32, f32) {
+    let a_uquat = UnitQuaternion::from_quaternion(get_quaternion(a));
+    let local = a_uquat.transform_vector(direction);
+    cartesian_to_spherical([local.x, local.y, local.z])
+}
+
+pub fn cartesian_to_spherical(a: [f32; 3]) -> (f32, f32, f32) {
     let r = (a[0].powi(2) + a[1].powi(2) + a[2].powi(2)).sqrt();
     let azimuth = a[0].atan2(a[2]);
     let elevation = a[1].atan2((a[2].powi(2) + a[0].powi(2)).sqrt());
 
     (r, azimuth, elevation)
 }
+
+#[test]
+fn test_apply_source_transforms() {
+    let source_transform = |id: u32, x: f32| {
+        let mut source_transform = Source_transform::new();
+        source_transform.id = id;
+        source_transform.transform.mut_or_insert_default().position.mut_or_insert_default().x = x;
+        source_transform
+    };
+
+    // the ids keep the order of the messages, the transforms replace those of the scene data
+    let mut scene_data = Scene_data::new();
+    let source_ids = apply_source_transforms(&mut scene_data, vec![source_transform(7, 1.0), source_transform(3, 2.0)]);
+    assert_eq!(source_ids.unwrap(), vec![7, 3]);
+    assert_eq!(scene_data.sources.transforms[1].position.x, 2.0);
+
+    // duplicate, missing and reserved ids
+    assert!(apply_source_transforms(&mut scene_data, vec![source_transform(7, 1.0), source_transform(7, 2.0)]).is_err());
+    assert!(apply_source_transforms(&mut scene_data, Vec::new()).is_err());
+    assert!(apply_source_transforms(&mut scene_data, vec![Source_transform::new()]).is_err());
+    assert!(apply_source_transforms(&mut scene_data, vec![source_transform(MAX_SOURCE_ID, 1.0)]).is_err());
+}
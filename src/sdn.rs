@@ -0,0 +1,194 @@
+use nalgebra::Point3;
+
+use crate::{
+    delay_line::{propagation_delay, DelayLine, DelayTap, Interpolation},
+    image_source_method::ISMRoom,
+};
+
+// one scattering node per wall
+pub const N_NODES: usize = 6;
+
+// isotropic scattering matrix A = 2 / (N - 1) 1 1^T - I
+const SCATTERING: f32 = 2.0 / (N_NODES - 1) as f32;
+
+// closest a source may get to a node in the source gain (m)
+const MIN_NODE_DISTANCE: f32 = 0.1;
+
+// Scattering Delay Network (De Sena et al., 2015). Every wall holds a node at the first-order
+// reflection point, the nodes are fully connected by delay lines and scatter the incoming
+// waves isotropically. The first-order reflections are rendered exactly, higher orders
+// approximately. The direct sound is not part of the network.
+pub struct ScatteringDelayNetwork {
+    bounds: (Point3<f32>, Point3<f32>),
+    // (axis, location, reflection gain) of the walls
+    walls: [(usize, f32, f32); N_NODES],
+    speed_of_sound: f32,
+    sample_rate: f32,
+    nodes: [Point3<f32>; N_NODES],
+
+    // source to the nodes: one line, one tap per node
+    source_line: DelayLine,
+    source_taps: [DelayTap; N_NODES],
+    source_gains: [f32; N_NODES],
+    // node to node, see line_index
+    node_lines: Vec<(DelayLine, DelayTap)>,
+    // node to listener
+    listener_lines: Vec<(DelayLine, DelayTap)>,
+    listener_gains: [f32; N_NODES],
+    // waves leaving the nodes in the current sample, [from][to]
+    outgoing: [[f32; N_NODES]; N_NODES],
+}
+
+impl ScatteringDelayNetwork {
+    pub fn new(room: &ISMRoom, sample_rate: f32) -> Self {
+        let bounds = room.get_bounds();
+        let mut walls = [(0, 0.0, 0.0); N_NODES];
+        for (wall, boundary) in walls.iter_mut().zip(room.get_boundaries().iter()) {
+            let absorption = boundary.get_material().clamp(0.0, 1.0);
+            *wall = (boundary.get_axis(), boundary.get_location(), (1.0 - absorption).sqrt());
+        }
+        // no path inside the room is longer than its diagonal
+        let speed_of_sound = room.get_speed_of_sound();
+        let max_delay = propagation_delay((bounds.1 - bounds.0).norm(), speed_of_sound, sample_rate).ceil() as usize + 2;
+        let interpolation = Interpolation::Linear;
+        let line = || (DelayLine::new(max_delay, interpolation), DelayTap::new(0.0));
+        Self {
+            bounds,
+            walls,
+            speed_of_sound,
+            sample_rate,
+            nodes: [Point3::origin(); N_NODES],
+            source_line: DelayLine::new(max_delay, interpolation),
+            source_taps: std::array::from_fn(|_| DelayTap::new(0.0)),
+            source_gains: [0.0; N_NODES],
+            node_lines: (0..N_NODES * (N_NODES - 1)).map(|_| line()).collect(),
+            listener_lines: (0..N_NODES).map(|_| line()).collect(),
+            listener_gains: [0.0; N_NODES],
+            outgoing: [[0.0; N_NODES]; N_NODES],
+        }
+    }
+
+    pub fn get_node_positions(&self) -> &[Point3<f32>; N_NODES] {
+        &self.nodes
+    }
+
+    pub fn get_bounds(&self) -> (Point3<f32>, Point3<f32>) {
+        self.bounds
+    }
+
+    // moves the nodes to the reflection points of the new positions, delays and gains are
+    // ramped over ramp_length samples
+    pub fn set_positions(&mut self, source: &Point3<f32>, listener: &Point3<f32>, ramp_length: usize) {
+        let (min, max) = self.bounds;
+        let clamp = |p: &Point3<f32>| Point3::from(p.coords.zip_zip_map(&min.coords, &max.coords, |p, lo, hi| p.clamp(lo, hi)));
+        let (source, listener) = (clamp(source), clamp(listener));
+        let delay = |distance: f32| propagation_delay(distance, self.speed_of_sound, self.sample_rate);
+
+        for (k, (axis, location, _)) in self.walls.iter().enumerate() {
+            self.nodes[k] = reflection_point(&source, &listener, *axis, *location);
+        }
+        for k in 0..N_NODES {
+            let source_distance = (self.nodes[k] - source).norm();
+            let listener_distance = (listener - self.nodes[k]).norm();
+            self.source_taps[k].set_delay(delay(source_distance), ramp_length);
+            // the product of both gains is 1 / path length, as for the image source
+            self.source_gains[k] = 1.0 / source_distance.max(MIN_NODE_DISTANCE);
+            self.listener_gains[k] = 1.0 / (1.0 + listener_distance / source_distance.max(MIN_NODE_DISTANCE));
+            self.listener_lines[k].1.set_delay(delay(listener_distance), ramp_length);
+            for m in (0..N_NODES).filter(|m| *m != k) {
+                // read before the write of the current sample, one sample shorter
+                let node_delay = (delay((self.nodes[m] - self.nodes[k]).norm()) - 1.0).max(0.0);
+                self.node_lines[line_index(k, m)].1.set_delay(node_delay, ramp_length);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.source_line.clear();
+        self.node_lines.iter_mut().chain(self.listener_lines.iter_mut()).for_each(|(line, _)| line.clear());
+        self.outgoing = [[0.0; N_NODES]; N_NODES];
+    }
+
+    // node_outputs[k]: signal arriving at the listener from node k
+    pub fn process(&mut self, input: &[f32], node_outputs: &mut [Vec<f32>]) {
+        for (i, x) in input.iter().enumerate() {
+            self.source_line.write(*x);
+            for (k, node_output) in node_outputs.iter_mut().enumerate().take(N_NODES) {
+                // half of the source pressure enters every incoming wave
+                let source_pressure = 0.5 * self.source_gains[k] * self.source_line.read(&mut self.source_taps[k]);
+                let mut incoming = [0.0; N_NODES];
+                for m in (0..N_NODES).filter(|m| *m != k) {
+                    let (line, tap) = &mut self.node_lines[line_index(m, k)];
+                    incoming[m] = line.read(tap) + source_pressure;
+                }
+                let sum: f32 = incoming.iter().sum();
+                let reflection_gain = self.walls[k].2;
+                for m in (0..N_NODES).filter(|m| *m != k) {
+                    self.outgoing[k][m] = reflection_gain * (SCATTERING * sum - incoming[m]);
+                }
+                let (line, tap) = &mut self.listener_lines[k];
+                line.write(reflection_gain * SCATTERING * sum);
+                node_output[i] = self.listener_gains[k] * line.read(tap);
+            }
+            for k in 0..N_NODES {
+                for m in (0..N_NODES).filter(|m| *m != k) {
+                    self.node_lines[line_index(k, m)].0.write(self.outgoing[k][m]);
+                }
+            }
+        }
+    }
+}
+
+// delay line from node `from` to node `to`
+fn line_index(from: usize, to: usize) -> usize {
+    from * (N_NODES - 1) + if to > from { to - 1 } else { to }
+}
+
+// specular reflection point of the wall at `location` on `axis`
+fn reflection_point(source: &Point3<f32>, listener: &Point3<f32>, axis: usize, location: f32) -> Point3<f32> {
+    let mut image = *source;
+    image[axis] = 2.0 * location - source[axis];
+    let direction = listener - image;
+    let mut point = match direction[axis].abs() > f32::EPSILON {
+        true => image + direction * ((location - image[axis]) / direction[axis]),
+        false => *listener,
+    };
+    point[axis] = location;
+    point
+}
+
+#[test]
+fn test_first_order_reflections() {
+    use nalgebra::Vector3;
+
+    let sample_rate = 48000.0;
+    let room = ISMRoom::new(Vector3::new(6.0, 3.0, 5.0), [0.36; 6], 343.0);
+    let mut sdn = ScatteringDelayNetwork::new(&room, sample_rate);
+    let (source, listener) = (Point3::new(1.0, 2.0, 1.0), Point3::new(3.5, 4.0, 2.0));
+    sdn.set_positions(&source, &listener, 0);
+
+    let length = 24000;
+    let mut input = vec![0.0; length];
+    input[0] = 1.0;
+    let mut outputs = vec![vec![0.0; length]; N_NODES];
+    sdn.process(&input, &mut outputs);
+
+    // the first arrival of every node is the first-order image source: delayed by the path
+    // length, scaled by the reflection gain over the path length
+    for (k, output) in outputs.iter().enumerate() {
+        let node = sdn.get_node_positions()[k];
+        let path_length = (node - source).norm() + (listener - node).norm();
+        let delay = propagation_delay(path_length, 343.0, sample_rate);
+        let first = output.iter().position(|y| y.abs() > 1e-6).unwrap();
+        // both legs interpolate, the impulse spreads over three samples
+        assert!((first as f32 - delay).abs() < 2.0, "{first} {delay}");
+        let energy: f32 = output[first..first + 3].iter().map(|y| y.abs()).sum();
+        assert!((energy - 0.8 / path_length).abs() < 1e-3, "{energy}");
+    }
+
+    // the network decays
+    let window_energy = |start: usize| -> f32 {
+        outputs.iter().map(|o| o[start..start + 4800].iter().map(|y| y * y).sum::<f32>()).sum()
+    };
+    assert!(window_energy(length - 4800) < 1e-3 * window_energy(0));
+}
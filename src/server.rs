@@ -1,34 +1,364 @@
-use std::sync::mpsc::Sender;
+use std::sync::{mpsc::{Receiver, Sender}, Arc};
+use std::thread;
 
 use protobuf::Message;
 
 use crate::{
-    audioSceneHandlerData::Scene_data,
-    image_source_method::ISMAcousticScene,
-    osc::{self, OSCHandler},
+    air_absorption::Atmosphere,
+    audioSceneHandlerData::{Scene_data, Source_transform},
+    audio_module::{Garbage, RoomModel, BUFFER_SIZE},
+    convolver::{ChannelFactory, DIFFRACTION_SOURCES, REFLECTION_SOURCES},
+    baked_acoustics::BakedAcoustics,
+    directivity::{DirectivityStorage, MAX_DIRECTIVITY_SEGMENTS},
+    filter::FFTManager,
+    image_source_method::{ISMAcousticScene, ISMListener, ISMRoom, ISMSoundSource},
+    fdn::FeedbackDelayNetwork,
+    multi_room::{CoupledReverb, MultiRoomScene},
+    ray_tracer::{LateReverb, RayTracerSettings},
+    readwav::read_source_signal,
+    osc::{self, DirectivityCommand, OSCHandler, OSC_message, ObstacleCommand, RoomCommand, Source_parameter},
+    reflection_lod::{select_paths, LodSettings, ReflectionPath},
+    room_mesh::{MaterialTable, RoomMesh, MESH_OBSTACLE_ID},
+    room_modes::{RoomModeBank, MAX_MODE_FREQUENCY},
+    scene::{apply_source_transforms, get_position, SceneUpdate},
+    sdn::{ScatteringDelayNetwork, N_NODES},
 };
-pub fn start_server(port: u32, tx: Sender<Scene_data>) {
+pub fn start_server(
+    port: u32,
+    tx: Sender<SceneUpdate>,
+    parameter_tx: Sender<Source_parameter>,
+    garbage_rx: Receiver<Garbage>,
+    mut channel_factory: ChannelFactory,
+) {
+    // what the audio thread replaced or removed is dropped here instead of on the audio thread
+    thread::spawn(move || garbage_rx.into_iter().for_each(drop));
+    // the channels of new sources are built here, with the settings of the audio thread
+    let sample_rate = channel_factory.get_sample_rate();
+
     // init server
     let mut ip_addr: String = String::new();
     ip_addr = "127.0.0.1".to_string() + ":" + &port.to_string();
     let mut osc_handle = OSCHandler::new(&ip_addr);
 
     let mut acoustic_scene = ISMAcousticScene::default();
+    // connected rooms, only used once rooms were added via OSC
+    let mut multi_room = MultiRoomScene::new();
+    // precomputed early reflections of static sources, replace the image sources
+    let mut baked_acoustics: Option<BakedAcoustics> = None;
+    // budget of reflection virtual sources over all sources
+    let mut lod_settings = LodSettings::default();
+    // stable ids of the sources in scene order, the audio thread knows the sources by id
+    let mut source_ids: Vec<u32> = Vec::new();
+    // reflection paths last sent to the audio thread, per source
+    let mut sent_paths: Vec<Vec<ReflectionPath>> = Vec::new();
+    // number of diffraction paths last sent to the audio thread, per source
+    let mut sent_diffraction: Vec<usize> = Vec::new();
+    // late reverberation of the neighbouring room last sent to the audio thread, per source
+    let mut sent_reverbs: Vec<Option<CoupledReverb>> = Vec::new();
+    // late reverberation of the room of the scene, traced again once the room or the atmosphere
+    // changed, and the one last sent to the audio thread, per source
+    let mut room_reverb: Option<LateReverb> = None;
+    let mut trace_room = true;
+    let mut sent_room_reverbs: Vec<Option<LateReverb>> = Vec::new();
+    // atmospheric conditions of all rooms, the protobuf scene carries none
+    let mut atmosphere = Atmosphere::default();
+    // image sources or a Scattering Delay Network per source, whether the audio thread has the
+    // room model of the current room, per source
+    let mut room_model = RoomModel::default();
+    let mut sent_room_models: Vec<bool> = Vec::new();
+    // whether the audio thread has the room modes of the current room, per source
+    let mut sent_room_modes: Vec<bool> = Vec::new();
+    // imported room geometry, replaces the room of the protobuf scene
+    let mut room_mesh: Option<RoomMesh> = None;
+    let mut mesh_changed = false;
+    // directivity filters are transformed with the block size of the audio thread
+    let mut fft_manager = FFTManager::new(BUFFER_SIZE);
     //let mut scene_data = Scene_data::default();
     loop {
         // receive from adress
-        let byte_string = osc_handle.try_recv();
+        let (byte_string, source_transforms) = match osc_handle.try_recv_message() {
+            OSC_message::SceneData(byte_string, source_transforms) => (byte_string, source_transforms),
+            OSC_message::SourceParameter(parameter) => {
+                parameter_tx.send(parameter).unwrap();
+                continue;
+            }
+            OSC_message::ObstacleCommand(command) => {
+                match command {
+                    ObstacleCommand::Add(obstacle) => acoustic_scene.add_obstacle(obstacle),
+                    ObstacleCommand::Remove(id) => {
+                        acoustic_scene.remove_obstacle(id);
+                    }
+                }
+                continue;
+            }
+            OSC_message::RoomCommand(command) => {
+                if !matches!(command, RoomCommand::SetLod(..)) {
+                    acoustic_scene.invalidate_paths();
+                }
+                match command {
+                    RoomCommand::AddRoom(origin, room) => {
+                        let mut room = *room;
+                        room.set_atmosphere(atmosphere);
+                        multi_room.add_room(origin, room);
+                    }
+                    RoomCommand::AddPortal(portal) => multi_room.add_portal(portal),
+                    RoomCommand::SetPortalOpen(id, open) => multi_room.set_portal_open(id, open),
+                    RoomCommand::LoadProbes(path) => match BakedAcoustics::load(&path) {
+                        Ok(baked) => baked_acoustics = Some(baked),
+                        Err(error) => eprintln!("Could not load baked acoustics {path}: {error}"),
+                    },
+                    RoomCommand::SetLod(max_paths, max_clusters) => {
+                        lod_settings.max_paths = max_paths;
+                        lod_settings.max_clusters = max_clusters;
+                    }
+                    RoomCommand::SetAtmosphere(new_atmosphere) => {
+                        atmosphere = new_atmosphere;
+                        acoustic_scene.set_atmosphere(atmosphere);
+                        multi_room.set_atmosphere(atmosphere);
+                        parameter_tx.send(Source_parameter::Atmosphere(atmosphere)).unwrap();
+                        channel_factory.set_atmosphere(atmosphere);
+                        sent_room_models.clear();
+                        sent_room_modes.clear();
+                        trace_room = true;
+                    }
+                    RoomCommand::SetRoomModel(new_room_model) => {
+                        room_model = new_room_model;
+                        sent_room_models.clear();
+                    }
+                    RoomCommand::LoadMesh(path) => match RoomMesh::load(&path, &MaterialTable::common()) {
+                        Ok(mesh) => {
+                            room_mesh = Some(mesh);
+                            mesh_changed = true;
+                        }
+                        Err(error) => eprintln!("Could not load the room mesh {path}: {error}"),
+                    },
+                }
+                continue;
+            }
+            OSC_message::DirectivityCommand(command) => {
+                let (id, directivity) = match command {
+                    DirectivityCommand::Pattern(id, pattern) => {
+                        (id, Ok(DirectivityStorage::from_pattern(pattern, &mut fft_manager, BUFFER_SIZE)))
+                    }
+                    DirectivityCommand::Measured(id, filterpath, anglepath, filter_length) => {
+                        (id, DirectivityStorage::from_files(&filterpath, &anglepath, filter_length, &mut fft_manager, BUFFER_SIZE))
+                    }
+                };
+                match directivity {
+                    // omnidirectional sources need no directivity stage
+                    Ok(directivity) if directivity.is_omnidirectional() => {
+                        parameter_tx.send(Source_parameter::Directivity(id, None)).unwrap()
+                    }
+                    Ok(directivity) if directivity.get_n_segments() <= MAX_DIRECTIVITY_SEGMENTS => parameter_tx
+                        .send(Source_parameter::Directivity(id, Some(Arc::new(directivity))))
+                        .unwrap(),
+                    Ok(_) => eprintln!("Directivity filters of source {id} are longer than {MAX_DIRECTIVITY_SEGMENTS} blocks"),
+                    Err(error) => eprintln!("Could not load the directivity of source {id}: {error}"),
+                }
+                continue;
+            }
+            OSC_message::SourceSignal(id, path) => {
+                let signal = match path.map(|path| read_source_signal(&path)).transpose() {
+                    Ok(signal) => signal,
+                    Err(error) => {
+                        eprintln!("Could not load the signal of source {id}: {error}");
+                        continue;
+                    }
+                };
+                if let Some((_, signal_rate)) = signal.as_ref().filter(|(_, rate)| *rate as f32 != sample_rate) {
+                    eprintln!("The signal of source {id} has {signal_rate} Hz, it is played at {sample_rate} Hz");
+                }
+                let signal = signal.map(|(signal, _)| Arc::from(signal));
+                parameter_tx.send(Source_parameter::Signal(id, signal)).unwrap();
+                continue;
+            }
+            OSC_message::Unknown(addr) => {
+                eprintln!("Ignoring invalid OSC message {addr}");
+                continue;
+            }
+        };
 
         // parse byte string to protobuf struct
-        let scene_data = Scene_data::parse_from_bytes(&byte_string[..]).unwrap();
-        acoustic_scene.from_protobuf_scene(&scene_data);
-        // Do something with scene;
+        let source_transforms: Result<Vec<_>, _> =
+            source_transforms.iter().map(|bytes| Source_transform::parse_from_bytes(bytes)).collect();
+        let (mut scene_data, source_transforms) = match (Scene_data::parse_from_bytes(&byte_string[..]), source_transforms) {
+            (Ok(scene_data), Ok(source_transforms)) => (scene_data, source_transforms),
+            (Err(error), _) | (_, Err(error)) => {
+                eprintln!("Ignoring invalid scene: {error}");
+                continue;
+            }
+        };
+        // the audio thread knows the sources by id, scenes without unique ids are dropped
+        let new_source_ids = match apply_source_transforms(&mut scene_data, source_transforms) {
+            Ok(source_ids) => source_ids,
+            Err(error) => {
+                eprintln!("Ignoring scene: {error}");
+                continue;
+            }
+        };
+        let room_changed = match room_mesh {
+            Some(_) => mesh_changed,
+            None => acoustic_scene.get_room().get_bounds() != ISMRoom::from_scene_data(&scene_data).get_bounds(),
+        };
+        let sources_changed = source_ids != new_source_ids;
+        if room_changed || sources_changed {
+            // the image sources are allocated per source and room, rebuild the scene
+            let obstacles = acoustic_scene.take_obstacles();
+            acoustic_scene = match room_mesh.as_ref() {
+                // the image sources only know box shaped rooms: the enclosing box of the mesh
+                // is the room, all faces inside the box become obstacles. Rooms that are not
+                // boxes are approximated by their bounding box.
+                Some(mesh) => mesh.to_acoustic_scene(
+                    ISMListener::from_scene_data(&scene_data),
+                    scene_data.sources.transforms.iter().map(ISMSoundSource::from_transform).collect(),
+                    2,
+                    atmosphere.speed_of_sound(),
+                ),
+                None => ISMAcousticScene::from_scene_data(&scene_data),
+            };
+            mesh_changed = false;
+            // the obstacles of the previous mesh are replaced
+            for obstacle in obstacles.into_iter().filter(|obstacle| obstacle.get_id() < MESH_OBSTACLE_ID) {
+                acoustic_scene.add_obstacle(obstacle);
+            }
+            acoustic_scene.set_atmosphere(atmosphere);
+            sent_room_models.clear();
+            sent_room_modes.clear();
+            trace_room = true;
+            if sources_changed {
+                let n_new_sources = new_source_ids.iter().filter(|id| !source_ids.contains(id)).count();
+                source_ids = new_source_ids;
+                let channels = channel_factory.new_channels(n_new_sources);
+                parameter_tx.send(Source_parameter::Sources(source_ids.clone(), channels)).unwrap();
+                sent_paths.clear();
+                sent_diffraction.clear();
+                sent_reverbs.clear();
+                sent_room_reverbs.clear();
+            }
+        } else {
+            acoustic_scene.from_protobuf_scene(&scene_data);
+        }
+        // the diffuse field of a room hardly depends on the positions, one trace from the first
+        // source serves all sources
+        if trace_room {
+            let settings = RayTracerSettings {
+                n_rays: 2000,
+                ..Default::default()
+            };
+            room_reverb = match acoustic_scene.get_n_sources() {
+                0 => None,
+                _ => acoustic_scene.trace_rays(0, &settings).get_late_reverb(),
+            };
+            trace_room = false;
+        }
+
+        // occlusion and wall transmission of the direct paths
+        let listener_position = get_position(&scene_data.listener.transform);
+        sent_paths.resize(acoustic_scene.get_n_sources(), Vec::new());
+        sent_diffraction.resize(acoustic_scene.get_n_sources(), 0);
+        sent_reverbs.resize(acoustic_scene.get_n_sources(), None);
+        sent_room_reverbs.resize(acoustic_scene.get_n_sources(), None);
+        sent_room_models.resize(acoustic_scene.get_n_sources(), false);
+        sent_room_modes.resize(acoustic_scene.get_n_sources(), false);
+        for (source_idx, &source_id) in source_ids.iter().enumerate() {
+            // networks and mode banks are built here, the audio thread only moves them
+            if !sent_room_models[source_idx] {
+                let sdn = match room_model {
+                    RoomModel::ScatteringDelayNetwork => {
+                        Some(Box::new(ScatteringDelayNetwork::new(acoustic_scene.get_room(), sample_rate)))
+                    }
+                    RoomModel::ImageSources => None,
+                };
+                // the nodes of a network the source already has keep their channels, the spare
+                // ones come back with the previous network
+                let channels = channel_factory.new_channels(if sdn.is_some() { N_NODES } else { 0 });
+                parameter_tx.send(Source_parameter::RoomModel(source_id, sdn, channels)).unwrap();
+                sent_room_models[source_idx] = true;
+            }
+            if !sent_room_modes[source_idx] {
+                let modes = RoomModeBank::new(acoustic_scene.get_room(), MAX_MODE_FREQUENCY, sample_rate);
+                parameter_tx.send(Source_parameter::RoomModes(source_id, Some(Box::new(modes)))).unwrap();
+                sent_room_modes[source_idx] = true;
+            }
+            // nothing moved (beyond the threshold) since the last update of this source
+            if !acoustic_scene.needs_update(source_idx) {
+                continue;
+            }
+            let occlusion = acoustic_scene.get_direct_occlusion(source_idx);
+            parameter_tx
+                .send(Source_parameter::Occlusion(source_id, occlusion))
+                .unwrap();
+            // sources in a neighbouring room are heard through the dominant portal path
+            let source_position = get_position(&scene_data.sources.transforms[source_idx]);
+            let transmission = match multi_room.find_best_portal_path(&source_position, &listener_position) {
+                Some(portal_path) => Some(portal_path.to_transmission()),
+                None => acoustic_scene.get_transmission(source_idx),
+            };
+            parameter_tx
+                .send(Source_parameter::Transmission(source_id, transmission))
+                .unwrap();
+            // the room of the source reverberates into the room of the listener, the network
+            // is rebuilt only if its room, reverberation time or coupling changed
+            let coupled_reverb = multi_room.get_coupled_reverb(&source_position, &listener_position);
+            if coupled_reverb != sent_reverbs[source_idx] {
+                let fdn = coupled_reverb.map(|reverb| Box::new(FeedbackDelayNetwork::new(reverb, sample_rate)));
+                parameter_tx
+                    .send(Source_parameter::CoupledReverb(source_id, fdn))
+                    .unwrap();
+                sent_reverbs[source_idx] = coupled_reverb;
+            }
+            let diffraction_paths = acoustic_scene.get_diffraction_paths(source_idx);
+            let n_paths = diffraction_paths.len().min(DIFFRACTION_SOURCES.len());
+            let channels = channel_factory.new_channels(n_paths.saturating_sub(sent_diffraction[source_idx]));
+            parameter_tx
+                .send(Source_parameter::Diffraction(source_id, diffraction_paths, channels))
+                .unwrap();
+            sent_diffraction[source_idx] = n_paths;
+            // the room of the listener reverberates for sources in the same room
+            let same_room = multi_room.find_room(&source_position) == multi_room.find_room(&listener_position);
+            let mut source_room_reverb = room_reverb.filter(|_| same_room);
+            if room_model == RoomModel::ScatteringDelayNetwork {
+                // the network renders the reflections and the tail, no image sources are computed
+                acoustic_scene.set_reflection_paths(source_idx, Vec::new());
+                source_room_reverb = None;
+            } else if let Some(grid) = baked_acoustics.as_ref().and_then(|baked| baked.find_grid(&source_position)) {
+                // sources at a baked position take their reflections and the late reverberation
+                // from the probes around the listener
+                let probe = grid.interpolate(&listener_position);
+                let paths = probe.get_reflections().iter().map(ReflectionPath::from).collect();
+                acoustic_scene.set_reflection_paths(source_idx, paths);
+                source_room_reverb = probe.get_late_reverb().filter(|_| same_room).or(source_room_reverb);
+            }
+            // networks with new decay times continue the tail of the previous one
+            if source_room_reverb != sent_room_reverbs[source_idx] {
+                let fdn = source_room_reverb.map(|reverb| Box::new(FeedbackDelayNetwork::from_late_reverb(reverb, sample_rate)));
+                parameter_tx.send(Source_parameter::RoomReverb(source_id, fdn)).unwrap();
+                sent_room_reverbs[source_idx] = source_room_reverb;
+            }
+        }
+        // the scene caches the paths and recomputes them only for sources that moved
+        let source_paths: Vec<Vec<ReflectionPath>> = (0..acoustic_scene.get_n_sources())
+            .map(|source_idx| acoustic_scene.get_reflection_paths(source_idx).to_vec())
+            .collect();
+        // reflections of all sources share the budget of virtual sources, only changes are sent
+        for (source_idx, paths) in select_paths(&source_paths, &lod_settings).iter().enumerate() {
+            let paths = paths.get_paths();
+            if paths != sent_paths[source_idx] {
+                let n_virtual_sources = |n_paths: usize| n_paths.min(REFLECTION_SOURCES.len());
+                let n_new_paths = n_virtual_sources(paths.len()).saturating_sub(n_virtual_sources(sent_paths[source_idx].len()));
+                let channels = channel_factory.new_channels(n_new_paths);
+                parameter_tx
+                    .send(Source_parameter::Reflections(source_ids[source_idx], listener_position, paths.clone(), channels))
+                    .unwrap();
+                sent_paths[source_idx] = paths;
+            }
+        }
 
         // calc delays
         // updateRoom
         //
         // update audio engine
-        let _ = tx.send(acoustic_scene).unwrap();
+        tx.send(SceneUpdate { source_ids: source_ids.clone(), scene_data }).unwrap();
         //
     }
 }
@@ -0,0 +1,380 @@
+use num_complex::Complex;
+
+// Spectrum with split real/imaginary storage, so the kernels below can load
+// several bins per instruction.
+#[derive(Debug, Clone, Default)]
+pub struct SplitComplex {
+    pub re: Vec<f32>,
+    pub im: Vec<f32>,
+}
+
+impl SplitComplex {
+    pub fn zeros(length: usize) -> Self {
+        Self {
+            re: vec![0.0; length],
+            im: vec![0.0; length],
+        }
+    }
+
+    pub fn from_interleaved(data: &[Complex<f32>]) -> Self {
+        let mut split = SplitComplex::zeros(data.len());
+        split.copy_from_interleaved(data);
+        split
+    }
+
+    // the FFT writes interleaved spectra, this is the one conversion per block on the way into
+    // the input history. Dispatches like complex_mac.
+    pub fn copy_from_interleaved(&mut self, data: &[Complex<f32>]) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            unsafe { deinterleave_sse(self, data) };
+            return;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            unsafe { deinterleave_neon(self, data) };
+            return;
+        }
+        #[allow(unreachable_code)]
+        deinterleave_scalar_range(self, data, 0, self.len().min(data.len()));
+    }
+
+    pub fn copy_to_interleaved(&self, data: &mut [Complex<f32>]) {
+        for (i, c) in data.iter_mut().enumerate() {
+            c.re = self.re[i];
+            c.im = self.im[i];
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.re.iter_mut().for_each(|v| *v = 0.0);
+        self.im.iter_mut().for_each(|v| *v = 0.0);
+    }
+
+    pub fn len(&self) -> usize {
+        self.re.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.re.is_empty()
+    }
+}
+
+// acc += a * b (bin-wise complex multiply-accumulate).
+// Dispatches at runtime to AVX/SSE on x86_64 and NEON on aarch64. All paths use the
+// same operation order as the scalar path (no FMA), so results are bit-identical.
+pub fn complex_mac(acc: &mut SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx") {
+            unsafe { complex_mac_avx(acc, a, b) };
+            return;
+        }
+        // SSE is part of the x86_64 baseline
+        unsafe { complex_mac_sse(acc, a, b) };
+        return;
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { complex_mac_neon(acc, a, b) };
+        return;
+    }
+    #[allow(unreachable_code)]
+    complex_mac_scalar(acc, a, b);
+}
+
+pub fn complex_mac_scalar(acc: &mut SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    let n = acc.len().min(a.len()).min(b.len());
+    complex_mac_scalar_range(acc, a, b, 0, n);
+}
+
+fn complex_mac_scalar_range(acc: &mut SplitComplex, a: &SplitComplex, b: &SplitComplex, start: usize, end: usize) {
+    for i in start..end {
+        acc.re[i] += a.re[i] * b.re[i] - a.im[i] * b.im[i];
+        acc.im[i] += a.re[i] * b.im[i] + a.im[i] * b.re[i];
+    }
+}
+
+// output = acc + a * b, written interleaved for the inverse FFT. Adds the last segment of a
+// convolution without a separate conversion pass, bit-identical to complex_mac followed by
+// copy_to_interleaved.
+pub fn complex_mac_interleaved(output: &mut [Complex<f32>], acc: &SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx") {
+            unsafe { complex_mac_interleaved_avx(output, acc, a, b) };
+            return;
+        }
+        unsafe { complex_mac_interleaved_sse(output, acc, a, b) };
+        return;
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { complex_mac_interleaved_neon(output, acc, a, b) };
+        return;
+    }
+    #[allow(unreachable_code)]
+    complex_mac_interleaved_scalar(output, acc, a, b);
+}
+
+pub fn complex_mac_interleaved_scalar(output: &mut [Complex<f32>], acc: &SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    let n = output.len().min(acc.len()).min(a.len()).min(b.len());
+    complex_mac_interleaved_scalar_range(output, acc, a, b, 0, n);
+}
+
+fn complex_mac_interleaved_scalar_range(output: &mut [Complex<f32>], acc: &SplitComplex, a: &SplitComplex, b: &SplitComplex, start: usize, end: usize) {
+    for (i, c) in (start..end).zip(output[start..end].iter_mut()) {
+        c.re = acc.re[i] + (a.re[i] * b.re[i] - a.im[i] * b.im[i]);
+        c.im = acc.im[i] + (a.re[i] * b.im[i] + a.im[i] * b.re[i]);
+    }
+}
+
+fn deinterleave_scalar_range(split: &mut SplitComplex, data: &[Complex<f32>], start: usize, end: usize) {
+    for (i, c) in (start..end).zip(data[start..end].iter()) {
+        split.re[i] = c.re;
+        split.im[i] = c.im;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn complex_mac_avx(acc: &mut SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    use std::arch::x86_64::*;
+    let n = acc.len().min(a.len()).min(b.len());
+    let n_simd = n - n % 8;
+    let mut i = 0;
+    while i < n_simd {
+        let ar = _mm256_loadu_ps(a.re.as_ptr().add(i));
+        let ai = _mm256_loadu_ps(a.im.as_ptr().add(i));
+        let br = _mm256_loadu_ps(b.re.as_ptr().add(i));
+        let bi = _mm256_loadu_ps(b.im.as_ptr().add(i));
+        let re = _mm256_sub_ps(_mm256_mul_ps(ar, br), _mm256_mul_ps(ai, bi));
+        let im = _mm256_add_ps(_mm256_mul_ps(ar, bi), _mm256_mul_ps(ai, br));
+        let acc_re = acc.re.as_mut_ptr().add(i);
+        let acc_im = acc.im.as_mut_ptr().add(i);
+        _mm256_storeu_ps(acc_re, _mm256_add_ps(_mm256_loadu_ps(acc_re), re));
+        _mm256_storeu_ps(acc_im, _mm256_add_ps(_mm256_loadu_ps(acc_im), im));
+        i += 8;
+    }
+    complex_mac_scalar_range(acc, a, b, n_simd, n);
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn complex_mac_sse(acc: &mut SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    use std::arch::x86_64::*;
+    let n = acc.len().min(a.len()).min(b.len());
+    let n_simd = n - n % 4;
+    let mut i = 0;
+    while i < n_simd {
+        let ar = _mm_loadu_ps(a.re.as_ptr().add(i));
+        let ai = _mm_loadu_ps(a.im.as_ptr().add(i));
+        let br = _mm_loadu_ps(b.re.as_ptr().add(i));
+        let bi = _mm_loadu_ps(b.im.as_ptr().add(i));
+        let re = _mm_sub_ps(_mm_mul_ps(ar, br), _mm_mul_ps(ai, bi));
+        let im = _mm_add_ps(_mm_mul_ps(ar, bi), _mm_mul_ps(ai, br));
+        let acc_re = acc.re.as_mut_ptr().add(i);
+        let acc_im = acc.im.as_mut_ptr().add(i);
+        _mm_storeu_ps(acc_re, _mm_add_ps(_mm_loadu_ps(acc_re), re));
+        _mm_storeu_ps(acc_im, _mm_add_ps(_mm_loadu_ps(acc_im), im));
+        i += 4;
+    }
+    complex_mac_scalar_range(acc, a, b, n_simd, n);
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn complex_mac_neon(acc: &mut SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    use std::arch::aarch64::*;
+    let n = acc.len().min(a.len()).min(b.len());
+    let n_simd = n - n % 4;
+    let mut i = 0;
+    while i < n_simd {
+        let ar = vld1q_f32(a.re.as_ptr().add(i));
+        let ai = vld1q_f32(a.im.as_ptr().add(i));
+        let br = vld1q_f32(b.re.as_ptr().add(i));
+        let bi = vld1q_f32(b.im.as_ptr().add(i));
+        let re = vsubq_f32(vmulq_f32(ar, br), vmulq_f32(ai, bi));
+        let im = vaddq_f32(vmulq_f32(ar, bi), vmulq_f32(ai, br));
+        let acc_re = acc.re.as_mut_ptr().add(i);
+        let acc_im = acc.im.as_mut_ptr().add(i);
+        vst1q_f32(acc_re, vaddq_f32(vld1q_f32(acc_re), re));
+        vst1q_f32(acc_im, vaddq_f32(vld1q_f32(acc_im), im));
+        i += 4;
+    }
+    complex_mac_scalar_range(acc, a, b, n_simd, n);
+}
+
+// Complex<f32> is repr(C) (re, im), so interleaved spectra are read and written as f32 pairs
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn deinterleave_sse(split: &mut SplitComplex, data: &[Complex<f32>]) {
+    use std::arch::x86_64::*;
+    let n = split.len().min(data.len());
+    let n_simd = n - n % 4;
+    let src = data.as_ptr() as *const f32;
+    let mut i = 0;
+    while i < n_simd {
+        let lo = _mm_loadu_ps(src.add(2 * i));
+        let hi = _mm_loadu_ps(src.add(2 * i + 4));
+        _mm_storeu_ps(split.re.as_mut_ptr().add(i), _mm_shuffle_ps::<0b10_00_10_00>(lo, hi));
+        _mm_storeu_ps(split.im.as_mut_ptr().add(i), _mm_shuffle_ps::<0b11_01_11_01>(lo, hi));
+        i += 4;
+    }
+    deinterleave_scalar_range(split, data, n_simd, n);
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn deinterleave_neon(split: &mut SplitComplex, data: &[Complex<f32>]) {
+    use std::arch::aarch64::*;
+    let n = split.len().min(data.len());
+    let n_simd = n - n % 4;
+    let src = data.as_ptr() as *const f32;
+    let mut i = 0;
+    while i < n_simd {
+        let pairs = vld2q_f32(src.add(2 * i));
+        vst1q_f32(split.re.as_mut_ptr().add(i), pairs.0);
+        vst1q_f32(split.im.as_mut_ptr().add(i), pairs.1);
+        i += 4;
+    }
+    deinterleave_scalar_range(split, data, n_simd, n);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn complex_mac_interleaved_avx(output: &mut [Complex<f32>], acc: &SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    use std::arch::x86_64::*;
+    let n = output.len().min(acc.len()).min(a.len()).min(b.len());
+    let n_simd = n - n % 8;
+    let dst = output.as_mut_ptr() as *mut f32;
+    let mut i = 0;
+    while i < n_simd {
+        let ar = _mm256_loadu_ps(a.re.as_ptr().add(i));
+        let ai = _mm256_loadu_ps(a.im.as_ptr().add(i));
+        let br = _mm256_loadu_ps(b.re.as_ptr().add(i));
+        let bi = _mm256_loadu_ps(b.im.as_ptr().add(i));
+        let re = _mm256_sub_ps(_mm256_mul_ps(ar, br), _mm256_mul_ps(ai, bi));
+        let im = _mm256_add_ps(_mm256_mul_ps(ar, bi), _mm256_mul_ps(ai, br));
+        let re = _mm256_add_ps(_mm256_loadu_ps(acc.re.as_ptr().add(i)), re);
+        let im = _mm256_add_ps(_mm256_loadu_ps(acc.im.as_ptr().add(i)), im);
+        // unpack works per 128 bit lane: (0, 1 | 4, 5) and (2, 3 | 6, 7)
+        let lo = _mm256_unpacklo_ps(re, im);
+        let hi = _mm256_unpackhi_ps(re, im);
+        _mm256_storeu_ps(dst.add(2 * i), _mm256_permute2f128_ps::<0x20>(lo, hi));
+        _mm256_storeu_ps(dst.add(2 * i + 8), _mm256_permute2f128_ps::<0x31>(lo, hi));
+        i += 8;
+    }
+    complex_mac_interleaved_scalar_range(output, acc, a, b, n_simd, n);
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn complex_mac_interleaved_sse(output: &mut [Complex<f32>], acc: &SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    use std::arch::x86_64::*;
+    let n = output.len().min(acc.len()).min(a.len()).min(b.len());
+    let n_simd = n - n % 4;
+    let dst = output.as_mut_ptr() as *mut f32;
+    let mut i = 0;
+    while i < n_simd {
+        let ar = _mm_loadu_ps(a.re.as_ptr().add(i));
+        let ai = _mm_loadu_ps(a.im.as_ptr().add(i));
+        let br = _mm_loadu_ps(b.re.as_ptr().add(i));
+        let bi = _mm_loadu_ps(b.im.as_ptr().add(i));
+        let re = _mm_sub_ps(_mm_mul_ps(ar, br), _mm_mul_ps(ai, bi));
+        let im = _mm_add_ps(_mm_mul_ps(ar, bi), _mm_mul_ps(ai, br));
+        let re = _mm_add_ps(_mm_loadu_ps(acc.re.as_ptr().add(i)), re);
+        let im = _mm_add_ps(_mm_loadu_ps(acc.im.as_ptr().add(i)), im);
+        _mm_storeu_ps(dst.add(2 * i), _mm_unpacklo_ps(re, im));
+        _mm_storeu_ps(dst.add(2 * i + 4), _mm_unpackhi_ps(re, im));
+        i += 4;
+    }
+    complex_mac_interleaved_scalar_range(output, acc, a, b, n_simd, n);
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn complex_mac_interleaved_neon(output: &mut [Complex<f32>], acc: &SplitComplex, a: &SplitComplex, b: &SplitComplex) {
+    use std::arch::aarch64::*;
+    let n = output.len().min(acc.len()).min(a.len()).min(b.len());
+    let n_simd = n - n % 4;
+    let dst = output.as_mut_ptr() as *mut f32;
+    let mut i = 0;
+    while i < n_simd {
+        let ar = vld1q_f32(a.re.as_ptr().add(i));
+        let ai = vld1q_f32(a.im.as_ptr().add(i));
+        let br = vld1q_f32(b.re.as_ptr().add(i));
+        let bi = vld1q_f32(b.im.as_ptr().add(i));
+        let re = vsubq_f32(vmulq_f32(ar, br), vmulq_f32(ai, bi));
+        let im = vaddq_f32(vmulq_f32(ar, bi), vmulq_f32(ai, br));
+        let re = vaddq_f32(vld1q_f32(acc.re.as_ptr().add(i)), re);
+        let im = vaddq_f32(vld1q_f32(acc.im.as_ptr().add(i)), im);
+        vst2q_f32(dst.add(2 * i), float32x4x2_t(re, im));
+        i += 4;
+    }
+    complex_mac_interleaved_scalar_range(output, acc, a, b, n_simd, n);
+}
+
+#[cfg(test)]
+fn test_spectrum(length: usize, seed: u32) -> Vec<Complex<f32>> {
+    // simple LCG, deterministic test data in [-1, 1)
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        (state >> 8) as f32 / (1u32 << 23) as f32 - 1.0
+    };
+    (0..length).map(|_| Complex::new(next(), next())).collect()
+}
+
+#[test]
+fn test_complex_mac_matches_scalar() {
+    // odd length to cover the scalar tail (n_points + 1 bins)
+    for length in [1, 7, 257, 513] {
+        let a = SplitComplex::from_interleaved(&test_spectrum(length, 1));
+        let b = SplitComplex::from_interleaved(&test_spectrum(length, 2));
+        let mut acc_simd = SplitComplex::from_interleaved(&test_spectrum(length, 3));
+        let mut acc_scalar = acc_simd.clone();
+        for _ in 0..4 {
+            complex_mac(&mut acc_simd, &a, &b);
+            complex_mac_scalar(&mut acc_scalar, &a, &b);
+        }
+        assert_eq!(acc_simd.re, acc_scalar.re);
+        assert_eq!(acc_simd.im, acc_scalar.im);
+    }
+}
+
+#[test]
+fn test_complex_mac_matches_complex_mul() {
+    let length = 513;
+    let a = test_spectrum(length, 4);
+    let b = test_spectrum(length, 5);
+    let mut acc = SplitComplex::zeros(length);
+    complex_mac(&mut acc, &SplitComplex::from_interleaved(&a), &SplitComplex::from_interleaved(&b));
+
+    let mut result = vec![Complex::new(0.0, 0.0); length];
+    acc.copy_to_interleaved(&mut result);
+    for (i, c) in result.iter().enumerate() {
+        let expected = a[i] * b[i];
+        assert!((c.re - expected.re).abs() <= f32::EPSILON);
+        assert!((c.im - expected.im).abs() <= f32::EPSILON);
+    }
+}
+
+#[test]
+fn test_interleaved_conversions_match_scalar() {
+    for length in [1, 7, 257, 513] {
+        let data = test_spectrum(length, 6);
+        let split = SplitComplex::from_interleaved(&data);
+        assert!(split.re.iter().zip(data.iter()).all(|(re, c)| *re == c.re));
+        assert!(split.im.iter().zip(data.iter()).all(|(im, c)| *im == c.im));
+
+        // the fused last segment equals complex_mac followed by copy_to_interleaved
+        let a = SplitComplex::from_interleaved(&test_spectrum(length, 7));
+        let b = SplitComplex::from_interleaved(&test_spectrum(length, 8));
+        let mut fused = vec![Complex::new(0.0, 0.0); length];
+        let mut fused_scalar = fused.clone();
+        complex_mac_interleaved(&mut fused, &split, &a, &b);
+        complex_mac_interleaved_scalar(&mut fused_scalar, &split, &a, &b);
+        let mut acc = split.clone();
+        complex_mac_scalar(&mut acc, &a, &b);
+        let mut expected = vec![Complex::new(0.0, 0.0); length];
+        acc.copy_to_interleaved(&mut expected);
+        assert_eq!(fused, expected);
+        assert_eq!(fused_scalar, expected);
+    }
+}
@@ -0,0 +1,85 @@
+use nalgebra::Point3;
+
+use crate::biquad::OCTAVE_BAND_CENTRES;
+
+// Sound reduction index R (dB) of a wall per octave band (see OCTAVE_BAND_CENTRES).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransmissionMaterial {
+    sound_reduction_index: [f32; OCTAVE_BAND_CENTRES.len()],
+}
+
+impl Default for TransmissionMaterial {
+    fn default() -> Self {
+        TransmissionMaterial::GYPSUM_WALL
+    }
+}
+
+impl TransmissionMaterial {
+    // single stud wall, gypsum boards on both sides
+    pub const GYPSUM_WALL: TransmissionMaterial =
+        TransmissionMaterial::new([15.0, 20.0, 28.0, 35.0, 42.0, 45.0, 40.0, 45.0]);
+    pub const BRICK_WALL: TransmissionMaterial =
+        TransmissionMaterial::new([30.0, 34.0, 38.0, 42.0, 48.0, 54.0, 58.0, 60.0]);
+    pub const CONCRETE_WALL: TransmissionMaterial =
+        TransmissionMaterial::new([35.0, 40.0, 44.0, 50.0, 57.0, 63.0, 68.0, 70.0]);
+    pub const DOUBLE_GLAZING: TransmissionMaterial =
+        TransmissionMaterial::new([18.0, 22.0, 25.0, 30.0, 35.0, 38.0, 32.0, 38.0]);
+    pub const WOODEN_DOOR: TransmissionMaterial =
+        TransmissionMaterial::new([15.0, 18.0, 20.0, 22.0, 25.0, 27.0, 28.0, 30.0]);
+
+    pub const fn new(sound_reduction_index: [f32; OCTAVE_BAND_CENTRES.len()]) -> Self {
+        Self {
+            sound_reduction_index,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gypsum_wall" => Some(TransmissionMaterial::GYPSUM_WALL),
+            "brick_wall" => Some(TransmissionMaterial::BRICK_WALL),
+            "concrete_wall" => Some(TransmissionMaterial::CONCRETE_WALL),
+            "double_glazing" => Some(TransmissionMaterial::DOUBLE_GLAZING),
+            "wooden_door" => Some(TransmissionMaterial::WOODEN_DOOR),
+            _ => None,
+        }
+    }
+
+    pub fn get_sound_reduction_index(&self) -> [f32; OCTAVE_BAND_CENTRES.len()] {
+        self.sound_reduction_index
+    }
+
+    // linear transmission gain per octave band
+    pub fn band_gains(&self) -> [f32; OCTAVE_BAND_CENTRES.len()] {
+        self.sound_reduction_index.map(|r| 10f32.powf(-r / 20.0))
+    }
+}
+
+// Transmission of a source outside the room through its nearest wall (or through a portal
+// from a neighbouring room). The source is heard from the transmission point, filtered per
+// octave band.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WallTransmission {
+    point: Point3<f32>,
+    band_gains: [f32; OCTAVE_BAND_CENTRES.len()],
+}
+
+impl WallTransmission {
+    pub fn new(point: Point3<f32>, material: &TransmissionMaterial) -> Self {
+        Self {
+            point,
+            band_gains: material.band_gains(),
+        }
+    }
+
+    pub fn from_band_gains(point: Point3<f32>, band_gains: [f32; OCTAVE_BAND_CENTRES.len()]) -> Self {
+        Self { point, band_gains }
+    }
+
+    pub fn get_point(&self) -> Point3<f32> {
+        self.point
+    }
+
+    pub fn get_band_gains(&self) -> &[f32] {
+        &self.band_gains
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    cell::UnsafeCell,
+    hint,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle, Thread},
+};
+
+// Job that is executed once per thread and block. The argument is the thread index,
+// 0 is the calling (audio) thread, 1..=n_workers are the pool threads.
+type Job = *const (dyn Fn(usize) + Sync);
+
+struct PoolShared {
+    generation: AtomicUsize,
+    finished: AtomicUsize,
+    // set by a worker whose job panicked, the panic is raised again on the calling thread
+    panicked: AtomicBool,
+    shutdown: AtomicBool,
+    job: UnsafeCell<Option<Job>>,
+}
+
+// job is only written by the calling thread while all workers are idle
+unsafe impl Sync for PoolShared {}
+unsafe impl Send for PoolShared {}
+
+// Real-time safe worker pool. Workers are parked between blocks and woken by the audio
+// thread, which takes part in the work itself and spins until all workers are done.
+// No allocation and no locking happens in `run`. `run` takes `&mut self`, so only one job
+// can be in flight at a time.
+pub struct WorkerPool {
+    shared: Arc<PoolShared>,
+    threads: Vec<Thread>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(n_workers: usize) -> Self {
+        let shared = Arc::new(PoolShared {
+            generation: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+            panicked: AtomicBool::new(false),
+            shutdown: AtomicBool::new(false),
+            job: UnsafeCell::new(None),
+        });
+
+        let mut handles = Vec::with_capacity(n_workers);
+        for worker_idx in 1..=n_workers {
+            let shared = shared.clone();
+            let handle = thread::Builder::new()
+                .name(format!("ruspar-worker-{worker_idx}"))
+                .spawn(move || worker_loop(shared, worker_idx))
+                .unwrap();
+            handles.push(handle);
+        }
+        let threads = handles.iter().map(|h| h.thread().clone()).collect();
+
+        Self {
+            shared,
+            threads,
+            handles,
+        }
+    }
+
+    // number of threads taking part in a job, including the calling thread
+    pub fn get_n_threads(&self) -> usize {
+        self.threads.len() + 1
+    }
+
+    // runs job(thread_idx) on every thread and returns when all of them are done.
+    // If the job panics on any thread, the panic is raised on the calling thread once all
+    // threads are done; the pool stays usable.
+    pub fn run(&mut self, job: &(dyn Fn(usize) + Sync)) {
+        // the job only has to live until all workers reported back, see below
+        let job: Job = unsafe { std::mem::transmute::<&(dyn Fn(usize) + Sync), Job>(job) };
+        unsafe { *self.shared.job.get() = Some(job) };
+        self.shared.finished.store(0, Ordering::Relaxed);
+        self.shared.panicked.store(false, Ordering::Relaxed);
+        self.shared.generation.fetch_add(1, Ordering::Release);
+        for thread in self.threads.iter() {
+            thread.unpark();
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| unsafe { (*job)(0) }));
+
+        while self.shared.finished.load(Ordering::Acquire) < self.threads.len() {
+            hint::spin_loop();
+        }
+        unsafe { *self.shared.job.get() = None };
+
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+        if self.shared.panicked.load(Ordering::Relaxed) {
+            panic!("worker pool job panicked");
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        for thread in self.threads.iter() {
+            thread.unpark();
+        }
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn worker_loop(shared: Arc<PoolShared>, worker_idx: usize) {
+    let mut generation = 0;
+    loop {
+        // wait for the next block
+        loop {
+            if shared.shutdown.load(Ordering::Acquire) {
+                return;
+            }
+            let new_generation = shared.generation.load(Ordering::Acquire);
+            if new_generation != generation {
+                generation = new_generation;
+                break;
+            }
+            thread::park();
+        }
+
+        if let Some(job) = unsafe { *shared.job.get() } {
+            // a panicking job must not keep the calling thread waiting
+            if panic::catch_unwind(AssertUnwindSafe(|| unsafe { (*job)(worker_idx) })).is_err() {
+                shared.panicked.store(true, Ordering::Relaxed);
+            }
+        }
+        shared.finished.fetch_add(1, Ordering::Release);
+    }
+}
+
+// Raw pointer that may be handed to the pool threads. The owner has to make sure that
+// every thread only touches its own part of the data while a job is running.
+pub struct SharedPtr<T>(*mut T);
+
+unsafe impl<T> Send for SharedPtr<T> {}
+unsafe impl<T> Sync for SharedPtr<T> {}
+
+impl<T> Clone for SharedPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for SharedPtr<T> {}
+
+impl<T> SharedPtr<T> {
+    pub fn new(ptr: *mut T) -> Self {
+        Self(ptr)
+    }
+
+    pub fn get(&self) -> *mut T {
+        self.0
+    }
+}
+
+#[test]
+fn test_worker_pool_runs_every_thread() {
+    let mut pool = WorkerPool::new(3);
+    let counters: Vec<AtomicUsize> = (0..pool.get_n_threads()).map(|_| AtomicUsize::new(0)).collect();
+    for _ in 0..100 {
+        pool.run(&|thread_idx| {
+            counters[thread_idx].fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    for counter in counters.iter() {
+        assert_eq!(counter.load(Ordering::Relaxed), 100);
+    }
+}
+
+#[test]
+#[should_panic(expected = "worker pool job panicked")]
+fn test_worker_pool_raises_worker_panic() {
+    let mut pool = WorkerPool::new(2);
+    pool.run(&|thread_idx| assert!(thread_idx != 2));
+}